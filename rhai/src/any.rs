@@ -1,6 +1,9 @@
 use std::any::{Any as StdAny, TypeId};
 use std::fmt;
 
+/// A value of any type handled by the engine, as stored in a `Scope` or returned by a script
+pub type Dynamic = Box<Any>;
+
 pub trait Any: StdAny {
     fn type_id(&self) -> TypeId;
 
@@ -77,9 +80,12 @@ impl fmt::Debug for Any {
 
 pub trait AnyExt: Sized {
     fn downcast<T: Any + Clone>(self) -> Result<Box<T>, Self>;
+
+    /// Wraps a value. Named `from_value` because `Dynamic::from` would clash with `From::from`.
+    fn from_value<T: Any>(value: T) -> Self;
 }
 
-impl AnyExt for Box<Any> {
+impl AnyExt for Dynamic {
     fn downcast<T: Any + Clone>(self) -> Result<Box<T>, Self> {
         if self.is::<T>() {
             unsafe {
@@ -90,6 +96,10 @@ impl AnyExt for Box<Any> {
             Err(self)
         }
     }
+
+    fn from_value<T: Any>(value: T) -> Self {
+        Box::new(value)
+    }
 }
 
 /// Private type which ensures that `rhai::Any` can only
@@ -1,23 +1,23 @@
 //! Helper module which defines `FnArgs`
 //! to make function calling easier.
 
-use any::Any;
+use any::{Any, Dynamic};
 
-pub trait FunArgs<'a> {
-    fn into_vec(self) -> Vec<&'a mut Any>;
+pub trait FunArgs {
+    fn into_vec(self) -> Vec<Dynamic>;
 }
 
 macro_rules! impl_args {
     ($($p:ident),*) => {
-        impl<'a, $($p),*> FunArgs<'a> for ($(&'a mut $p,)*)
+        impl<$($p),*> FunArgs for ($($p,)*)
         where
             $($p: Any + Clone),*
         {
-            fn into_vec(self) -> Vec<&'a mut Any> {
+            fn into_vec(self) -> Vec<Dynamic> {
                 let ($($p,)*) = self;
 
                 let mut v = Vec::new();
-                $(v.push($p as &mut Any);)*
+                $(v.push(Box::new($p) as Dynamic);)*
 
                 v
             }
@@ -1,32 +1,50 @@
 use std::any::TypeId;
 use std::borrow::Borrow;
+use std::cell::RefCell;
 use std::cmp::{PartialEq, PartialOrd};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt;
+use std::io::{self, Read};
 use std::ops::{Add, BitAnd, BitOr, BitXor, Deref, Div, Mul, Neg, Rem, Shl, Shr, Sub};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use any::{Any, AnyExt};
+use any::{Any, AnyExt, Dynamic};
 use call::FunArgs;
-use fn_register::{Mut, RegisterFn};
-use parser::{lex, parse, Expr, FnDef, Stmt};
+use fn_register::{Mut, RegisterFn, RegisterResultFn};
+use parser::{
+    dump_ast, dump_fn_def, lex, lex_read, parse, parse_expression, Expr, FnDef, ParseError, Stmt,
+};
 
 #[derive(Debug)]
 pub enum EvalAltResult {
     ErrorFunctionNotFound(String),
     ErrorFunctionArgMismatch,
-    ErrorFunctionCallNotSupported,
+    ErrorFunctionArgTypes(String, Vec<String>, Vec<String>),
     ErrorIndexMismatch,
     ErrorIfGuardMismatch,
+    ErrorForMismatch,
     ErrorVariableNotFound(String),
-    ErrorFunctionArityNotSupported,
     ErrorAssignmentToUnknownLHS,
+    ErrorAssignmentToConstant(String),
+    ErrorAssignmentInExpression,
     ErrorMismatchOutputType(String),
+    ErrorFunctionDidNotReturn(String),
+    ErrorTypeNotRegistered(String),
+    ErrorAssertionFailed(String),
+    ErrorRuntime(String),
+    ErrorFunctionRedefined(String),
+    ErrorDuplicateFunction(String),
+    ErrorTooManyParameters(String),
     ErrorCantOpenScriptFile,
+    ErrorIncludeCycle,
+    ErrorScriptNotUtf8(String),
+    ErrorInFile(String, Box<EvalAltResult>),
     InternalErrorMalformedDotExpression,
     LoopBreak,
-    Return(Box<Any>),
+    Return(Dynamic),
 }
 
 impl EvalAltResult {
@@ -35,6 +53,15 @@ impl EvalAltResult {
             EvalAltResult::ErrorVariableNotFound(ref s) => Some(s.as_str()),
             EvalAltResult::ErrorFunctionNotFound(ref s) => Some(s.as_str()),
             EvalAltResult::ErrorMismatchOutputType(ref s) => Some(s.as_str()),
+            EvalAltResult::ErrorFunctionDidNotReturn(ref s) => Some(s.as_str()),
+            EvalAltResult::ErrorTypeNotRegistered(ref s) => Some(s.as_str()),
+            EvalAltResult::ErrorAssertionFailed(ref s) => Some(s.as_str()),
+            EvalAltResult::ErrorRuntime(ref s) => Some(s.as_str()),
+            EvalAltResult::ErrorFunctionRedefined(ref s) => Some(s.as_str()),
+            EvalAltResult::ErrorDuplicateFunction(ref s) => Some(s.as_str()),
+            EvalAltResult::ErrorTooManyParameters(ref s) => Some(s.as_str()),
+            EvalAltResult::ErrorScriptNotUtf8(ref s) => Some(s.as_str()),
+            EvalAltResult::ErrorAssignmentToConstant(ref s) => Some(s.as_str()),
             _ => None,
         }
     }
@@ -47,14 +74,29 @@ impl PartialEq for EvalAltResult {
         match (self, other) {
             (&ErrorFunctionNotFound(ref a), &ErrorFunctionNotFound(ref b)) => a == b,
             (&ErrorFunctionArgMismatch, &ErrorFunctionArgMismatch) => true,
-            (&ErrorFunctionCallNotSupported, &ErrorFunctionCallNotSupported) => true,
+            (
+                &ErrorFunctionArgTypes(ref a, ref aa, ref ae),
+                &ErrorFunctionArgTypes(ref b, ref ba, ref be),
+            ) => a == b && aa == ba && ae == be,
             (&ErrorIndexMismatch, &ErrorIndexMismatch) => true,
             (&ErrorIfGuardMismatch, &ErrorIfGuardMismatch) => true,
+            (&ErrorForMismatch, &ErrorForMismatch) => true,
             (&ErrorVariableNotFound(ref a), &ErrorVariableNotFound(ref b)) => a == b,
-            (&ErrorFunctionArityNotSupported, &ErrorFunctionArityNotSupported) => true,
             (&ErrorAssignmentToUnknownLHS, &ErrorAssignmentToUnknownLHS) => true,
+            (&ErrorAssignmentToConstant(ref a), &ErrorAssignmentToConstant(ref b)) => a == b,
+            (&ErrorAssignmentInExpression, &ErrorAssignmentInExpression) => true,
             (&ErrorMismatchOutputType(ref a), &ErrorMismatchOutputType(ref b)) => a == b,
+            (&ErrorFunctionDidNotReturn(ref a), &ErrorFunctionDidNotReturn(ref b)) => a == b,
+            (&ErrorTypeNotRegistered(ref a), &ErrorTypeNotRegistered(ref b)) => a == b,
+            (&ErrorAssertionFailed(ref a), &ErrorAssertionFailed(ref b)) => a == b,
+            (&ErrorRuntime(ref a), &ErrorRuntime(ref b)) => a == b,
+            (&ErrorFunctionRedefined(ref a), &ErrorFunctionRedefined(ref b)) => a == b,
+            (&ErrorDuplicateFunction(ref a), &ErrorDuplicateFunction(ref b)) => a == b,
+            (&ErrorTooManyParameters(ref a), &ErrorTooManyParameters(ref b)) => a == b,
             (&ErrorCantOpenScriptFile, &ErrorCantOpenScriptFile) => true,
+            (&ErrorIncludeCycle, &ErrorIncludeCycle) => true,
+            (&ErrorScriptNotUtf8(ref a), &ErrorScriptNotUtf8(ref b)) => a == b,
+            (&ErrorInFile(ref a, ref ea), &ErrorInFile(ref b, ref eb)) => a == b && ea == eb,
             (&InternalErrorMalformedDotExpression, &InternalErrorMalformedDotExpression) => true,
             (&LoopBreak, &LoopBreak) => true,
             _ => false,
@@ -67,20 +109,34 @@ impl Error for EvalAltResult {
         match *self {
             EvalAltResult::ErrorFunctionNotFound(_) => "Function not found",
             EvalAltResult::ErrorFunctionArgMismatch => "Function argument types do not match",
-            EvalAltResult::ErrorFunctionCallNotSupported => {
-                "Function call with > 2 argument not supported"
-            }
+            EvalAltResult::ErrorFunctionArgTypes(_, _, _) => "Function argument types do not match",
             EvalAltResult::ErrorIndexMismatch => "Index does not match array",
             EvalAltResult::ErrorIfGuardMismatch => "If guards expect boolean expression",
+            EvalAltResult::ErrorForMismatch => "For loops expect an array",
             EvalAltResult::ErrorVariableNotFound(_) => "Variable not found",
-            EvalAltResult::ErrorFunctionArityNotSupported => {
-                "Functions of more than 3 parameters are not yet supported"
-            }
             EvalAltResult::ErrorAssignmentToUnknownLHS => {
                 "Assignment to an unsupported left-hand side"
             }
+            EvalAltResult::ErrorAssignmentToConstant(_) => "Cannot assign to a constant",
+            EvalAltResult::ErrorAssignmentInExpression => {
+                "A single expression cannot assign to a variable"
+            }
             EvalAltResult::ErrorMismatchOutputType(_) => "Cast of output failed",
+            EvalAltResult::ErrorFunctionDidNotReturn(_) => "Function did not return a value",
+            EvalAltResult::ErrorTypeNotRegistered(_) => "Type not registered with the engine",
+            EvalAltResult::ErrorAssertionFailed(_) => "Assertion failed",
+            EvalAltResult::ErrorRuntime(_) => "Runtime error",
+            EvalAltResult::ErrorFunctionRedefined(_) => {
+                "Function already registered with the same argument types"
+            }
+            EvalAltResult::ErrorDuplicateFunction(_) => "Function defined twice in the script",
+            EvalAltResult::ErrorTooManyParameters(_) => {
+                "Function defined with more than 6 parameters"
+            }
             EvalAltResult::ErrorCantOpenScriptFile => "Cannot open script file",
+            EvalAltResult::ErrorIncludeCycle => "Script file is already being included",
+            EvalAltResult::ErrorScriptNotUtf8(_) => "Script is not valid UTF-8",
+            EvalAltResult::ErrorInFile(_, ref e) => e.description(),
             EvalAltResult::InternalErrorMalformedDotExpression => {
                 "[Internal error] Unexpected expression in dot expression"
             }
@@ -90,13 +146,29 @@ impl Error for EvalAltResult {
     }
 
     fn cause(&self) -> Option<&Error> {
-        None
+        match *self {
+            EvalAltResult::ErrorInFile(_, ref e) => Some(&**e),
+            _ => None,
+        }
     }
 }
 
 impl fmt::Display for EvalAltResult {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(s) = self.as_str() {
+        if let EvalAltResult::ErrorInFile(ref fname, ref e) = *self {
+            write!(f, "{}: {}", fname, e)
+        } else if let EvalAltResult::ErrorFunctionArgTypes(ref ident, ref args, ref expected) =
+            *self
+        {
+            write!(
+                f,
+                "{}: {} ({}), registered: {}",
+                self.description(),
+                ident,
+                args.join(","),
+                expected.join(", ")
+            )
+        } else if let Some(s) = self.as_str() {
             write!(f, "{}: {}", self.description(), s)
         } else {
             write!(f, "{}", self.description())
@@ -104,6 +176,11 @@ impl fmt::Display for EvalAltResult {
     }
 }
 
+/// The value of a `fn(x) { ... }` expression, called like a named function through the
+/// variable holding it, e.g. `let f = fn(x) { x + 1 }; f(4)`
+#[derive(Clone, Debug)]
+pub struct AnonFn(Arc<FnDef>);
+
 #[derive(Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
 pub struct FnSpec {
     ident: String,
@@ -129,6 +206,17 @@ pub struct Engine {
     /// A hashmap containing all functions known to the engine
     pub fns: HashMap<FnSpec, Arc<FnIntExt>>,
     pub type_names: HashMap<TypeId, String>,
+    /// The set of types registered through `register_type`
+    pub registered_types: HashSet<TypeId>,
+    /// Whether functions defined by a script stay callable after the script has run
+    pub retain_functions: bool,
+    /// Whether `try_register_fn` refuses to replace a function registered with the same argument types
+    pub strict_registration: bool,
+    /// Whether `call_fn` promotes an `i32`, `u32` or `f32` result to the requested `i64` or `f64`
+    /// when no precision is lost, instead of failing because the types differ
+    pub coerce_numeric_results: bool,
+    /// The directory relative script paths are resolved against, see `set_base_dir`
+    base_dir: Option<PathBuf>,
 }
 
 pub enum FnIntExt {
@@ -136,7 +224,8 @@ pub enum FnIntExt {
     Int(FnDef),
 }
 
-pub type FnAny = Fn(Vec<&mut Any>) -> Result<Box<Any>, EvalAltResult>;
+/// Native functions must be `Send + Sync`, so that one `Engine` can be shared between threads
+pub type FnAny = Fn(Vec<&mut Any>) -> Result<Dynamic, EvalAltResult> + Send + Sync;
 
 /// A type containing information about current scope.
 /// Useful for keeping state between `Engine` runs
@@ -151,22 +240,272 @@ pub type FnAny = Fn(Vec<&mut Any>) -> Result<Box<Any>, EvalAltResult>;
 /// assert_eq!(engine.eval_with_scope::<i64>(&mut my_scope, "x + 1").unwrap(), 6);
 /// ```
 ///
-/// Between runs, `Engine` only remembers functions when not using own `Scope`.
-pub type Scope = Vec<(String, Box<Any>)>;
+/// Between runs, `Engine` remembers the functions defined by scripts, unless
+/// `retain_functions` is set to `false`.
+#[derive(Clone, Default)]
+pub struct Scope {
+    vars: Vec<ScopeVar>,
+    /// Amount of variables when each enclosing block was entered
+    layers: Vec<usize>,
+}
+
+#[derive(Clone)]
+struct ScopeVar {
+    name: String,
+    value: Dynamic,
+    constant: bool,
+}
+
+impl Scope {
+    /// Creates a scope without any variable
+    pub fn new() -> Scope {
+        Scope::default()
+    }
+
+    /// Amount of variables, shadowed ones included
+    pub fn len(&self) -> usize {
+        self.vars.len()
+    }
+
+    /// True when no variable was pushed
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
+
+    /// Adds a variable, shadowing any previous one with the same name
+    pub fn push_var<S: Into<String>>(&mut self, name: S, value: Dynamic) {
+        self.push(name.into(), value, false);
+    }
+
+    /// Adds a variable that scripts can read but not assign to
+    pub fn push_const<S: Into<String>>(&mut self, name: S, value: Dynamic) {
+        self.push(name.into(), value, true);
+    }
+
+    fn push(&mut self, name: String, value: Dynamic, constant: bool) {
+        self.vars.push(ScopeVar {
+            name: name,
+            value: value,
+            constant: constant,
+        });
+    }
+
+    /// The value of the latest variable with that name
+    pub fn get(&self, name: &str) -> Option<&Dynamic> {
+        self.vars
+            .iter()
+            .rev()
+            .find(|var| var.name == name)
+            .map(|var| &var.value)
+    }
+
+    /// Replaces the value of the latest variable with that name, unless it is a constant
+    pub fn set(&mut self, name: &str, value: Dynamic) -> Result<(), EvalAltResult> {
+        let idx = self.writable(name)?;
+        self.vars[idx].value = value;
+        Ok(())
+    }
+
+    /// Starts a block: the variables added from now on are dropped by `pop_layer`
+    pub fn push_layer(&mut self) {
+        self.layers.push(self.vars.len());
+    }
+
+    /// Ends the block started by the matching `push_layer`
+    pub fn pop_layer(&mut self) {
+        if let Some(len) = self.layers.pop() {
+            self.vars.truncate(len);
+        }
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.vars.iter().rposition(|var| var.name == name)
+    }
+
+    fn writable(&self, name: &str) -> Result<usize, EvalAltResult> {
+        match self.position(name) {
+            Some(idx) if self.vars[idx].constant => {
+                Err(EvalAltResult::ErrorAssignmentToConstant(name.to_owned()))
+            }
+            Some(idx) => Ok(idx),
+            None => Err(EvalAltResult::ErrorVariableNotFound(name.to_owned())),
+        }
+    }
+}
+
+/// The file of an `include("path");` statement
+fn include_path(stmt: &Stmt) -> Option<String> {
+    if let Stmt::Expr(ref e) = *stmt {
+        if let Expr::FnCall(ref name, ref args) = **e {
+            if let (true, Some(&Expr::StringConst(ref path))) = (name == "include", args.first()) {
+                if args.len() == 1 {
+                    return Some(path.clone());
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Whether an expression assigns to a variable anywhere, e.g. `1 + (x = 5)`. The body of an
+/// anonymous function only assigns to its own variables, so it isn't looked into
+fn has_assignment(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Assignment(_, _) => true,
+        Expr::FnCall(_, ref args) | Expr::Array(ref args) => args.iter().any(has_assignment),
+        Expr::Dot(ref a, ref b) | Expr::IndexValue(ref a, ref b) => {
+            has_assignment(a) || has_assignment(b)
+        }
+        Expr::Index(_, ref idx) => has_assignment(idx),
+        _ => false,
+    }
+}
+
+/// Whether scripts can call a function with this name. Operators (`+`, `==`...) and property
+/// accessors (`get$x`) don't parse as identifiers but are called by name too, so only what
+/// no token can produce is refused
+fn is_callable_name(ident: &str) -> bool {
+    !ident.is_empty() && !ident.chars().any(char::is_whitespace)
+}
+
+/// The position in an array of an index given by a script, or `None` if no array can be that
+/// long: negative, or beyond `usize` on 32-bit targets, where a plain cast would wrap around
+fn array_index(idx: i64) -> Option<usize> {
+    usize::try_from(idx).ok()
+}
+
+/// Converts a number to the type `T` when it is `i64` or `f64` and no precision is lost:
+/// from `i32` or `u32` to either, and from `f32` to `f64`
+fn promote_number<T: Any + Clone>(value: &Any) -> Option<T> {
+    let target = TypeId::of::<T>();
+
+    let promoted: Dynamic = if target == TypeId::of::<i64>() {
+        if let Some(&x) = value.downcast_ref::<i32>() {
+            Box::new(i64::from(x))
+        } else if let Some(&x) = value.downcast_ref::<u32>() {
+            Box::new(i64::from(x))
+        } else {
+            return None;
+        }
+    } else if target == TypeId::of::<f64>() {
+        if let Some(&x) = value.downcast_ref::<i32>() {
+            Box::new(f64::from(x))
+        } else if let Some(&x) = value.downcast_ref::<u32>() {
+            Box::new(f64::from(x))
+        } else if let Some(&x) = value.downcast_ref::<f32>() {
+            Box::new(f64::from(x))
+        } else {
+            return None;
+        }
+    } else {
+        return None;
+    };
+
+    promoted.downcast().ok().map(|x| *x)
+}
+
+/// Parse a script read from `reader`. A read error is reported even if the script read
+/// so far parsed, since the rest of it is missing
+fn parse_reader<R: Read>(
+    reader: R,
+) -> Result<Result<(Vec<Stmt>, Vec<FnDef>), ParseError>, EvalAltResult> {
+    let error = RefCell::new(None);
+    let tree = parse(&mut lex_read(reader, &error).peekable());
+
+    match error.into_inner() {
+        Some(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+            Err(EvalAltResult::ErrorScriptNotUtf8(e.to_string()))
+        }
+        Some(_) => Err(EvalAltResult::ErrorCantOpenScriptFile),
+        None => Ok(tree),
+    }
+}
+
+/// Files met while expanding the includes of one script
+#[derive(Default)]
+struct Includes {
+    /// Files being expanded, starting with the script file itself, to refuse cycles
+    stack: Vec<PathBuf>,
+    /// Every file expanded so far; a file included again, e.g. by two others, is skipped
+    done: HashSet<PathBuf>,
+}
+
+impl Includes {
+    /// Includes of the script read from `path`, which can't include itself
+    fn of_file(path: &Path) -> Includes {
+        let mut includes = Includes::default();
+
+        if let Ok(path) = path.canonicalize() {
+            includes.stack.push(path.clone());
+            includes.done.insert(path);
+        }
+
+        includes
+    }
+}
+
+/// Calls the function given to `map`, `filter` or `reduce`
+type ArrayFnCallback<'a> = Fn(Vec<&mut Any>) -> Result<Dynamic, EvalAltResult> + 'a;
+
+/// Array function calling back the function given as its last argument, by name or as an
+/// anonymous function. Gets the array, every argument and the callback
+type HigherOrderFn =
+    fn(&Engine, &[Dynamic], &[&mut Any], &ArrayFnCallback) -> Result<Dynamic, EvalAltResult>;
+
+/// Higher-order array functions, with their amount of arguments
+const HIGHER_ORDER_FNS: &[(&str, usize, HigherOrderFn)] = &[
+    ("map", 2, Engine::map_array),
+    ("filter", 2, Engine::filter_array),
+    ("reduce", 3, Engine::reduce_array),
+];
 
 impl Engine {
-    pub fn call_fn<'a, I, A, T>(&self, ident: I, args: A) -> Result<T, EvalAltResult>
+    /// Call a function by name, either registered with the `Engine` or
+    /// defined by a script that has already been run
+    ///
+    /// ```rust
+    /// use rhai::Engine;
+    ///
+    /// let mut engine = Engine::new();
+    /// engine.consume("fn add(a, b) { a + b }").unwrap();
+    ///
+    /// assert_eq!(engine.call_fn::<_, _, i64>("add", (2i64, 3i64)), Ok(5));
+    /// ```
+    pub fn call_fn<I, A, T>(&self, ident: I, args: A) -> Result<T, EvalAltResult>
     where
         I: Into<String>,
-        A: FunArgs<'a>,
+        A: FunArgs,
         T: Any + Clone,
     {
-        self.call_fn_raw(ident.into(), args.into_vec())
-            .and_then(|b| {
-                b.downcast()
-                    .map(|b| *b)
-                    .map_err(|a| EvalAltResult::ErrorMismatchOutputType(self.nice_type_name(a)))
-            })
+        let ident = ident.into();
+        let mut values = args.into_vec();
+
+        self.call_fn_raw(
+            ident.clone(),
+            values.iter_mut().map(|v| v.as_mut()).collect(),
+        )
+        .and_then(|b| {
+            let a = match b.downcast() {
+                Ok(b) => return Ok(*b),
+                Err(a) => a,
+            };
+
+            if self.coerce_numeric_results {
+                if let Some(x) = promote_number(&*a) {
+                    return Ok(x);
+                }
+            }
+
+            // Most likely a script function without `return` nor final expression
+            if a.is::<()>() {
+                Err(EvalAltResult::ErrorFunctionDidNotReturn(ident))
+            } else {
+                Err(EvalAltResult::ErrorMismatchOutputType(
+                    self.nice_type_name(a),
+                ))
+            }
+        })
     }
 
     /// Universal method for calling functions, that are either
@@ -175,7 +514,7 @@ impl Engine {
         &self,
         ident: String,
         args: Vec<&mut Any>,
-    ) -> Result<Box<Any>, EvalAltResult> {
+    ) -> Result<Dynamic, EvalAltResult> {
         debug_println!(
             "Trying to call function {:?} with args {:?}",
             ident,
@@ -187,53 +526,103 @@ impl Engine {
             args: Some(args.iter().map(|a| <Any as Any>::type_id(&**a)).collect()),
         };
 
-        self.fns
-            .get(&spec)
-            .or_else(|| {
-                let spec1 = FnSpec {
-                    ident: ident.clone(),
-                    args: None,
-                };
-                self.fns.get(&spec1)
-            })
+        let script_spec = FnSpec {
+            ident: ident.clone(),
+            args: None,
+        };
+        let registered = self.fns.get(&spec).or_else(|| self.fns.get(&script_spec));
+
+        // `help()` and the array functions (higher-order ones, `to_string`, `print` and
+        // `is_array_of_type`) need the engine itself, so they can't be registered like other
+        // functions; a function registered by the user, or defined by a script, takes precedence
+        if registered.is_none() {
+            if ident == "help" && args.is_empty() {
+                return self.print_help();
+            }
+            if ident == "include" {
+                return Err(EvalAltResult::ErrorRuntime(
+                    "include must be a top-level statement with a literal path".to_string(),
+                ));
+            }
+            if let Some(result) = self.call_array_fn(&ident, &args) {
+                return result;
+            }
+        }
+
+        registered
             .ok_or_else(|| {
                 let typenames = args
                     .iter()
                     .map(|x| self.nice_type_name((&**x).box_clone()))
                     .collect::<Vec<_>>();
-                EvalAltResult::ErrorFunctionNotFound(format!("{} ({})", ident, typenames.join(",")))
+                let expected = self.signatures(|spec| spec.ident == ident);
+
+                if expected.is_empty() {
+                    EvalAltResult::ErrorFunctionNotFound(format!(
+                        "{} ({})",
+                        ident,
+                        typenames.join(",")
+                    ))
+                } else {
+                    EvalAltResult::ErrorFunctionArgTypes(ident.clone(), typenames, expected)
+                }
             })
             .and_then(move |f| match **f {
                 FnIntExt::Ext(ref f) => f(args),
-                FnIntExt::Int(ref f) => {
-                    let mut scope = Scope::new();
-                    scope.extend(
-                        f.params
-                            .iter()
-                            .cloned()
-                            .zip(args.iter().map(|x| (&**x).box_clone())),
-                    );
-
-                    match self.eval_stmt(&mut scope, &*f.body) {
-                        Err(EvalAltResult::Return(x)) => Ok(x),
-                        other => other,
-                    }
-                }
+                FnIntExt::Int(ref f) => self.call_script_fn(f, args),
             })
     }
 
+    fn call_script_fn(&self, f: &FnDef, args: Vec<&mut Any>) -> Result<Dynamic, EvalAltResult> {
+        if f.params.len() != args.len() {
+            return Err(EvalAltResult::ErrorFunctionArgMismatch);
+        }
+
+        // The body only sees its own parameters, never the caller's variables;
+        // the arguments are copies, so the callee can't change them
+        let mut scope = Scope::new();
+        for (name, x) in f.params.iter().zip(args.iter()) {
+            scope.push_var(name.clone(), (&**x).box_clone());
+        }
+
+        match self.eval_stmt(&mut scope, &*f.body) {
+            Err(EvalAltResult::Return(x)) => Ok(x),
+            other => other,
+        }
+    }
+
+    /// Register a native function. A name no script can call, being empty or containing
+    /// whitespace, is most likely a mistake: the function is then ignored, with a warning
     pub fn register_fn_raw(&mut self, ident: String, args: Option<Vec<TypeId>>, f: Box<FnAny>) {
         debug_println!("Register; {:?} with args {:?}", ident, args);
 
+        if !is_callable_name(&ident) {
+            eprintln!(
+                "Warning: function {:?} cannot be called by scripts, ignored",
+                ident
+            );
+            return;
+        }
+
         let spec = FnSpec { ident, args };
 
         self.fns.insert(spec, Arc::new(FnIntExt::Ext(f)));
     }
 
+    /// Tells whether an external function is registered under this name with exactly these argument types.
+    pub fn has_fn(&self, ident: &str, args: &[TypeId]) -> bool {
+        let spec = FnSpec {
+            ident: ident.to_owned(),
+            args: Some(args.to_vec()),
+        };
+
+        self.fns.contains_key(&spec)
+    }
+
     /// Register a type for use with Engine. Keep in mind that
     /// your type must implement Clone.
     pub fn register_type<T: Any>(&mut self) {
-        // currently a no-op, exists for future extensibility
+        self.registered_types.insert(TypeId::of::<T>());
     }
 
     /// Register a type, providing a name for nice error messages.
@@ -241,21 +630,46 @@ impl Engine {
         self.register_type::<T>();
         debug_println!("register type {}: {:?}", name, TypeId::of::<T>());
         self.type_names.insert(TypeId::of::<T>(), name.into());
+
+        // `Vec<T>` returned by a registered function is named after `T`,
+        // unless it has been registered itself
+        let array_tid = TypeId::of::<Vec<T>>();
+        if !self.registered_types.contains(&array_tid) {
+            self.type_names
+                .insert(array_tid, format!("array<{}>", name));
+        }
     }
 
     /// Register a get function for a member of a registered type
     pub fn register_get<T: Clone + Any, U: Clone + Any, F>(&mut self, name: &str, get_fn: F)
     where
-        F: 'static + Fn(&mut T) -> U,
+        F: 'static + Fn(&mut T) -> U + Send + Sync,
     {
         let get_name = "get$".to_string() + name;
         self.register_fn(&get_name, get_fn);
     }
 
+    /// Register a get function returning a reference to a member of a registered type.
+    /// The member is cloned only once, when it is handed to the script.
+    pub fn register_get_ref<T: Clone + Any, U: Clone + Any, F>(&mut self, name: &str, get_fn: F)
+    where
+        F: 'static + Fn(&T) -> &U + Send + Sync,
+    {
+        let get_name = "get$".to_string() + name;
+        let fun = move |args: Vec<&mut Any>| {
+            let this = args[0]
+                .downcast_ref::<T>()
+                .ok_or(EvalAltResult::ErrorFunctionArgMismatch)?;
+
+            Ok(Box::new(get_fn(this).clone()) as Dynamic)
+        };
+        self.register_fn_raw(get_name, Some(vec![TypeId::of::<T>()]), Box::new(fun));
+    }
+
     /// Register a set function for a member of a registered type
     pub fn register_set<T: Clone + Any, U: Clone + Any, F>(&mut self, name: &str, set_fn: F)
     where
-        F: 'static + Fn(&mut T, U) -> (),
+        F: 'static + Fn(&mut T, U) -> () + Send + Sync,
     {
         let set_name = "set$".to_string() + name;
         self.register_fn(&set_name, set_fn);
@@ -268,24 +682,42 @@ impl Engine {
         get_fn: F,
         set_fn: G,
     ) where
-        F: 'static + Fn(&mut T) -> U,
-        G: 'static + Fn(&mut T, U) -> (),
+        F: 'static + Fn(&mut T) -> U + Send + Sync,
+        G: 'static + Fn(&mut T, U) -> () + Send + Sync,
     {
         self.register_get(name, get_fn);
         self.register_set(name, set_fn);
     }
 
+    /// Call a `get$`/`set$` property function, reporting an unregistered
+    /// `this` type instead of a missing function where that is the cause
+    fn call_prop_fn(&self, fn_name: String, args: Vec<&mut Any>) -> Result<Dynamic, EvalAltResult> {
+        let this_type = <Any as Any>::type_id(&*args[0]);
+
+        match self.call_fn_raw(fn_name, args) {
+            Err(EvalAltResult::ErrorFunctionNotFound(_))
+            | Err(EvalAltResult::ErrorFunctionArgTypes(_, _, _))
+                if !self.registered_types.contains(&this_type) =>
+            {
+                Err(EvalAltResult::ErrorTypeNotRegistered(
+                    self.type_name_of(this_type),
+                ))
+            }
+            other => other,
+        }
+    }
+
     fn get_dot_val_helper(
         &self,
         scope: &mut Scope,
         this_ptr: &mut Any,
         dot_rhs: &Expr,
-    ) -> Result<Box<Any>, EvalAltResult> {
+    ) -> Result<Dynamic, EvalAltResult> {
         use std::iter::once;
 
         match *dot_rhs {
             Expr::FnCall(ref fn_name, ref args) => {
-                let mut args: Vec<Box<Any>> = args
+                let mut args: Vec<Dynamic> = args
                     .iter()
                     .map(|arg| self.eval_expr(scope, arg))
                     .collect::<Result<Vec<_>, _>>()?;
@@ -298,23 +730,27 @@ impl Engine {
             Expr::Identifier(ref id) => {
                 let get_fn_name = "get$".to_string() + id;
 
-                self.call_fn_raw(get_fn_name, vec![this_ptr])
+                self.call_prop_fn(get_fn_name, vec![this_ptr])
             }
             Expr::Index(ref id, ref idx_raw) => {
                 let idx = self.eval_expr(scope, idx_raw)?;
                 let get_fn_name = "get$".to_string() + id;
 
-                let mut val = self.call_fn_raw(get_fn_name, vec![this_ptr])?;
+                let mut val = self.call_prop_fn(get_fn_name, vec![this_ptr])?;
 
-                ((*val).downcast_mut() as Option<&mut Vec<Box<Any>>>)
-                    .and_then(|arr| idx.downcast_ref::<i64>().map(|idx| (arr, *idx as usize)))
-                    .map(|(arr, idx)| arr[idx].clone())
+                ((*val).downcast_mut() as Option<&mut Vec<Dynamic>>)
+                    .and_then(|arr| {
+                        idx.downcast_ref::<i64>()
+                            .and_then(|idx| array_index(*idx))
+                            .map(|idx| (arr, idx))
+                    })
+                    .and_then(|(arr, idx)| arr.get(idx).cloned())
                     .ok_or(EvalAltResult::ErrorIndexMismatch)
             }
             Expr::Dot(ref inner_lhs, ref inner_rhs) => match **inner_lhs {
                 Expr::Identifier(ref id) => {
                     let get_fn_name = "get$".to_string() + id;
-                    self.call_fn_raw(get_fn_name, vec![this_ptr])
+                    self.call_prop_fn(get_fn_name, vec![this_ptr])
                         .and_then(|mut v| self.get_dot_val_helper(scope, v.as_mut(), inner_rhs))
                 }
                 _ => Err(EvalAltResult::InternalErrorMalformedDotExpression),
@@ -331,13 +767,11 @@ impl Engine {
     where
         F: FnOnce(&'a mut Any) -> Result<T, EvalAltResult>,
     {
-        scope
-            .iter_mut()
-            .enumerate()
-            .rev()
-            .find(|&(_, &mut (ref name, _))| *id == *name)
-            .ok_or_else(|| EvalAltResult::ErrorVariableNotFound(id.to_owned()))
-            .and_then(move |(idx, &mut (_, ref mut val))| map(val.as_mut()).map(|val| (idx, val)))
+        let idx = scope
+            .position(id)
+            .ok_or_else(|| EvalAltResult::ErrorVariableNotFound(id.to_owned()))?;
+
+        map(scope.vars[idx].value.as_mut()).map(|val| (idx, val))
     }
 
     fn array_value(
@@ -345,15 +779,15 @@ impl Engine {
         scope: &mut Scope,
         id: &str,
         idx: &Expr,
-    ) -> Result<(usize, usize, Box<Any>), EvalAltResult> {
+    ) -> Result<(usize, usize, Dynamic), EvalAltResult> {
         let idx_boxed = self
             .eval_expr(scope, idx)?
             .downcast::<i64>()
             .map_err(|_| EvalAltResult::ErrorIndexMismatch)?;
-        let idx = *idx_boxed as usize;
+        let idx = array_index(*idx_boxed).ok_or(EvalAltResult::ErrorIndexMismatch)?;
         let (idx_sc, val) = Self::search_scope(scope, id, |val| {
-            ((*val).downcast_mut() as Option<&mut Vec<Box<Any>>>)
-                .map(|arr| arr[idx].clone())
+            ((*val).downcast_mut() as Option<&mut Vec<Dynamic>>)
+                .and_then(|arr| arr.get(idx).cloned())
                 .ok_or(EvalAltResult::ErrorIndexMismatch)
         })?;
 
@@ -365,25 +799,42 @@ impl Engine {
         scope: &mut Scope,
         dot_lhs: &Expr,
         dot_rhs: &Expr,
-    ) -> Result<Box<Any>, EvalAltResult> {
+    ) -> Result<Dynamic, EvalAltResult> {
         match *dot_lhs {
-            Expr::Identifier(ref id) => {
-                let (sc_idx, mut target) = Self::search_scope(scope, id, |x| Ok(x.box_clone()))?;
-                let value = self.get_dot_val_helper(scope, target.as_mut(), dot_rhs);
-
-                // In case the expression mutated `target`, we need to reassign it because
-                // of the above `clone`.
-                scope[sc_idx].1 = target;
-
-                value
-            }
+            Expr::Identifier(ref id) => match *dot_rhs {
+                // Reading a property doesn't need a copy of the whole target
+                Expr::Identifier(ref prop) => Self::search_scope(scope, id, |x| {
+                    self.call_prop_fn("get$".to_string() + prop, vec![x])
+                })
+                .map(|(_, value)| value),
+                _ => {
+                    let (sc_idx, mut target) =
+                        Self::search_scope(scope, id, |x| Ok(x.box_clone()))?;
+                    let value = self.get_dot_val_helper(scope, target.as_mut(), dot_rhs);
+
+                    // In case the expression mutated `target`, we need to reassign it because
+                    // of the above `clone`.
+                    scope.vars[sc_idx].value = target;
+
+                    value
+                }
+            },
             Expr::Index(ref id, ref idx_raw) => {
                 let (sc_idx, idx, mut target) = self.array_value(scope, id, idx_raw)?;
                 let value = self.get_dot_val_helper(scope, target.as_mut(), dot_rhs);
 
-                // In case the expression mutated `target`, we need to reassign it because
-                // of the above `clone`.
-                scope[sc_idx].1.downcast_mut::<Vec<Box<Any>>>().unwrap()[idx] = target;
+                // Only a method call can mutate `target`; plain getters leave the
+                // array element untouched, so there is nothing to write back.
+                if let Expr::FnCall(_, _) = *dot_rhs {
+                    match scope.vars[sc_idx]
+                        .value
+                        .downcast_mut::<Vec<Dynamic>>()
+                        .and_then(|arr| arr.get_mut(idx))
+                    {
+                        Some(elem) => *elem = target,
+                        None => return Err(EvalAltResult::ErrorIndexMismatch),
+                    }
+                }
 
                 value
             }
@@ -395,17 +846,17 @@ impl Engine {
         &self,
         this_ptr: &mut Any,
         dot_rhs: &Expr,
-        mut source_val: Box<Any>,
-    ) -> Result<Box<Any>, EvalAltResult> {
+        mut source_val: Dynamic,
+    ) -> Result<Dynamic, EvalAltResult> {
         match *dot_rhs {
             Expr::Identifier(ref id) => {
                 let set_fn_name = "set$".to_string() + id;
-                self.call_fn_raw(set_fn_name, vec![this_ptr, source_val.as_mut()])
+                self.call_prop_fn(set_fn_name, vec![this_ptr, source_val.as_mut()])
             }
             Expr::Dot(ref inner_lhs, ref inner_rhs) => match **inner_lhs {
                 Expr::Identifier(ref id) => {
                     let get_fn_name = "get$".to_string() + id;
-                    self.call_fn_raw(get_fn_name, vec![this_ptr])
+                    self.call_prop_fn(get_fn_name, vec![this_ptr])
                         .and_then(|mut v| {
                             self.set_dot_val_helper(v.as_mut(), inner_rhs, source_val)
                                 .map(|_| v) // Discard Ok return value
@@ -413,7 +864,7 @@ impl Engine {
                         .and_then(|mut v| {
                             let set_fn_name = "set$".to_string() + id;
 
-                            self.call_fn_raw(set_fn_name, vec![this_ptr, v.as_mut()])
+                            self.call_prop_fn(set_fn_name, vec![this_ptr, v.as_mut()])
                         })
                 }
                 _ => Err(EvalAltResult::InternalErrorMalformedDotExpression),
@@ -427,26 +878,31 @@ impl Engine {
         scope: &mut Scope,
         dot_lhs: &Expr,
         dot_rhs: &Expr,
-        source_val: Box<Any>,
-    ) -> Result<Box<Any>, EvalAltResult> {
+        source_val: Dynamic,
+    ) -> Result<Dynamic, EvalAltResult> {
         match *dot_lhs {
             Expr::Identifier(ref id) => {
+                scope.writable(id)?;
                 let (sc_idx, mut target) = Self::search_scope(scope, id, |x| Ok(x.box_clone()))?;
                 let value = self.set_dot_val_helper(target.as_mut(), dot_rhs, source_val);
 
                 // In case the expression mutated `target`, we need to reassign it because
                 // of the above `clone`.
-                scope[sc_idx].1 = target;
+                scope.vars[sc_idx].value = target;
 
                 value
             }
             Expr::Index(ref id, ref idx_raw) => {
+                scope.writable(id)?;
                 let (sc_idx, idx, mut target) = self.array_value(scope, id, idx_raw)?;
                 let value = self.set_dot_val_helper(target.as_mut(), dot_rhs, source_val);
 
                 // In case the expression mutated `target`, we need to reassign it because
                 // of the above `clone`.
-                scope[sc_idx].1.downcast_mut::<Vec<Box<Any>>>().unwrap()[idx] = target;
+                scope.vars[sc_idx]
+                    .value
+                    .downcast_mut::<Vec<Dynamic>>()
+                    .unwrap()[idx] = target;
 
                 value
             }
@@ -454,62 +910,61 @@ impl Engine {
         }
     }
 
-    fn eval_expr(&self, scope: &mut Scope, expr: &Expr) -> Result<Box<Any>, EvalAltResult> {
+    fn eval_expr(&self, scope: &mut Scope, expr: &Expr) -> Result<Dynamic, EvalAltResult> {
         match *expr {
             Expr::IntConst(i) => Ok(Box::new(i)),
             Expr::FloatConst(i) => Ok(Box::new(i)),
             Expr::StringConst(ref s) => Ok(Box::new(s.clone())),
             Expr::CharConst(ref c) => Ok(Box::new(*c)),
-            Expr::Identifier(ref id) => {
-                for &mut (ref name, ref mut val) in &mut scope.iter_mut().rev() {
-                    if *id == *name {
-                        return Ok(val.clone());
-                    }
-                }
-                Err(EvalAltResult::ErrorVariableNotFound(id.clone()))
-            }
+            Expr::Identifier(ref id) => scope
+                .get(id)
+                .cloned()
+                .ok_or_else(|| EvalAltResult::ErrorVariableNotFound(id.clone())),
             Expr::Index(ref id, ref idx_raw) => {
                 self.array_value(scope, id, idx_raw).map(|(_, _, x)| x)
             }
+            Expr::IndexValue(ref lhs, ref idx_raw) => {
+                let arr = self
+                    .eval_expr(scope, lhs)?
+                    .downcast::<Vec<Dynamic>>()
+                    .map_err(|_| EvalAltResult::ErrorIndexMismatch)?;
+                let idx = self
+                    .eval_expr(scope, idx_raw)?
+                    .downcast::<i64>()
+                    .map_err(|_| EvalAltResult::ErrorIndexMismatch)?;
+
+                array_index(*idx)
+                    .and_then(|idx| arr.into_iter().nth(idx))
+                    .ok_or(EvalAltResult::ErrorIndexMismatch)
+            }
             Expr::Assignment(ref id, ref rhs) => {
                 let rhs_val = self.eval_expr(scope, rhs)?;
 
                 match **id {
-                    Expr::Identifier(ref n) => {
-                        for &mut (ref name, ref mut val) in &mut scope.iter_mut().rev() {
-                            if *n == *name {
-                                *val = rhs_val;
-
-                                return Ok(Box::new(()));
-                            }
-                        }
-                        Err(EvalAltResult::ErrorVariableNotFound(n.clone()))
-                    }
+                    Expr::Identifier(ref n) => scope.set(n, rhs_val.clone()).map(|_| rhs_val),
                     Expr::Index(ref id, ref idx_raw) => {
                         let idx = self.eval_expr(scope, idx_raw)?;
-
-                        for &mut (ref name, ref mut val) in &mut scope.iter_mut().rev() {
-                            if *id == *name {
-                                if let Some(i) = idx.downcast_ref::<i64>() {
-                                    if let Some(arr_typed) =
-                                        (*val).downcast_mut() as Option<&mut Vec<Box<Any>>>
-                                    {
-                                        arr_typed[*i as usize] = rhs_val;
-                                        return Ok(Box::new(()));
-                                    } else {
-                                        return Err(EvalAltResult::ErrorIndexMismatch);
-                                    }
-                                } else {
-                                    return Err(EvalAltResult::ErrorIndexMismatch);
-                                }
+                        let sc_idx = scope.writable(id)?;
+                        let i = idx
+                            .downcast_ref::<i64>()
+                            .and_then(|i| array_index(*i))
+                            .ok_or(EvalAltResult::ErrorIndexMismatch)?;
+
+                        match scope.vars[sc_idx]
+                            .value
+                            .downcast_mut::<Vec<Dynamic>>()
+                            .and_then(|arr| arr.get_mut(i))
+                        {
+                            Some(item) => {
+                                *item = rhs_val.clone();
+                                Ok(rhs_val)
                             }
+                            None => Err(EvalAltResult::ErrorIndexMismatch),
                         }
-
-                        Err(EvalAltResult::ErrorVariableNotFound(id.clone()))
-                    }
-                    Expr::Dot(ref dot_lhs, ref dot_rhs) => {
-                        self.set_dot_val(scope, dot_lhs, dot_rhs, rhs_val)
                     }
+                    Expr::Dot(ref dot_lhs, ref dot_rhs) => self
+                        .set_dot_val(scope, dot_lhs, dot_rhs, rhs_val.clone())
+                        .map(|_| rhs_val),
                     _ => Err(EvalAltResult::ErrorAssignmentToUnknownLHS),
                 }
             }
@@ -524,27 +979,51 @@ impl Engine {
 
                 Ok(Box::new(arr))
             }
-            Expr::FnCall(ref fn_name, ref args) => self.call_fn_raw(
-                fn_name.to_owned(),
-                args.iter()
+            Expr::FnCall(ref fn_name, ref args)
+                if args.len() == 2 && (fn_name == "&&" || fn_name == "||") =>
+            {
+                // A boolean left operand deciding the result skips the right operand;
+                // other types go through the registered operator as usual
+                let mut lhs = self.eval_expr(scope, &args[0])?;
+                if let Some(&b) = lhs.downcast_ref::<bool>() {
+                    if b == (fn_name == "||") {
+                        return Ok(Box::new(b));
+                    }
+                }
+
+                let mut rhs = self.eval_expr(scope, &args[1])?;
+                self.call_fn_raw(fn_name.clone(), vec![lhs.as_mut(), rhs.as_mut()])
+            }
+            Expr::FnCall(ref fn_name, ref args) => {
+                let mut args = args
+                    .iter()
                     .map(|ex| self.eval_expr(scope, ex))
-                    .collect::<Result<Vec<Box<Any>>, _>>()?
-                    .iter_mut()
-                    .map(|b| b.as_mut())
-                    .collect(),
-            ),
+                    .collect::<Result<Vec<Dynamic>, _>>()?;
+                let args = args.iter_mut().map(|b| b.as_mut()).collect();
+
+                // A variable holding an anonymous function is called in place of a named function
+                let anon_fn = scope
+                    .get(fn_name)
+                    .and_then(|val| val.downcast_ref::<AnonFn>());
+
+                match anon_fn {
+                    Some(f) => self.call_script_fn(&f.0, args),
+                    None => self.call_fn_raw(fn_name.to_owned(), args),
+                }
+            }
+            Expr::AnonFn(ref f) => Ok(Box::new(AnonFn(f.clone()))),
             Expr::True => Ok(Box::new(true)),
             Expr::False => Ok(Box::new(false)),
             Expr::Unit => Ok(Box::new(())),
         }
     }
 
-    fn eval_stmt(&self, scope: &mut Scope, stmt: &Stmt) -> Result<Box<Any>, EvalAltResult> {
+    fn eval_stmt(&self, scope: &mut Scope, stmt: &Stmt) -> Result<Dynamic, EvalAltResult> {
         match *stmt {
             Stmt::Expr(ref e) => self.eval_expr(scope, e),
             Stmt::Block(ref b) => {
-                let prev_len = scope.len();
-                let mut last_result: Result<Box<Any>, EvalAltResult> = Ok(Box::new(()));
+                scope.push_layer();
+                let mut last_result: Result<Dynamic, EvalAltResult> = Ok(Box::new(()));
 
                 for s in b.iter() {
                     last_result = self.eval_stmt(scope, s);
@@ -554,9 +1033,7 @@ impl Engine {
                     }
                 }
 
-                while scope.len() > prev_len {
-                    scope.pop();
-                }
+                scope.pop_layer();
 
                 last_result
             }
@@ -603,6 +1080,43 @@ impl Engine {
                     Err(_) => return Err(EvalAltResult::ErrorIfGuardMismatch),
                 }
             },
+            Stmt::DoWhile(ref body, ref guard) => loop {
+                match self.eval_stmt(scope, body) {
+                    Err(EvalAltResult::LoopBreak) => return Ok(Box::new(())),
+                    Err(x) => return Err(x),
+                    _ => (),
+                }
+
+                let guard_result = self.eval_expr(scope, guard)?;
+                match guard_result.downcast::<bool>() {
+                    Ok(g) => {
+                        if !*g {
+                            return Ok(Box::new(()));
+                        }
+                    }
+                    Err(_) => return Err(EvalAltResult::ErrorIfGuardMismatch),
+                }
+            },
+            Stmt::For(ref name, ref expr, ref body) => {
+                let arr = self
+                    .eval_expr(scope, expr)?
+                    .downcast::<Vec<Dynamic>>()
+                    .map_err(|_| EvalAltResult::ErrorForMismatch)?;
+                for item in arr.into_iter() {
+                    scope.push_layer();
+                    scope.push_var(name.clone(), item);
+                    let result = self.eval_stmt(scope, body);
+                    scope.pop_layer();
+
+                    match result {
+                        Err(EvalAltResult::LoopBreak) => break,
+                        Err(x) => return Err(x),
+                        _ => (),
+                    }
+                }
+
+                Ok(Box::new(()))
+            }
             Stmt::Loop(ref body) => loop {
                 match self.eval_stmt(scope, body) {
                     Err(EvalAltResult::LoopBreak) => return Ok(Box::new(())),
@@ -610,6 +1124,26 @@ impl Engine {
                     _ => (),
                 }
             },
+            Stmt::Switch(ref value, ref arms) => {
+                let mut value = self.eval_expr(scope, value)?;
+
+                for &(ref pattern, ref body) in arms.iter() {
+                    let matched = match *pattern {
+                        Some(ref pattern) => {
+                            let mut pattern = self.eval_expr(scope, pattern)?;
+
+                            self.values_equal(value.as_mut(), pattern.as_mut())?
+                        }
+                        None => true,
+                    };
+
+                    if matched {
+                        return self.eval_stmt(scope, body);
+                    }
+                }
+
+                Ok(Box::new(()))
+            }
             Stmt::Break => Err(EvalAltResult::LoopBreak),
             Stmt::Return => Err(EvalAltResult::Return(Box::new(()))),
             Stmt::ReturnWithVal(ref a) => {
@@ -620,120 +1154,599 @@ impl Engine {
                 match *init {
                     Some(ref v) => {
                         let i = self.eval_expr(scope, v)?;
-                        scope.push((name.clone(), i));
+                        scope.push_var(name.clone(), i);
                     }
-                    None => scope.push((name.clone(), Box::new(()))),
+                    None => scope.push_var(name.clone(), Box::new(())),
                 };
                 Ok(Box::new(()))
             }
         }
     }
 
-    fn nice_type_name(&self, b: Box<Any>) -> String {
-        let tid = <Any as Any>::type_id(&*b);
-        if let Some(name) = self.type_names.get(&tid) {
-            name.to_string()
-        } else {
-            format!("<unknown> {:?}", b.type_id())
-        }
-    }
+    fn nice_type_name(&self, b: Dynamic) -> String {
+        // A script array whose elements all have the same named type is named after it
+        if let Some(arr) = b.downcast_ref::<Vec<Dynamic>>() {
+            let mut tids = arr.iter().map(|x| <Any as Any>::type_id(&**x));
 
-    /// Evaluate a file
-    pub fn eval_file<T: Any + Clone>(&mut self, fname: &str) -> Result<T, EvalAltResult> {
-        use std::fs::File;
-        use std::io::prelude::*;
+            if let Some(first) = tids.next() {
+                if let Some(name) = self.type_names.get(&first) {
+                    if tids.all(|tid| tid == first) {
+                        return format!("array<{}>", name);
+                    }
+                }
+            }
+        }
 
-        if let Ok(mut f) = File::open(fname) {
-            let mut contents = String::new();
+        self.type_name_of(<Any as Any>::type_id(&*b))
+    }
 
-            if f.read_to_string(&mut contents).is_ok() {
-                self.eval::<T>(&contents)
-            } else {
-                Err(EvalAltResult::ErrorCantOpenScriptFile)
-            }
+    fn type_name_of(&self, tid: TypeId) -> String {
+        if let Some(name) = self.type_names.get(&tid) {
+            name.to_string()
         } else {
-            Err(EvalAltResult::ErrorCantOpenScriptFile)
+            format!("<unknown> {:?}", tid)
         }
     }
 
-    /// Evaluate a string
-    pub fn eval<T: Any + Clone>(&mut self, input: &str) -> Result<T, EvalAltResult> {
-        let mut scope: Scope = Vec::new();
-
-        self.eval_with_scope(&mut scope, input)
+    /// List the signatures of every callable function, e.g. `+(integer, integer)`,
+    /// sorted by name then by argument types so that the listing is stable.
+    /// Script functions show their parameter names instead of types.
+    pub fn list_functions(&self) -> Vec<String> {
+        self.signatures(|_| true)
     }
 
-    /// Evaluate with own scope
-    pub fn eval_with_scope<T: Any + Clone>(
-        &mut self,
-        scope: &mut Scope,
-        input: &str,
-    ) -> Result<T, EvalAltResult> {
-        let tokens = lex(input);
+    /// The signatures of `list_functions` whose spec is kept by the predicate
+    fn signatures<P: Fn(&FnSpec) -> bool>(&self, keep: P) -> Vec<String> {
+        let mut signatures: Vec<(&str, String)> = self
+            .fns
+            .iter()
+            .filter(|&(spec, _)| keep(spec))
+            .map(|(spec, f)| {
+                let args = match (&spec.args, &**f) {
+                    (&Some(ref args), _) => args
+                        .iter()
+                        .map(|tid| self.type_name_of(*tid))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    (&None, &FnIntExt::Int(ref def)) => def.params.join(", "),
+                    (&None, &FnIntExt::Ext(_)) => "...".to_string(),
+                };
 
-        let mut peekables = tokens.peekable();
-        let tree = parse(&mut peekables);
+                (spec.ident.as_str(), args)
+            })
+            .collect();
 
-        match tree {
-            Ok((ref os, ref fns)) => {
-                let mut x: Result<Box<Any>, EvalAltResult> = Ok(Box::new(()));
+        signatures.sort();
 
-                for f in fns {
-                    let name = f.name.clone();
-                    let local_f = f.clone();
+        signatures
+            .into_iter()
+            .map(|(ident, args)| format!("{}({})", ident, args))
+            .collect()
+    }
 
-                    let spec = FnSpec {
-                        ident: name,
-                        args: None,
-                    };
+    /// Print every signature of `list_functions` through the registered `print` function
+    fn print_help(&self) -> Result<Dynamic, EvalAltResult> {
+        for mut signature in self.list_functions() {
+            self.call_fn_raw("print".to_string(), vec![&mut signature])?;
+        }
 
-                    self.fns.insert(spec, Arc::new(FnIntExt::Int(local_f)));
-                }
+        Ok(Box::new(()))
+    }
 
-                for o in os {
-                    x = match self.eval_stmt(scope, o) {
-                        Ok(v) => Ok(v),
-                        Err(e) => return Err(e),
-                    }
+    /// Runs `map(arr, fn)`, `filter(arr, fn)` or `reduce(arr, init, fn)`, where `fn` is either
+    /// the name of the function to call or an anonymous function. Returns `None` if the call is
+    /// none of these.
+    fn call_array_fn(
+        &self,
+        ident: &str,
+        args: &[&mut Any],
+    ) -> Option<Result<Dynamic, EvalAltResult>> {
+        let arr = args.first()?.downcast_ref::<Vec<Dynamic>>()?;
+
+        // An array is written with the `to_string` of its elements, and printed
+        // by the `print` registered for strings; its elements are compared with
+        // their own `==`
+        match (ident, args.len()) {
+            ("to_string", 1) => {
+                return Some(self.array_to_string(arr).map(|s| Box::new(s) as Dynamic));
+            }
+            ("print", 1) => {
+                return Some(
+                    self.array_to_string(arr)
+                        .and_then(|mut s| self.call_fn_raw(ident.to_string(), vec![&mut s])),
+                );
+            }
+            ("is_array_of_type", 2) => {
+                if let Some(name) = args[1].downcast_ref::<String>() {
+                    return Some(
+                        self.array_of_type(arr, name)
+                            .map(|all| Box::new(all) as Dynamic),
+                    );
                 }
-
-                let x = x?;
-
-                match x.downcast::<T>() {
-                    Ok(out) => Ok(*out),
-                    Err(a) => Err(EvalAltResult::ErrorMismatchOutputType(
-                        self.nice_type_name(a),
-                    )),
+            }
+            ("contains", 2) => {
+                return Some(
+                    self.array_contains(arr, args[1])
+                        .map(|found| Box::new(found) as Dynamic),
+                );
+            }
+            ("==", 2) | ("!=", 2) => {
+                if let Some(other) = args[1].downcast_ref::<Vec<Dynamic>>() {
+                    return Some(
+                        self.arrays_equal(arr, other)
+                            .map(|equal| Box::new(equal == (ident == "==")) as Dynamic),
+                    );
                 }
             }
-            Err(_) => Err(EvalAltResult::ErrorFunctionArgMismatch),
+            _ => {}
         }
-    }
 
-    /// Evaluate a file, but only return errors, if there are any.
-    /// Useful for when you don't need the result, but still need
-    /// to keep track of possible errors
-    pub fn consume_file(&mut self, fname: &str) -> Result<(), EvalAltResult> {
+        let &(_, _, array_fn) = HIGHER_ORDER_FNS
+            .iter()
+            .find(|&&(name, arity, _)| name == ident && arity == args.len())?;
+        let callee = args.last()?;
+
+        let call: &ArrayFnCallback = if let Some(name) = callee.downcast_ref::<String>() {
+            &move |args| self.call_fn_raw(name.clone(), args)
+        } else if let Some(f) = callee.downcast_ref::<AnonFn>() {
+            &move |args| self.call_script_fn(&f.0, args)
+        } else {
+            return None;
+        };
+
+        Some(array_fn(self, arr, args, call))
+    }
+
+    /// Arrays may mix types, e.g. `[1, 2.0, "x"]`; this checks that every element has the type
+    /// named `name`, as in error messages. Names are looked up when called, so types named
+    /// after the engine was created are known too
+    fn array_of_type(&self, arr: &[Dynamic], name: &str) -> Result<bool, EvalAltResult> {
+        match self.type_names.iter().find(|&(_, n)| n == name) {
+            Some((tid, _)) => Ok(arr.iter().all(|x| <Any as Any>::type_id(&**x) == *tid)),
+            None => Err(EvalAltResult::ErrorRuntime(format!(
+                "unknown type name: {}",
+                name
+            ))),
+        }
+    }
+
+    /// Compares two values with the registered `==`; values of different types, or of a type
+    /// without `==`, are simply not equal
+    fn values_equal(&self, a: &mut Any, b: &mut Any) -> Result<bool, EvalAltResult> {
+        match self.call_fn_raw("==".to_string(), vec![a, b]) {
+            Ok(result) => Ok(result.downcast::<bool>().map(|b| *b).unwrap_or(false)),
+            Err(EvalAltResult::ErrorFunctionNotFound(_))
+            | Err(EvalAltResult::ErrorFunctionArgTypes(_, _, _)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn arrays_equal(&self, a: &[Dynamic], b: &[Dynamic]) -> Result<bool, EvalAltResult> {
+        if a.len() != b.len() {
+            return Ok(false);
+        }
+
+        for (x, y) in a.iter().zip(b) {
+            if !self.values_equal(&mut *x.clone(), &mut *y.clone())? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn array_contains(&self, arr: &[Dynamic], value: &Any) -> Result<bool, EvalAltResult> {
+        for x in arr {
+            if self.values_equal(&mut *x.clone(), &mut *value.box_clone())? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn array_to_string(&self, arr: &[Dynamic]) -> Result<String, EvalAltResult> {
+        let mut parts = Vec::new();
+
+        for x in arr {
+            let part = self
+                .call_fn_raw("to_string".to_string(), vec![&mut *x.clone()])?
+                .downcast::<String>()
+                .map_err(|_| {
+                    EvalAltResult::ErrorRuntime("to_string must return a string".to_string())
+                })?;
+
+            parts.push(*part);
+        }
+
+        Ok(format!("[{}]", parts.join(", ")))
+    }
+
+    fn map_array(
+        &self,
+        arr: &[Dynamic],
+        _: &[&mut Any],
+        call: &ArrayFnCallback,
+    ) -> Result<Dynamic, EvalAltResult> {
+        let mut mapped = Vec::new();
+
+        for x in arr {
+            mapped.push(call(vec![&mut *x.clone()])?);
+        }
+
+        Ok(Box::new(mapped))
+    }
+
+    fn filter_array(
+        &self,
+        arr: &[Dynamic],
+        _: &[&mut Any],
+        call: &ArrayFnCallback,
+    ) -> Result<Dynamic, EvalAltResult> {
+        let mut kept = Vec::new();
+
+        for x in arr {
+            let keep = call(vec![&mut *x.clone()])?
+                .downcast::<bool>()
+                .map_err(|_| {
+                    EvalAltResult::ErrorRuntime(
+                        "the function passed to filter must return a boolean".to_string(),
+                    )
+                })?;
+
+            if *keep {
+                kept.push(x.clone());
+            }
+        }
+
+        Ok(Box::new(kept))
+    }
+
+    fn reduce_array(
+        &self,
+        arr: &[Dynamic],
+        args: &[&mut Any],
+        call: &ArrayFnCallback,
+    ) -> Result<Dynamic, EvalAltResult> {
+        let mut acc = args[1].box_clone();
+
+        for x in arr {
+            acc = call(vec![&mut *acc, &mut *x.clone()])?;
+        }
+
+        Ok(acc)
+    }
+
+    /// Replace every top-level `include("path");` statement with the statements of that file,
+    /// and add its functions to `fns`, so that the whole runs as one script. Each file is
+    /// expanded once, and include cycles are refused
+    fn expand_includes(
+        &self,
+        os: Vec<Stmt>,
+        mut fns: Vec<FnDef>,
+        includes: &mut Includes,
+    ) -> Result<(Vec<Stmt>, Vec<FnDef>), EvalAltResult> {
+        let mut expanded = Vec::with_capacity(os.len());
+
+        for o in os {
+            match include_path(&o) {
+                Some(fname) => {
+                    let (included_os, included_fns) = self
+                        .read_include(&fname, includes)
+                        .map_err(|e| EvalAltResult::ErrorInFile(fname.clone(), Box::new(e)))?;
+
+                    expanded.extend(included_os);
+                    fns.extend(included_fns);
+                }
+                None => expanded.push(o),
+            }
+        }
+
+        Ok((expanded, fns))
+    }
+
+    fn read_include(
+        &self,
+        fname: &str,
+        includes: &mut Includes,
+    ) -> Result<(Vec<Stmt>, Vec<FnDef>), EvalAltResult> {
+        use std::fs::{self, File};
+
+        let path = fs::canonicalize(self.resolve_path(fname))
+            .map_err(|_| EvalAltResult::ErrorCantOpenScriptFile)?;
+        if includes.stack.contains(&path) {
+            return Err(EvalAltResult::ErrorIncludeCycle);
+        }
+        if !includes.done.insert(path.clone()) {
+            return Ok((Vec::new(), Vec::new()));
+        }
+
+        let file = File::open(&path).map_err(|_| EvalAltResult::ErrorCantOpenScriptFile)?;
+        let (os, fns) = parse_reader(file)?.map_err(|_| EvalAltResult::ErrorFunctionArgMismatch)?;
+
+        includes.stack.push(path);
+        let result = self.expand_includes(os, fns, includes);
+        includes.stack.pop();
+
+        result
+    }
+
+    /// Make the functions defined by a script callable. Script functions are looked up by name
+    /// only, so a script defining the same name twice is refused rather than keeping the last one;
+    /// redefining a function retained from a previous run is fine, the function it replaces is
+    /// returned along with each spec
+    fn define_script_fns(
+        &mut self,
+        fns: &[FnDef],
+    ) -> Result<Vec<(FnSpec, Option<Arc<FnIntExt>>)>, EvalAltResult> {
+        let mut names = HashSet::new();
+        if let Some(f) = fns.iter().find(|f| !names.insert(&f.name)) {
+            return Err(EvalAltResult::ErrorDuplicateFunction(f.name.clone()));
+        }
+
+        Ok(fns
+            .iter()
+            .map(|f| {
+                let spec = FnSpec {
+                    ident: f.name.clone(),
+                    args: None,
+                };
+
+                let replaced = self
+                    .fns
+                    .insert(spec.clone(), Arc::new(FnIntExt::Int(f.clone())));
+                (spec, replaced)
+            })
+            .collect())
+    }
+
+    /// Drop the functions defined by a script once it has run, unless they must be retained.
+    /// The functions they replaced, retained from a previous run, are put back
+    fn forget_script_fns(&mut self, specs: Vec<(FnSpec, Option<Arc<FnIntExt>>)>) {
+        if !self.retain_functions {
+            for (spec, replaced) in specs {
+                match replaced {
+                    Some(f) => self.fns.insert(spec, f),
+                    None => self.fns.remove(&spec),
+                };
+            }
+        }
+    }
+
+    /// Evaluate a file
+    pub fn eval_file<T: Any + Clone>(&mut self, fname: &str) -> Result<T, EvalAltResult> {
+        self.eval_file_raw(fname).and_then(|b| {
+            b.downcast().map(|b| *b).map_err(|a| {
+                EvalAltResult::ErrorInFile(
+                    fname.to_string(),
+                    Box::new(EvalAltResult::ErrorMismatchOutputType(
+                        self.nice_type_name(a),
+                    )),
+                )
+            })
+        })
+    }
+
+    /// Evaluate a file, without casting the result
+    pub fn eval_file_raw(&mut self, fname: &str) -> Result<Dynamic, EvalAltResult> {
+        self.eval_file_unnamed(fname)
+            .map_err(|e| EvalAltResult::ErrorInFile(fname.to_string(), Box::new(e)))
+    }
+
+    /// Set the directory `eval_file` and `consume_file` resolve relative paths against,
+    /// instead of the current directory. Errors still name the file as it was given
+    pub fn set_base_dir<P: Into<PathBuf>>(&mut self, path: P) {
+        self.base_dir = Some(path.into());
+    }
+
+    fn resolve_path(&self, fname: &str) -> PathBuf {
+        match self.base_dir {
+            // An absolute `fname` replaces the base directory
+            Some(ref dir) => dir.join(fname),
+            None => PathBuf::from(fname),
+        }
+    }
+
+    /// Evaluate a file; errors are wrapped with the file name by the callers
+    fn eval_file_unnamed(&mut self, fname: &str) -> Result<Dynamic, EvalAltResult> {
         use std::fs::File;
-        use std::io::prelude::*;
 
-        if let Ok(mut f) = File::open(fname) {
-            let mut contents = String::new();
+        let path = self.resolve_path(fname);
+        match File::open(&path) {
+            Ok(f) => {
+                let tree = parse_reader(f)?;
+                self.eval_parsed(&mut Scope::new(), tree, Includes::of_file(&path))
+            }
+            Err(_) => Err(EvalAltResult::ErrorCantOpenScriptFile),
+        }
+    }
+
+    /// Evaluate a script read from `reader`. The script is lexed as it is read, so its text
+    /// is never held in memory as a whole, e.g. for large generated scripts
+    pub fn eval_reader<T: Any + Clone, R: Read>(&mut self, reader: R) -> Result<T, EvalAltResult> {
+        let x = self.eval_reader_raw(&mut Scope::new(), reader)?;
 
-            if f.read_to_string(&mut contents).is_ok() {
-                if let e @ Err(_) = self.consume(&contents) {
-                    e
-                } else {
-                    Ok(())
+        match x.downcast::<T>() {
+            Ok(out) => Ok(*out),
+            Err(a) => Err(EvalAltResult::ErrorMismatchOutputType(
+                self.nice_type_name(a),
+            )),
+        }
+    }
+
+    /// Evaluate a script read from `reader` with own scope, without casting the result
+    pub fn eval_reader_raw<R: Read>(
+        &mut self,
+        scope: &mut Scope,
+        reader: R,
+    ) -> Result<Dynamic, EvalAltResult> {
+        let tree = parse_reader(reader)?;
+        self.eval_parsed(scope, tree, Includes::default())
+    }
+
+    /// Evaluate a string
+    pub fn eval<T: Any + Clone>(&mut self, input: &str) -> Result<T, EvalAltResult> {
+        let mut scope = Scope::new();
+
+        self.eval_with_scope(&mut scope, input)
+    }
+
+    /// Evaluate a script given as bytes, e.g. read from a block, which must be valid UTF-8
+    pub fn eval_bytes<T: Any + Clone>(&mut self, input: &[u8]) -> Result<T, EvalAltResult> {
+        match ::std::str::from_utf8(input) {
+            Ok(input) => self.eval(input),
+            Err(e) => Err(EvalAltResult::ErrorScriptNotUtf8(e.to_string())),
+        }
+    }
+
+    /// Evaluate a string, without casting the result
+    pub fn eval_dynamic(&mut self, input: &str) -> Result<Dynamic, EvalAltResult> {
+        self.eval_with_scope_raw(&mut Scope::new(), input)
+    }
+
+    /// Evaluate a string to a boolean
+    pub fn eval_bool(&mut self, input: &str) -> Result<bool, EvalAltResult> {
+        self.eval::<bool>(input)
+    }
+
+    /// Evaluate a string to an integer
+    pub fn eval_i64(&mut self, input: &str) -> Result<i64, EvalAltResult> {
+        self.eval::<i64>(input)
+    }
+
+    /// Evaluate a string to a float
+    pub fn eval_f64(&mut self, input: &str) -> Result<f64, EvalAltResult> {
+        self.eval::<f64>(input)
+    }
+
+    /// Evaluate a string to a string
+    pub fn eval_string(&mut self, input: &str) -> Result<String, EvalAltResult> {
+        self.eval::<String>(input)
+    }
+
+    /// Evaluate with own scope
+    pub fn eval_with_scope<T: Any + Clone>(
+        &mut self,
+        scope: &mut Scope,
+        input: &str,
+    ) -> Result<T, EvalAltResult> {
+        let x = self.eval_with_scope_raw(scope, input)?;
+
+        match x.downcast::<T>() {
+            Ok(out) => Ok(*out),
+            Err(a) => Err(EvalAltResult::ErrorMismatchOutputType(
+                self.nice_type_name(a),
+            )),
+        }
+    }
+
+    /// Evaluate a single expression with own scope, e.g. a guard configured by the host.
+    /// Statements such as `let` or `if`, and anything following the expression, are refused.
+    /// An assignment anywhere in the expression gives `ErrorAssignmentInExpression`
+    pub fn eval_expression<T: Any + Clone>(
+        &self,
+        scope: &mut Scope,
+        input: &str,
+    ) -> Result<T, EvalAltResult> {
+        let expr = parse_expression(&mut lex(input).peekable())
+            .map_err(|_| EvalAltResult::ErrorFunctionArgMismatch)?;
+        // compound assignments like `x += 1` are parsed as assignments too
+        if has_assignment(&expr) {
+            return Err(EvalAltResult::ErrorAssignmentInExpression);
+        }
+        let x = self.eval_expr(scope, &expr)?;
+
+        match x.downcast::<T>() {
+            Ok(out) => Ok(*out),
+            Err(a) => Err(EvalAltResult::ErrorMismatchOutputType(
+                self.nice_type_name(a),
+            )),
+        }
+    }
+
+    /// Evaluate with own scope, without casting the result.
+    /// Useful when the type of the result isn't known in advance, e.g. in a REPL
+    pub fn eval_with_scope_raw(
+        &mut self,
+        scope: &mut Scope,
+        input: &str,
+    ) -> Result<Dynamic, EvalAltResult> {
+        let tree = parse(&mut lex(input).peekable());
+        self.eval_parsed(scope, tree, Includes::default())
+    }
+
+    fn eval_parsed(
+        &mut self,
+        scope: &mut Scope,
+        tree: Result<(Vec<Stmt>, Vec<FnDef>), ParseError>,
+        mut includes: Includes,
+    ) -> Result<Dynamic, EvalAltResult> {
+        match tree {
+            Ok((os, fns)) => {
+                let (os, fns) = self.expand_includes(os, fns, &mut includes)?;
+                let mut x: Result<Dynamic, EvalAltResult> = Ok(Box::new(()));
+
+                let specs = self.define_script_fns(&fns)?;
+
+                for o in &os {
+                    x = self.eval_stmt(scope, o);
+
+                    if x.is_err() {
+                        break;
+                    }
                 }
-            } else {
-                Err(EvalAltResult::ErrorCantOpenScriptFile)
+
+                self.forget_script_fns(specs);
+
+                x
             }
-        } else {
-            Err(EvalAltResult::ErrorCantOpenScriptFile)
+            Err(_) => Err(EvalAltResult::ErrorFunctionArgMismatch),
+        }
+    }
+
+    /// Parses a script without evaluating it, and renders its function definitions
+    /// and statements as an indented tree. Useful to check how a script was parsed.
+    pub fn dump_ast(&self, input: &str) -> Result<String, EvalAltResult> {
+        let mut peekables = lex(input).peekable();
+
+        match parse(&mut peekables) {
+            Ok((ref os, ref fns)) => {
+                let mut out: String = fns.iter().map(dump_fn_def).collect();
+                out.push_str(&dump_ast(os));
+                Ok(out)
+            }
+            Err(_) => Err(EvalAltResult::ErrorFunctionArgMismatch),
+        }
+    }
+
+    /// Evaluate a file, but only return errors, if there are any.
+    /// Useful for when you don't need the result, but still need
+    /// to keep track of possible errors
+    pub fn consume_file(&mut self, fname: &str) -> Result<(), EvalAltResult> {
+        self.consume_file_unnamed(fname)
+            .map_err(|e| EvalAltResult::ErrorInFile(fname.to_string(), Box::new(e)))
+    }
+
+    /// Consume a file; errors are wrapped with the file name by `consume_file`
+    fn consume_file_unnamed(&mut self, fname: &str) -> Result<(), EvalAltResult> {
+        use std::fs::File;
+
+        let path = self.resolve_path(fname);
+        match File::open(&path) {
+            Ok(f) => {
+                let tree = parse_reader(f)?;
+                self.consume_parsed(&mut Scope::new(), tree, Includes::of_file(&path))
+            }
+            Err(_) => Err(EvalAltResult::ErrorCantOpenScriptFile),
         }
     }
 
+    /// Consume a script read from `reader`, lexing it as it is read like `eval_reader`
+    pub fn consume_reader<R: Read>(&mut self, reader: R) -> Result<(), EvalAltResult> {
+        let tree = parse_reader(reader)?;
+        self.consume_parsed(&mut Scope::new(), tree, Includes::default())
+    }
+
     /// Evaluate a string, but only return errors, if there are any.
     /// Useful for when you don't need the result, but still need
     /// to keep track of possible errors
@@ -749,35 +1762,36 @@ impl Engine {
         scope: &mut Scope,
         input: &str,
     ) -> Result<(), EvalAltResult> {
-        let tokens = lex(input);
-
-        let mut peekables = tokens.peekable();
-        let tree = parse(&mut peekables);
+        let tree = parse(&mut lex(input).peekable());
+        self.consume_parsed(scope, tree, Includes::default())
+    }
 
+    fn consume_parsed(
+        &mut self,
+        scope: &mut Scope,
+        tree: Result<(Vec<Stmt>, Vec<FnDef>), ParseError>,
+        mut includes: Includes,
+    ) -> Result<(), EvalAltResult> {
         match tree {
-            Ok((ref os, ref fns)) => {
-                for f in fns {
-                    if f.params.len() > 6 {
-                        return Ok(());
-                    }
-                    let name = f.name.clone();
-                    let local_f = f.clone();
-
-                    let spec = FnSpec {
-                        ident: name,
-                        args: None,
-                    };
-
-                    self.fns.insert(spec, Arc::new(FnIntExt::Int(local_f)));
+            Ok((os, fns)) => {
+                if let Some(f) = fns.iter().find(|f| f.params.len() > 6) {
+                    return Err(EvalAltResult::ErrorTooManyParameters(f.name.clone()));
                 }
 
-                for o in os {
+                let (os, fns) = self.expand_includes(os, fns, &mut includes)?;
+                let specs = self.define_script_fns(&fns)?;
+                let mut result = Ok(());
+
+                for o in &os {
                     if let Err(e) = self.eval_stmt(scope, o) {
-                        return Err(e);
+                        result = Err(e);
+                        break;
                     }
                 }
 
-                Ok(())
+                self.forget_script_fns(specs);
+
+                result
             }
             Err(_) => Err(EvalAltResult::ErrorFunctionArgMismatch),
         }
@@ -790,13 +1804,15 @@ impl Engine {
         engine.register_type_name::<u32>("u32");
         engine.register_type_name::<i64>("integer");
         engine.register_type_name::<u64>("u64");
-        engine.register_type_name::<u64>("usize");
-        engine.register_type_name::<f32>("f64");
+        engine.register_type_name::<usize>("usize");
+        engine.register_type_name::<f32>("f32");
         engine.register_type_name::<f64>("float");
         engine.register_type_name::<String>("string");
         engine.register_type_name::<char>("char");
         engine.register_type_name::<bool>("boolean");
-        engine.register_type_name::<Vec<Box<Any>>>("array");
+        engine.register_type_name::<()>("unit");
+        engine.register_type_name::<Vec<Dynamic>>("array");
+        engine.register_type_name::<AnonFn>("function");
 
         macro_rules! reg_op {
             ($engine:expr, $x:expr, $op:expr, $( $y:ty ),*) => (
@@ -822,6 +1838,38 @@ impl Engine {
             )
         }
 
+        macro_rules! reg_to_string {
+            ($engine:expr, $( $y:ty ),*) => (
+                $(
+                    $engine.register_fn("to_string", |x: $y| x.to_string());
+                )*
+            )
+        }
+
+        macro_rules! reg_assert_eq {
+            ($engine:expr, $( $y:ty ),*) => (
+                $(
+                    $engine.register_fn_raw(
+                        "assert_eq".to_string(),
+                        Some(vec![TypeId::of::<$y>(), TypeId::of::<$y>()]),
+                        Box::new(|args: Vec<&mut Any>| {
+                            let left = args[0].downcast_ref::<$y>().unwrap();
+                            let right = args[1].downcast_ref::<$y>().unwrap();
+
+                            if left == right {
+                                Ok(Box::new(()))
+                            } else {
+                                Err(EvalAltResult::ErrorAssertionFailed(format!(
+                                    "left: {:?}, right: {:?}",
+                                    left, right
+                                )))
+                            }
+                        }),
+                    );
+                )*
+            )
+        }
+
         fn add<T: Add>(x: T, y: T) -> <T as Add>::Output {
             x + y
         }
@@ -867,6 +1915,10 @@ impl Engine {
         fn concat(x: String, y: String) -> String {
             x + &y
         }
+        fn concat_arrays(mut x: Vec<Dynamic>, y: Vec<Dynamic>) -> Vec<Dynamic> {
+            x.extend(y);
+            x
+        }
         fn binary_and<T: BitAnd>(x: T, y: T) -> <T as BitAnd>::Output {
             x & y
         }
@@ -903,19 +1955,20 @@ impl Engine {
         reg_op!(engine, "*", mul, i32, i64, u32, u64, f32, f64);
         reg_op!(engine, "/", div, i32, i64, u32, u64, f32, f64);
 
-        reg_cmp!(engine, "<", lt, i32, i64, u32, u64, String, f64);
-        reg_cmp!(engine, "<=", lte, i32, i64, u32, u64, String, f64);
-        reg_cmp!(engine, ">", gt, i32, i64, u32, u64, String, f64);
-        reg_cmp!(engine, ">=", gte, i32, i64, u32, u64, String, f64);
-        reg_cmp!(engine, "==", eq, i32, i64, u32, u64, bool, String, f64);
-        reg_cmp!(engine, "!=", ne, i32, i64, u32, u64, bool, String, f64);
+        // Every type supporting arithmetic above can also be compared
+        reg_cmp!(engine, "<", lt, i32, i64, u32, u64, String, char, f32, f64);
+        reg_cmp!(engine, "<=", lte, i32, i64, u32, u64, String, char, f32, f64);
+        reg_cmp!(engine, ">", gt, i32, i64, u32, u64, String, char, f32, f64);
+        reg_cmp!(engine, ">=", gte, i32, i64, u32, u64, String, char, f32, f64);
+        reg_cmp!(engine, "==", eq, i32, i64, u32, u64, bool, String, char, f32, f64);
+        reg_cmp!(engine, "!=", ne, i32, i64, u32, u64, bool, String, char, f32, f64);
 
+        // `&&` and `||` are the logical operators, short-circuiting in `eval_expr`;
+        // `&`, `|` and `^` are bitwise and only apply to integers
         reg_op!(engine, "||", or, bool);
         reg_op!(engine, "&&", and, bool);
         reg_op!(engine, "|", binary_or, i32, i64, u32, u64);
-        reg_op!(engine, "|", or, bool);
         reg_op!(engine, "&", binary_and, i32, i64, u32, u64);
-        reg_op!(engine, "&", and, bool);
         reg_op!(engine, "^", binary_xor, i32, i64, u32, u64);
         reg_op!(engine, "<<", left_shift, i32, i64, u32, u64);
         reg_op!(engine, ">>", right_shift, i32, i64, u32, u64);
@@ -928,8 +1981,41 @@ impl Engine {
         reg_un!(engine, "!", not, bool);
 
         engine.register_fn("+", concat);
+        engine.register_fn("+", concat_arrays);
         engine.register_fn("==", unit_eq);
 
+        engine.register_fn("char_to_int", |c: char| c as i64);
+        engine.register_result_fn("int_to_char", |i: i64| {
+            if i < 0 || i > u32::MAX as i64 {
+                return Err(EvalAltResult::ErrorRuntime(format!(
+                    "invalid char code: {}",
+                    i
+                )));
+            }
+
+            ::std::char::from_u32(i as u32)
+                .ok_or_else(|| EvalAltResult::ErrorRuntime(format!("invalid char code: {}", i)))
+        });
+
+        engine.register_fn_raw(
+            "assert".to_string(),
+            Some(vec![TypeId::of::<bool>()]),
+            Box::new(|args: Vec<&mut Any>| {
+                if *args[0].downcast_ref::<bool>().unwrap() {
+                    Ok(Box::new(()))
+                } else {
+                    Err(EvalAltResult::ErrorAssertionFailed(
+                        "condition is false".to_string(),
+                    ))
+                }
+            }),
+        );
+        reg_assert_eq!(engine, i32, i64, u32, u64, f32, f64, bool, String, char);
+
+        // Used by string interpolation, `"${x}"` being `to_string(x)`
+        reg_to_string!(engine, i32, i64, u32, u64, f32, f64, bool, String, char);
+        engine.register_fn("to_string", |_: ()| String::new());
+
         // engine.register_fn("[]", idx);
         // FIXME?  Registering array lookups are a special case because we want to return boxes
         // directly let ent = engine.fns.entry("[]".to_string()).or_insert_with(Vec::new);
@@ -938,13 +2024,1516 @@ impl Engine {
 
     /// Make a new engine
     pub fn new() -> Engine {
-        let mut engine = Engine {
+        let mut engine = Engine::new_raw();
+
+        Engine::register_default_lib(&mut engine);
+
+        engine
+    }
+
+    /// Make a new engine without the default library, not even operators, so that scripts
+    /// can only call the functions registered afterwards, e.g. to sandbox untrusted scripts.
+    /// `register_default_lib` can still be called on it
+    pub fn new_raw() -> Engine {
+        Engine {
             fns: HashMap::new(),
             type_names: HashMap::new(),
-        };
+            registered_types: HashSet::new(),
+            retain_functions: true,
+            strict_registration: false,
+            coerce_numeric_results: false,
+            base_dir: None,
+        }
+    }
+}
 
-        Engine::register_default_lib(&mut engine);
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Clone)]
+    struct TestStruct {
+        x: i64,
+    }
+
+    impl TestStruct {
+        fn get_x(&mut self) -> i64 {
+            self.x
+        }
+
+        fn update(&mut self) {
+            self.x += 1000;
+        }
+
+        fn new() -> TestStruct {
+            TestStruct { x: 1 }
+        }
+    }
+
+    fn test_engine() -> Engine {
+        let mut engine = Engine::new();
+
+        engine.register_type::<TestStruct>();
+        engine.register_get("x", TestStruct::get_x);
+        engine.register_fn("update", TestStruct::update);
+        engine.register_fn("new_ts", TestStruct::new);
 
         engine
     }
+
+    #[test]
+    fn index_dot_get_does_not_corrupt_array() {
+        let mut engine = test_engine();
+
+        assert_eq!(
+            engine.eval::<i64>(
+                "let a = [new_ts(), new_ts()]; let i = 0; let sum = 0; \
+                 while i < 3 { sum = sum + a[0].x; i = i + 1; } \
+                 sum + a[1].x"
+            ),
+            Ok(4)
+        );
+        assert_eq!(
+            engine.eval::<i64>("let a = [new_ts()]; a[0].update(); a[0].x"),
+            Ok(1001)
+        );
+    }
+
+    #[test]
+    fn property_on_unregistered_type() {
+        let mut engine = Engine::new();
+        engine.register_fn("new_ts", TestStruct::new);
+
+        match engine.eval::<i64>("let a = new_ts(); a.x") {
+            Err(EvalAltResult::ErrorTypeNotRegistered(_)) => (),
+            other => panic!("expected ErrorTypeNotRegistered, got {:?}", other),
+        }
+
+        engine.register_type_name::<TestStruct>("TestStruct");
+
+        match engine.eval::<i64>("let a = new_ts(); a.x") {
+            Err(EvalAltResult::ErrorFunctionNotFound(_)) => (),
+            other => panic!("expected ErrorFunctionNotFound, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn assert_builtins() {
+        let mut engine = Engine::new();
+
+        assert_eq!(engine.eval::<()>("assert(1 == 1)"), Ok(()));
+        assert_eq!(
+            engine.eval::<()>("assert(1 == 2)"),
+            Err(EvalAltResult::ErrorAssertionFailed(
+                "condition is false".to_string()
+            ))
+        );
+
+        assert_eq!(engine.eval::<()>("assert_eq(\"a\", \"a\")"), Ok(()));
+        assert_eq!(
+            engine.eval::<()>("assert_eq(1 + 1, 3)"),
+            Err(EvalAltResult::ErrorAssertionFailed(
+                "left: 2, right: 3".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn do_while_runs_body_once() {
+        let mut engine = Engine::new();
+
+        assert_eq!(
+            engine.eval::<i64>("let i = 5; do { i = i + 1; } while i < 3; i"),
+            Ok(6)
+        );
+        assert_eq!(
+            engine.eval::<i64>("let i = 0; do { i = i + 1; if i == 2 { break; } } while i < 5; i"),
+            Ok(2)
+        );
+    }
+
+    #[test]
+    fn optional_semicolons_and_empty_blocks() {
+        let mut engine = Engine::new();
+
+        assert_eq!(engine.eval::<()>("{ }"), Ok(()));
+        assert_eq!(engine.eval::<()>("if true { }"), Ok(()));
+        assert_eq!(engine.eval::<i64>("let x = 1;; x"), Ok(1));
+        assert_eq!(engine.eval::<i64>(";; let x = 1; { ; x + 1 }"), Ok(2));
+        assert_eq!(engine.eval::<i64>("{ let x = 20; x * 2 + 2 }"), Ok(42));
+        assert_eq!(engine.eval::<i64>("fn f() { let y = 3;; y } f()"), Ok(3));
+    }
+
+    #[test]
+    fn call_script_fn_after_consume() {
+        let mut engine = Engine::new();
+
+        engine.consume("fn add(a,b){a+b}").unwrap();
+
+        assert_eq!(engine.call_fn::<_, _, i64>("add", (2i64, 3i64)), Ok(5));
+    }
+
+    #[test]
+    fn script_fns_can_be_dropped_after_run() {
+        let mut engine = Engine::new();
+        engine.retain_functions = false;
+
+        assert_eq!(engine.eval::<i64>("fn one() { 1 } one()"), Ok(1));
+        assert!(engine.call_fn::<_, _, i64>("one", ()).is_err());
+    }
+
+    #[test]
+    fn dropping_script_fns_restores_retained_ones() {
+        let mut engine = Engine::new();
+        engine.consume("fn f() { 1 }").unwrap();
+
+        engine.retain_functions = false;
+        assert_eq!(engine.eval::<i64>("fn f() { 2 } f()"), Ok(2));
+        assert_eq!(engine.call_fn::<_, _, i64>("f", ()), Ok(1));
+    }
+
+    #[test]
+    fn consume_refuses_functions_with_too_many_params() {
+        let mut engine = Engine::new();
+
+        assert_eq!(
+            engine.consume("fn f(a, b, c, d, e, g, h) { a } let x = 1;"),
+            Err(EvalAltResult::ErrorTooManyParameters("f".to_string()))
+        );
+        assert!(engine.call_fn::<_, _, i64>("f", (1i64,)).is_err());
+    }
+
+    #[test]
+    fn result_fn_error_is_returned_by_eval() {
+        let mut engine = Engine::new();
+
+        engine.register_result_fn("checked_div", |a: i64, b: i64| {
+            if b == 0 {
+                Err(EvalAltResult::ErrorRuntime("division by zero".to_string()))
+            } else {
+                Ok(a / b)
+            }
+        });
+
+        assert_eq!(engine.eval::<i64>("checked_div(84, 2)"), Ok(42));
+        assert_eq!(
+            engine.eval::<i64>("let x = checked_div(1, 0); 42"),
+            Err(EvalAltResult::ErrorRuntime("division by zero".to_string()))
+        );
+    }
+
+    #[test]
+    fn typed_eval() {
+        let mut engine = Engine::new();
+
+        assert_eq!(engine.eval_bool("1 < 2"), Ok(true));
+        assert_eq!(engine.eval_i64("40 + 2"), Ok(42));
+        assert_eq!(engine.eval_f64("1.5 * 2.0"), Ok(3.0));
+        assert_eq!(engine.eval_string("\"ab\" + \"c\""), Ok("abc".to_string()));
+
+        assert_eq!(
+            engine.eval_bool("42"),
+            Err(EvalAltResult::ErrorMismatchOutputType(
+                "integer".to_string()
+            ))
+        );
+        assert!(engine.eval_i64("true").is_err());
+        assert!(engine.eval_f64("1").is_err());
+        assert!(engine.eval_string("'c'").is_err());
+    }
+
+    #[test]
+    fn char_builtins() {
+        let mut engine = Engine::new();
+
+        assert_eq!(engine.eval::<i64>("char_to_int('A')"), Ok(65));
+        assert_eq!(engine.eval::<char>("int_to_char(66)"), Ok('B'));
+        assert_eq!(engine.eval::<bool>("'a' < 'b'"), Ok(true));
+        assert_eq!(engine.eval::<bool>("'a' == int_to_char(97)"), Ok(true));
+        assert_eq!(engine.eval::<bool>("'a' != 'a'"), Ok(false));
+        assert_eq!(
+            engine.eval::<char>("int_to_char(55296)"),
+            Err(EvalAltResult::ErrorRuntime(
+                "invalid char code: 55296".to_string()
+            ))
+        );
+        assert!(engine.eval::<char>("int_to_char(-1)").is_err());
+    }
+
+    #[test]
+    fn for_loop_over_array() {
+        let mut engine = Engine::new();
+
+        assert_eq!(
+            engine.eval::<i64>("let sum = 0; for x in [1, 2, 3] { sum += x; } sum"),
+            Ok(6)
+        );
+        assert_eq!(
+            engine.eval::<i64>(
+                "let sum = 0; for x in [1, 2, 3, 4] { if x == 3 { break; } sum += x; } sum"
+            ),
+            Ok(3)
+        );
+        assert_eq!(
+            engine.eval::<()>("for x in 42 { }"),
+            Err(EvalAltResult::ErrorForMismatch)
+        );
+        assert!(engine.eval::<i64>("for x in [1] { } x").is_err());
+    }
+
+    #[test]
+    fn chained_assignment() {
+        let mut engine = Engine::new();
+
+        assert_eq!(
+            engine.eval::<i64>("let a = 0; let b = 0; a = b = 7; a + b"),
+            Ok(14)
+        );
+        assert_eq!(
+            engine.eval::<i64>("let a = [0, 0]; let b = 0; a[1] = b = 3; a[1] + b"),
+            Ok(6)
+        );
+        assert_eq!(
+            engine.eval::<i64>("let a = 0; a = b = 7"),
+            Err(EvalAltResult::ErrorVariableNotFound("b".to_string()))
+        );
+        assert_eq!(
+            engine.eval::<i64>("let b = 0; a = b = 7"),
+            Err(EvalAltResult::ErrorVariableNotFound("a".to_string()))
+        );
+    }
+
+    #[test]
+    fn script_fn_scope_isolation() {
+        let mut engine = Engine::new();
+
+        assert_eq!(
+            engine.eval::<i64>("fn f(x) { x = x + 1; x } let x = 5; f(10) * 10 + x"),
+            Ok(115)
+        );
+        assert_eq!(
+            engine.eval::<i64>("let y = 1; fn g() { y } g()"),
+            Err(EvalAltResult::ErrorVariableNotFound("y".to_string()))
+        );
+        assert_eq!(
+            engine.eval::<i64>("fn h(char_to_int) { char_to_int + 1 } h(1)"),
+            Ok(2)
+        );
+        assert_eq!(
+            engine.eval::<i64>("fn k(a, b) { a } k(1)"),
+            Err(EvalAltResult::ErrorFunctionArgMismatch)
+        );
+    }
+
+    #[test]
+    fn duplicate_registration_is_detected() {
+        fn one() -> i64 {
+            1
+        }
+        fn two() -> i64 {
+            2
+        }
+        fn add_one(x: i64) -> i64 {
+            x + 1
+        }
+
+        let mut engine = Engine::new();
+
+        assert_eq!(engine.try_register_fn("f", one), Ok(false));
+        assert_eq!(engine.try_register_fn("f", add_one), Ok(false));
+        assert_eq!(engine.try_register_fn("f", two), Ok(true));
+        assert_eq!(engine.eval::<i64>("f()"), Ok(2));
+
+        engine.strict_registration = true;
+
+        assert_eq!(
+            engine.try_register_fn("f", one),
+            Err(EvalAltResult::ErrorFunctionRedefined("f".to_string()))
+        );
+        assert_eq!(engine.eval::<i64>("f()"), Ok(2));
+    }
+
+    #[test]
+    fn underscores_in_numbers() {
+        use parser::{LexError, Token};
+
+        let mut engine = Engine::new();
+
+        assert_eq!(engine.eval::<i64>("1_000_000"), Ok(1000000));
+        assert_eq!(engine.eval::<f64>("1_000.000_5"), Ok(1000.0005));
+        assert_eq!(engine.eval::<i64>("0xff_ff"), Ok(0xffff));
+
+        for input in &["1__0", "1_", "1_.5", "1._5", "0x_1", "0b1_"] {
+            match lex(input).next() {
+                Some(Token::LexErr(LexError::MalformedNumber)) => (),
+                other => panic!("{} lexed as {:?}", input, other),
+            }
+        }
+    }
+
+    #[test]
+    fn return_from_loops() {
+        let mut engine = Engine::new();
+
+        assert_eq!(
+            engine.eval::<i64>("fn f() { while true { return 9; } 0 } f()"),
+            Ok(9)
+        );
+        assert_eq!(
+            engine.eval::<i64>("fn f() { loop { if true { { return 8; } } } 0 } f()"),
+            Ok(8)
+        );
+        assert_eq!(
+            engine.eval::<i64>(
+                "fn f() { for x in [1, 2, 3] { while true { if x == 2 { return x; } break; } } 0 } f()"
+            ),
+            Ok(2)
+        );
+    }
+
+    #[test]
+    fn dump_if_else() {
+        let engine = Engine::new();
+
+        let dump = engine.dump_ast("if x { 1 } else { 2 }").unwrap();
+
+        assert!(dump.starts_with("If Identifier(\"x\")\n"));
+        assert!(dump.contains("\n  Block\n    Expr IntConst(1)\n"));
+        assert!(dump.contains("\nElse\n  Block\n    Expr IntConst(2)\n"));
+    }
+
+    #[test]
+    fn index_through_property() {
+        #[derive(Clone)]
+        struct Wrapper {
+            items: Vec<Dynamic>,
+        }
+
+        impl Wrapper {
+            fn get_items(&mut self) -> Vec<Dynamic> {
+                self.items.clone()
+            }
+
+            fn get_inner(&mut self) -> Wrapper {
+                self.clone()
+            }
+
+            fn new() -> Wrapper {
+                Wrapper {
+                    items: vec![Box::new(10i64), Box::new(20i64), Box::new(30i64)],
+                }
+            }
+        }
+
+        let mut engine = Engine::new();
+
+        engine.register_type::<Wrapper>();
+        engine.register_get("items", Wrapper::get_items);
+        engine.register_get("inner", Wrapper::get_inner);
+        engine.register_fn("new_wrapper", Wrapper::new);
+
+        assert_eq!(
+            engine.eval::<i64>("let w = new_wrapper(); w.items[1]"),
+            Ok(20)
+        );
+        assert_eq!(
+            engine.eval::<i64>("let w = new_wrapper(); w.inner.items[2]"),
+            Ok(30)
+        );
+        assert_eq!(
+            engine.eval::<i64>("let w = new_wrapper(); w.items[3]"),
+            Err(EvalAltResult::ErrorIndexMismatch)
+        );
+    }
+
+    #[test]
+    fn list_functions_sorted() {
+        fn print(_: String) {}
+
+        let mut engine = Engine::new();
+        engine.register_fn("print", print);
+        engine.consume("fn double(x) { x * 2 }").unwrap();
+
+        let list = engine.list_functions();
+        let idents: Vec<&str> = list.iter().map(|f| &f[..f.find('(').unwrap()]).collect();
+        let mut sorted = idents.clone();
+        sorted.sort();
+
+        assert_eq!(idents, sorted);
+        assert_eq!(engine.list_functions(), list);
+        assert!(list.contains(&"print(string)".to_string()));
+        assert!(list.contains(&"+(integer, integer)".to_string()));
+        assert!(list.contains(&"double(x)".to_string()));
+    }
+
+    #[test]
+    fn help_prints_functions() {
+        use std::sync::Mutex;
+
+        let printed = Arc::new(Mutex::new(Vec::new()));
+        let print_printed = printed.clone();
+
+        let mut engine = Engine::new();
+        engine.register_fn("print", move |s: String| {
+            print_printed.lock().unwrap().push(s)
+        });
+
+        assert_eq!(engine.eval::<()>("help()"), Ok(()));
+
+        let printed = printed.lock().unwrap();
+        assert!(printed.contains(&"+(integer, integer)".to_string()));
+        assert!(printed.contains(&"print(string)".to_string()));
+    }
+
+    #[test]
+    fn script_help_overrides_the_builtin() {
+        let mut engine = Engine::new();
+
+        assert_eq!(engine.eval::<i64>("fn help() { 42 } help()"), Ok(42));
+    }
+
+    #[test]
+    fn negative_floats() {
+        let mut engine = Engine::new();
+
+        assert_eq!(engine.eval::<f64>("-5.0"), Ok(-5.0));
+        assert_eq!(engine.eval::<f64>("3.0 - -2.0"), Ok(5.0));
+        assert_eq!(engine.eval::<f64>("let x = 2.5; -x * 2.0"), Ok(-5.0));
+        assert_eq!(engine.eval::<f64>("- -1.5"), Ok(1.5));
+        assert_eq!(engine.eval::<i64>("-(3 + 4)"), Ok(-7));
+        assert_eq!(engine.eval::<bool>("!!true"), Ok(true));
+    }
+
+    #[test]
+    fn dynamic_values() {
+        let value = Dynamic::from_value(42i64);
+        assert_eq!(value.downcast_ref::<i64>(), Some(&42));
+        assert_eq!(*value.downcast::<i64>().unwrap(), 42);
+
+        let mut engine = Engine::new();
+        let mut scope = Scope::new();
+        scope.push_var("x", Dynamic::from_value(40i64));
+
+        assert_eq!(engine.eval_with_scope::<i64>(&mut scope, "x + 2"), Ok(42));
+
+        let result = engine.eval_dynamic("\"a\" + \"b\"").unwrap();
+        assert_eq!(result.downcast_ref::<String>(), Some(&"ab".to_string()));
+    }
+
+    #[test]
+    fn mixed_arrays() {
+        let mut engine = Engine::new();
+
+        assert_eq!(
+            engine.eval::<bool>("let a = [1, 2, 3]; is_array_of_type(a, \"integer\")"),
+            Ok(true)
+        );
+        assert_eq!(
+            engine.eval::<bool>("let a = [1, 2.0, \"x\"]; is_array_of_type(a, \"integer\")"),
+            Ok(false)
+        );
+        assert_eq!(
+            engine.eval::<bool>("let a = []; is_array_of_type(a, \"string\")"),
+            Ok(true)
+        );
+        assert_eq!(
+            engine.eval::<bool>("let a = [1]; is_array_of_type(a, \"number\")"),
+            Err(EvalAltResult::ErrorRuntime(
+                "unknown type name: number".to_string()
+            ))
+        );
+
+        // types named once the engine exists are known too
+        #[derive(Clone)]
+        struct Money(i64);
+        engine.register_type_name::<Money>("money");
+        engine.register_fn("money", Money);
+        assert_eq!(
+            engine.eval::<bool>("let a = [money(1), money(2)]; is_array_of_type(a, \"money\")"),
+            Ok(true)
+        );
+        assert_eq!(
+            engine.eval::<bool>("let a = [money(1), 2]; is_array_of_type(a, \"money\")"),
+            Ok(false)
+        );
+
+        // Elements keep their own type, so mixing them in arithmetic names both types
+        match engine.eval::<f64>("let a = [1, 2.0, \"x\"]; a[0] + a[1]") {
+            Err(EvalAltResult::ErrorFunctionArgTypes(_, ref args, _)) => {
+                assert_eq!(*args, vec!["integer".to_string(), "float".to_string()])
+            }
+            other => panic!("expected ErrorFunctionArgTypes, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn file_errors_name_the_file() {
+        let mut engine = Engine::new();
+        let path = "scripts/does_not_exist.rhai";
+
+        let err = engine.eval_file::<i64>(path).unwrap_err();
+        assert_eq!(
+            err,
+            EvalAltResult::ErrorInFile(
+                path.to_string(),
+                Box::new(EvalAltResult::ErrorCantOpenScriptFile)
+            )
+        );
+        assert!(err.to_string().contains(path));
+
+        assert!(engine
+            .consume_file(path)
+            .unwrap_err()
+            .to_string()
+            .contains(path));
+    }
+
+    #[test]
+    fn relative_paths_use_base_dir() {
+        use std::fs;
+
+        let dir = ::std::env::temp_dir().join(format!("rhai_base_dir_{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("answer.rhai"), "fn answer() { 42 } answer()").unwrap();
+
+        let mut engine = Engine::new();
+        assert!(engine.eval_file::<i64>("answer.rhai").is_err());
+
+        engine.set_base_dir(&dir);
+        assert_eq!(engine.eval_file::<i64>("answer.rhai"), Ok(42));
+        assert_eq!(engine.consume_file("answer.rhai"), Ok(()));
+
+        let absolute = dir.join("answer.rhai");
+        engine.set_base_dir("/nonexistent");
+        assert_eq!(engine.eval_file::<i64>(absolute.to_str().unwrap()), Ok(42));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_script_files() {
+        use std::fs;
+
+        let dir = ::std::env::temp_dir().join(format!("rhai_include_{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("helper.rhai"),
+            "fn double(x) { x * 2 } let base = 20;",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("main.rhai"),
+            "include(\"helper.rhai\"); double(base) + 2",
+        )
+        .unwrap();
+        fs::write(dir.join("a.rhai"), "include(\"b.rhai\");").unwrap();
+        fs::write(dir.join("b.rhai"), "include(\"a.rhai\");").unwrap();
+        fs::write(dir.join("self.rhai"), "include(\"self.rhai\");").unwrap();
+        // `left` and `right` both include the helper, which runs only once
+        fs::write(
+            dir.join("left.rhai"),
+            "include(\"helper.rhai\"); base += 1;",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("right.rhai"),
+            "include(\"helper.rhai\"); base += 1;",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("diamond.rhai"),
+            "include(\"left.rhai\"); include(\"right.rhai\"); double(base)",
+        )
+        .unwrap();
+
+        let mut engine = Engine::new();
+        engine.set_base_dir(&dir);
+        assert_eq!(engine.eval_file::<i64>("main.rhai"), Ok(42));
+        assert_eq!(engine.eval::<i64>("double(1)"), Ok(2));
+
+        assert_eq!(
+            engine.consume("include(\"a.rhai\");"),
+            Err(EvalAltResult::ErrorInFile(
+                "a.rhai".to_string(),
+                Box::new(EvalAltResult::ErrorInFile(
+                    "b.rhai".to_string(),
+                    Box::new(EvalAltResult::ErrorInFile(
+                        "a.rhai".to_string(),
+                        Box::new(EvalAltResult::ErrorIncludeCycle)
+                    ))
+                ))
+            ))
+        );
+        assert_eq!(
+            engine.consume_file("self.rhai"),
+            Err(EvalAltResult::ErrorInFile(
+                "self.rhai".to_string(),
+                Box::new(EvalAltResult::ErrorInFile(
+                    "self.rhai".to_string(),
+                    Box::new(EvalAltResult::ErrorIncludeCycle)
+                ))
+            ))
+        );
+        assert_eq!(engine.eval_file::<i64>("diamond.rhai"), Ok(44));
+        assert!(engine
+            .eval::<()>("if true { include(\"helper.rhai\"); }")
+            .is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn integer_literal_out_of_range() {
+        use parser::{LexError, Token};
+
+        for input in &["99999999999999999999", "0xffff_ffff_ffff_ffff_f"] {
+            match lex(input).next() {
+                Some(Token::LexErr(LexError::NumberOutOfRange(ref literal))) => {
+                    assert!(input.ends_with(literal.as_str()))
+                }
+                other => panic!("{} lexed as {:?}", input, other),
+            }
+        }
+
+        let mut engine = Engine::new();
+        assert_eq!(engine.eval::<i64>("9223372036854775807"), Ok(i64::MAX));
+        assert!(engine.eval::<i64>("9223372036854775808").is_err());
+
+        // 2^63 is only in range as the magnitude of i64::MIN
+        assert_eq!(engine.eval::<i64>("-9223372036854775808"), Ok(i64::MIN));
+        assert_eq!(engine.eval::<i64>("- 0x8000_0000_0000_0000"), Ok(i64::MIN));
+        assert_eq!(
+            engine.eval::<i64>("1 + -9223372036854775808"),
+            Ok(i64::MIN + 1)
+        );
+        assert!(engine.eval::<i64>("-9223372036854775809").is_err());
+        assert!(engine.eval::<i64>("0 - 9223372036854775808").is_err());
+    }
+
+    #[test]
+    fn compare_every_arithmetic_type() {
+        let mut engine = Engine::new();
+        engine.register_fn("f32", |x: f64| x as f32);
+
+        assert_eq!(engine.eval::<bool>("f32(1.5) < f32(2.5)"), Ok(true));
+        assert_eq!(engine.eval::<bool>("f32(1.5) >= f32(2.5)"), Ok(false));
+        assert_eq!(engine.eval::<bool>("f32(1.5) == f32(1.5)"), Ok(true));
+
+        let types = [
+            TypeId::of::<i32>(),
+            TypeId::of::<i64>(),
+            TypeId::of::<u32>(),
+            TypeId::of::<u64>(),
+            TypeId::of::<f32>(),
+            TypeId::of::<f64>(),
+        ];
+        for &t in types.iter() {
+            assert!(engine.has_fn("+", &[t, t]));
+            for op in &["<", "<=", ">", ">=", "==", "!="] {
+                assert!(engine.has_fn(op, &[t, t]), "{} missing for {:?}", op, t);
+            }
+        }
+    }
+
+    #[test]
+    fn higher_order_array_fns() {
+        let mut engine = Engine::new();
+        engine.register_fn("double", |x: i64| x * 2);
+
+        let doubled = engine
+            .eval::<Vec<Dynamic>>("map([1, 2, 3], \"double\")")
+            .unwrap()
+            .into_iter()
+            .map(|x| *x.downcast::<i64>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(doubled, vec![2, 4, 6]);
+
+        let odd = engine
+            .eval::<Vec<Dynamic>>("fn is_odd(x) { x % 2 == 1 } filter([1, 2, 3, 4, 5], \"is_odd\")")
+            .unwrap()
+            .into_iter()
+            .map(|x| *x.downcast::<i64>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(odd, vec![1, 3, 5]);
+
+        assert_eq!(
+            engine.eval::<i64>("fn add(a, b) { a + b } reduce([1, 2, 3, 4], 10, \"add\")"),
+            Ok(20)
+        );
+        assert_eq!(engine.eval::<i64>("reduce([], 7, \"add\")"), Ok(7));
+        assert_eq!(
+            engine.eval::<i64>("fn map(a, b) { 42 } map([1], \"double\")"),
+            Ok(42)
+        );
+        assert_eq!(
+            engine
+                .eval::<Vec<Dynamic>>("filter([1, 2], \"double\")")
+                .unwrap_err(),
+            EvalAltResult::ErrorRuntime(
+                "the function passed to filter must return a boolean".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn anonymous_fns() {
+        let mut engine = Engine::new();
+
+        assert_eq!(engine.eval::<i64>("let f = fn(x){ x + 1 }; f(4)"), Ok(5));
+        assert_eq!(engine.eval::<i64>("let f = fn() { 3 }; f()"), Ok(3));
+        assert_eq!(
+            engine.eval::<i64>("let f = fn(x) { x }; f(1, 2)"),
+            Err(EvalAltResult::ErrorFunctionArgMismatch)
+        );
+
+        // Nothing is captured from the scope the function is created in
+        assert_eq!(
+            engine.eval::<i64>("let y = 1; let f = fn(x) { x + y }; f(1)"),
+            Err(EvalAltResult::ErrorVariableNotFound("y".to_string()))
+        );
+
+        assert_eq!(
+            engine.eval::<i64>("reduce([1, 2, 3], 0, fn(acc, x) { acc + x * 2 })"),
+            Ok(12)
+        );
+    }
+
+    #[test]
+    fn eval_script_bytes() {
+        let mut engine = Engine::new();
+
+        assert_eq!(engine.eval_bytes::<i64>(b"let x = 40; x + 2"), Ok(42));
+        assert_eq!(
+            engine.eval_bytes::<i64>(b"40 + \xff"),
+            Err(EvalAltResult::ErrorScriptNotUtf8(
+                "invalid utf-8 sequence of 1 bytes from index 5".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn array_errors_name_element_type() {
+        #[derive(Clone)]
+        struct Point {
+            x: i64,
+        }
+
+        let mut engine = Engine::new();
+        engine.register_type_name::<Point>("Point");
+        engine.register_fn("point", |x: i64| Point { x: x });
+        engine.register_fn("points", || vec![Point { x: 1 }, Point { x: 2 }]);
+        engine.register_get("x", |p: &mut Point| p.x);
+
+        assert_eq!(
+            engine.eval::<i64>("describe(points())"),
+            Err(EvalAltResult::ErrorFunctionNotFound(
+                "describe (array<Point>)".to_string()
+            ))
+        );
+        assert_eq!(
+            engine.eval::<i64>("let a = [point(1), point(2)]; describe(a)"),
+            Err(EvalAltResult::ErrorFunctionNotFound(
+                "describe (array<Point>)".to_string()
+            ))
+        );
+        assert_eq!(
+            engine.eval::<i64>("let a = [point(1), 2]; describe(a)"),
+            Err(EvalAltResult::ErrorFunctionNotFound(
+                "describe (array)".to_string()
+            ))
+        );
+        assert_eq!(
+            engine.eval::<i64>("describe([])"),
+            Err(EvalAltResult::ErrorFunctionNotFound(
+                "describe (array)".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn concat_assignment() {
+        let mut engine = Engine::new();
+
+        assert_eq!(
+            engine.eval::<String>("let s = \"a\"; s += \"b\"; s"),
+            Ok("ab".to_string())
+        );
+
+        let arr = engine
+            .eval::<Vec<Dynamic>>("let a = [1]; a += [2]; a")
+            .unwrap();
+        assert_eq!(arr.len(), 2);
+        assert_eq!(arr[1].downcast_ref::<i64>(), Some(&2));
+
+        let arr = engine.eval::<Vec<Dynamic>>("[1, \"x\"] + []").unwrap();
+        assert_eq!(arr.len(), 2);
+    }
+
+    #[test]
+    fn arg_type_errors_list_overloads() {
+        #[derive(Clone)]
+        struct Point;
+
+        let mut engine = Engine::new();
+        engine.register_type_name::<Point>("Point");
+        engine.register_fn("point", || Point);
+        engine.register_fn("print", |_: String| ());
+        engine.register_fn("print", |_: i64| ());
+
+        let err = engine.eval::<()>("print(point())").unwrap_err();
+        assert_eq!(
+            err,
+            EvalAltResult::ErrorFunctionArgTypes(
+                "print".to_string(),
+                vec!["Point".to_string()],
+                vec!["print(integer)".to_string(), "print(string)".to_string()]
+            )
+        );
+        assert_eq!(
+            err.to_string(),
+            "Function argument types do not match: print (Point), \
+             registered: print(integer), print(string)"
+        );
+    }
+
+    #[test]
+    fn string_interpolation() {
+        let mut engine = Engine::new();
+
+        assert_eq!(
+            engine.eval::<String>("let n = 3; \"block ${n}\""),
+            Ok("block 3".to_string())
+        );
+        assert_eq!(
+            engine.eval::<String>("let a = [1, 2]; \"${a[0] + a[1]}/${true}${'c'}\""),
+            Ok("3/truec".to_string())
+        );
+        assert_eq!(
+            engine.eval::<String>("\"cost: \\${n} $5\""),
+            Ok("cost: ${n} $5".to_string())
+        );
+
+        #[derive(Clone)]
+        struct Point;
+        engine.register_fn("point", || Point);
+        engine.register_fn("to_string", |_: Point| "(0, 0)".to_string());
+        assert_eq!(
+            engine.eval::<String>("\"at ${point()}\""),
+            Ok("at (0, 0)".to_string())
+        );
+
+        assert!(engine.eval::<String>("\"${1 +}\"").is_err());
+        assert!(engine.eval::<String>("\"${1\"").is_err());
+    }
+
+    #[test]
+    fn box_fns_pick_return_type() {
+        use fn_register::RegisterBoxFn;
+
+        let mut engine = Engine::new();
+        engine.register_box_fn("pick", |x: i64| {
+            if x > 0 {
+                Box::new(x) as Dynamic
+            } else {
+                Box::new("negative".to_string()) as Dynamic
+            }
+        });
+
+        assert_eq!(engine.eval::<i64>("pick(3)"), Ok(3));
+        assert_eq!(
+            engine.eval::<String>("pick(-1)"),
+            Ok("negative".to_string())
+        );
+        assert_eq!(engine.eval::<i64>("pick(2) + 1"), Ok(3));
+    }
+
+    #[test]
+    fn arrays_are_printed_readably() {
+        use std::sync::{Arc, Mutex};
+
+        let printed = Arc::new(Mutex::new(Vec::new()));
+        let sink = printed.clone();
+
+        let mut engine = Engine::new();
+        engine.register_fn("print", move |s: String| sink.lock().unwrap().push(s));
+
+        assert_eq!(
+            engine.eval::<String>(r#"to_string([1, [2, "a"], true])"#),
+            Ok("[1, [2, a], true]".to_string())
+        );
+        assert_eq!(engine.eval::<String>("to_string([])"), Ok("[]".to_string()));
+
+        engine.eval::<()>("print([[1, 2], [3]])").unwrap();
+        assert_eq!(*printed.lock().unwrap(), vec!["[[1, 2], [3]]".to_string()]);
+    }
+
+    #[test]
+    fn native_fns_take_more_than_two_args() {
+        let mut engine = Engine::new();
+        engine.register_fn("clamp", |x: i64, lo: i64, hi: i64| x.max(lo).min(hi));
+        engine.register_fn("sum4", |a: i64, b: i64, c: i64, d: i64| a + b + c + d);
+
+        assert_eq!(engine.eval::<i64>("clamp(15, 0, 10)"), Ok(10));
+        assert_eq!(engine.eval::<i64>("sum4(1, 2, 3, 4)"), Ok(10));
+        assert_eq!(
+            engine.call_fn::<_, _, i64>("clamp", (-5i64, 0i64, 10i64)),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn registered_eq_for_custom_types() {
+        #[derive(Clone, PartialEq)]
+        struct Money(i64);
+
+        let mut engine = Engine::new();
+        engine.register_type::<Money>();
+        engine.register_fn("money", Money);
+        engine.register_fn("==", |a: Money, b: Money| a == b);
+        engine.register_fn("==", |a: Money, b: i64| a.0 == b);
+
+        assert_eq!(
+            engine.eval::<i64>("if money(5) == money(5) { 1 } else { 0 }"),
+            Ok(1)
+        );
+        assert_eq!(
+            engine.eval::<i64>("if money(5) == money(6) { 1 } else { 0 }"),
+            Ok(0)
+        );
+        assert_eq!(
+            engine.eval::<i64>("let r = 0; switch money(2) { 1 => { r = 1; }, 2 => { r = 2; } } r"),
+            Ok(2)
+        );
+        assert_eq!(
+            engine.eval::<bool>("contains([money(1), money(2)], money(2))"),
+            Ok(true)
+        );
+        assert_eq!(
+            engine.eval::<bool>("contains([money(1), 2], money(2))"),
+            Ok(false)
+        );
+        assert_eq!(
+            engine.eval::<bool>("[money(1), [money(2)]] == [money(1), [money(2)]]"),
+            Ok(true)
+        );
+        assert_eq!(engine.eval::<bool>("[money(1)] != [money(2)]"), Ok(true));
+        assert_eq!(engine.eval::<bool>("[1, 2] == [1]"), Ok(false));
+    }
+
+    #[test]
+    fn script_fns_override_array_builtins() {
+        let mut engine = Engine::new();
+
+        assert_eq!(
+            engine.eval::<i64>("fn contains(a, b) { 42 } contains([1, 2], 2)"),
+            Ok(42)
+        );
+        assert_eq!(
+            engine.eval::<String>("fn to_string(a) { \"mine\" } to_string([1])"),
+            Ok("mine".to_string())
+        );
+    }
+
+    #[test]
+    fn scope_variables() {
+        let mut scope = Scope::new();
+        assert!(scope.is_empty());
+        assert!(scope.get("x").is_none());
+
+        scope.push_var("x", Dynamic::from_value(1i64));
+        scope.push_var("x", Dynamic::from_value(2i64));
+        assert_eq!(scope.len(), 2);
+        assert_eq!(scope.get("x").unwrap().downcast_ref::<i64>(), Some(&2));
+
+        scope.set("x", Dynamic::from_value(3i64)).unwrap();
+        assert_eq!(scope.get("x").unwrap().downcast_ref::<i64>(), Some(&3));
+        assert_eq!(
+            scope.set("y", Dynamic::from_value(0i64)),
+            Err(EvalAltResult::ErrorVariableNotFound("y".to_string()))
+        );
+
+        scope.push_layer();
+        scope.push_var("y", Dynamic::from_value(4i64));
+        assert!(scope.get("y").is_some());
+        scope.pop_layer();
+        assert!(scope.get("y").is_none());
+        assert_eq!(scope.len(), 2);
+
+        scope.push_const("c", Dynamic::from_value(5i64));
+        assert_eq!(
+            scope.set("c", Dynamic::from_value(6i64)),
+            Err(EvalAltResult::ErrorAssignmentToConstant("c".to_string()))
+        );
+
+        let mut engine = Engine::new();
+        assert_eq!(engine.eval_with_scope::<i64>(&mut scope, "c + x"), Ok(8));
+        assert_eq!(
+            engine.eval_with_scope::<i64>(&mut scope, "c = 1"),
+            Err(EvalAltResult::ErrorAssignmentToConstant("c".to_string()))
+        );
+        assert_eq!(
+            engine.eval_with_scope::<i64>(&mut scope, "{ let c = 1; c = 2; c }"),
+            Ok(2)
+        );
+        assert_eq!(engine.eval_with_scope::<i64>(&mut scope, "c"), Ok(5));
+    }
+
+    #[test]
+    fn duplicate_script_fns_are_reported() {
+        let mut engine = Engine::new();
+
+        assert_eq!(
+            engine.eval::<i64>("fn f() { 1 } fn f() { 2 } f()"),
+            Err(EvalAltResult::ErrorDuplicateFunction("f".to_string()))
+        );
+        assert_eq!(
+            engine.consume("fn g(x) { x } fn g(x, y) { y }"),
+            Err(EvalAltResult::ErrorDuplicateFunction("g".to_string()))
+        );
+
+        /* a later run may still redefine a retained function */
+        engine.consume("fn h() { 1 }").unwrap();
+        assert_eq!(engine.eval::<i64>("fn h() { 2 } h()"), Ok(2));
+    }
+
+    #[test]
+    fn logical_and_bitwise_operators() {
+        let mut engine = Engine::new();
+
+        assert_eq!(engine.eval::<i64>("6 | 3"), Ok(7));
+        assert_eq!(engine.eval::<i64>("6 & 3"), Ok(2));
+        assert_eq!(engine.eval::<bool>("true || false"), Ok(true));
+        assert_eq!(engine.eval::<bool>("true && false"), Ok(false));
+
+        /* the right operand is not evaluated once the left one decides */
+        assert_eq!(engine.eval::<bool>("true || undefined()"), Ok(true));
+        assert_eq!(engine.eval::<bool>("false && undefined()"), Ok(false));
+        assert!(engine.eval::<bool>("false || undefined()").is_err());
+
+        match engine.eval::<bool>("true | false") {
+            Err(EvalAltResult::ErrorFunctionArgTypes(ref op, _, _)) => assert_eq!(op, "|"),
+            other => panic!("unexpected result {:?}", other.map_err(|e| e.to_string())),
+        }
+        assert!(engine.eval::<bool>("true & false").is_err());
+    }
+
+    #[test]
+    fn numeric_type_names() {
+        let mut engine = Engine::new();
+        engine.register_fn("small", || 1.5f32);
+        engine.register_fn("big", || 1u64);
+        engine.register_fn("len", || 1usize);
+
+        assert_eq!(
+            engine.eval::<i64>("small()"),
+            Err(EvalAltResult::ErrorMismatchOutputType("f32".to_string()))
+        );
+        match engine.eval::<f32>("small() + 1.0") {
+            Err(EvalAltResult::ErrorFunctionArgTypes(_, ref args, _)) => {
+                assert_eq!(args, &vec!["f32".to_string(), "float".to_string()])
+            }
+            other => panic!("unexpected result {:?}", other.map_err(|e| e.to_string())),
+        }
+        assert_eq!(
+            engine.eval::<i64>("big()"),
+            Err(EvalAltResult::ErrorMismatchOutputType("u64".to_string()))
+        );
+        assert_eq!(
+            engine.eval::<i64>("len()"),
+            Err(EvalAltResult::ErrorMismatchOutputType("usize".to_string()))
+        );
+    }
+
+    #[test]
+    fn call_fn_reports_missing_return() {
+        let mut engine = Engine::new();
+        engine
+            .consume("fn nothing(x) { let y = x; } fn text(x) { \"a\" }")
+            .unwrap();
+
+        let err = engine.call_fn::<_, _, i64>("nothing", (1i64,)).unwrap_err();
+        assert_eq!(
+            err,
+            EvalAltResult::ErrorFunctionDidNotReturn("nothing".to_string())
+        );
+        assert_eq!(err.to_string(), "Function did not return a value: nothing");
+
+        assert_eq!(engine.call_fn::<_, _, ()>("nothing", (1i64,)), Ok(()));
+        assert_eq!(
+            engine.call_fn::<_, _, i64>("text", (1i64,)),
+            Err(EvalAltResult::ErrorMismatchOutputType("string".to_string()))
+        );
+    }
+
+    #[test]
+    fn streamed_scripts_match_in_memory() {
+        use std::io::{self, Read};
+
+        /// Hands out one byte per read, so characters are split across buffer fills
+        struct Trickle<'a>(&'a [u8]);
+
+        impl<'a> Read for Trickle<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() || buf.is_empty() {
+                    return Ok(0);
+                }
+                buf[0] = self.0[0];
+                self.0 = &self.0[1..];
+                Ok(1)
+            }
+        }
+
+        let mut script = String::from("fn step(x, i) { x + i % 7 } let x = 0; let s = \"\";\n");
+        for i in 0..20000 {
+            script.push_str(&format!("x = step(x, {}); // generated line ✓\n", i));
+        }
+        script.push_str("s = \"héllo ✓\"; if s == \"héllo ✓\" { x = x + 1; } x");
+
+        let mut engine = Engine::new();
+        let expected = engine.eval::<i64>(&script).unwrap();
+
+        assert_eq!(
+            engine.eval_reader::<i64, _>(script.as_bytes()),
+            Ok(expected)
+        );
+        assert_eq!(
+            engine.eval_reader::<i64, _>(Trickle(script.as_bytes())),
+            Ok(expected)
+        );
+        assert_eq!(engine.consume_reader(script.as_bytes()), Ok(()));
+
+        match engine.eval_reader::<i64, _>(&b"let x = \"\xff\"; 1"[..]) {
+            Err(EvalAltResult::ErrorScriptNotUtf8(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn single_expressions() {
+        let engine = Engine::new();
+        let mut scope = Scope::new();
+        scope.push_var("x", Box::new(7i64));
+
+        assert_eq!(
+            engine.eval_expression::<bool>(&mut scope, "x > 5"),
+            Ok(true)
+        );
+        assert_eq!(
+            engine.eval_expression::<i64>(&mut scope, "(x - 1) * 2"),
+            Ok(12)
+        );
+
+        scope.set("x", Box::new(3i64)).unwrap();
+        assert_eq!(
+            engine.eval_expression::<bool>(&mut scope, "x > 5"),
+            Ok(false)
+        );
+
+        for input in &["let y = 1", "if x > 5 { 1 }", "x > 5; x", "x > 5 x", ""] {
+            assert_eq!(
+                engine.eval_expression::<bool>(&mut scope, input),
+                Err(EvalAltResult::ErrorFunctionArgMismatch)
+            );
+        }
+        assert_eq!(
+            engine.eval_expression::<bool>(&mut scope, "x + 1"),
+            Err(EvalAltResult::ErrorMismatchOutputType(
+                "integer".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn single_expressions_do_not_assign() {
+        let engine = Engine::new();
+        let mut scope = Scope::new();
+        scope.push_var("x", Box::new(7i64));
+        scope.push_var("a", Box::new(vec![Box::new(1i64) as Dynamic]));
+
+        let inputs = [
+            "x = 5",
+            "x += 1",
+            "x -= 1",
+            "x *= 2",
+            "x <<= 1",
+            "(x = 5)",
+            "1 + (x = 5)",
+            "[x = 9] == [9]",
+            "abs(x += 1)",
+            "a[x = 0]",
+            "\"${x = 3}\"",
+        ];
+        for input in &inputs {
+            assert_eq!(
+                engine.eval_expression::<i64>(&mut scope, input),
+                Err(EvalAltResult::ErrorAssignmentInExpression)
+            );
+        }
+        assert_eq!(engine.eval_expression::<i64>(&mut scope, "x"), Ok(7));
+
+        // an anonymous function only assigns to its own variables
+        assert_eq!(
+            engine.eval_expression::<i64>(&mut scope, "reduce([1], x, fn(a, b) { a += b; a })"),
+            Ok(8)
+        );
+    }
+
+    #[test]
+    fn uncallable_names_are_ignored() {
+        let mut engine = Engine::new();
+
+        for name in &["", "add one", "tab\t", " add"] {
+            engine.register_fn(name, |x: i64| x + 1);
+            assert!(!engine.has_fn(name, &[TypeId::of::<i64>()]));
+        }
+
+        engine.register_fn("add_one", |x: i64| x + 1);
+        engine.register_fn("+", |x: i64, y: bool| if y { x + 1 } else { x });
+        assert_eq!(engine.eval::<i64>("add_one(1) + true"), Ok(3));
+    }
+
+    #[test]
+    fn raw_engine_has_no_default_lib() {
+        let mut engine = Engine::new_raw();
+
+        match engine.eval::<i64>("1 + 2") {
+            Err(EvalAltResult::ErrorFunctionNotFound(ref f)) if f.starts_with("+ (") => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        engine.register_fn("double", |x: i64| x * 2);
+        assert_eq!(engine.eval::<i64>("double(21)"), Ok(42));
+
+        Engine::register_default_lib(&mut engine);
+        assert_eq!(engine.eval::<i64>("1 + 2"), Ok(3));
+    }
+
+    #[test]
+    fn call_fn_promotes_numbers() {
+        let mut engine = Engine::new();
+        engine.register_fn("small", |x: i64| x as i32);
+        engine.register_fn("unsigned", |x: i64| x as u32);
+        engine.register_fn("single", |x: f64| x as f32);
+        engine
+            .consume(
+                "fn get(x) { small(x) } fn get_u(x) { unsigned(x) } fn get_f() { single(0.5) }",
+            )
+            .unwrap();
+
+        // Strict by default
+        assert_eq!(
+            engine.call_fn::<_, _, i64>("get", (-7i64,)),
+            Err(EvalAltResult::ErrorMismatchOutputType("i32".to_string()))
+        );
+
+        engine.coerce_numeric_results = true;
+        assert_eq!(engine.call_fn::<_, _, i64>("get", (-7i64,)), Ok(-7));
+        assert_eq!(engine.call_fn::<_, _, i32>("get", (-7i64,)), Ok(-7));
+        assert_eq!(engine.call_fn::<_, _, f64>("get", (-7i64,)), Ok(-7.0));
+        assert_eq!(engine.call_fn::<_, _, i64>("get_u", (7i64,)), Ok(7));
+        assert_eq!(engine.call_fn::<_, _, f64>("get_f", ()), Ok(0.5));
+
+        // Conversions that could lose precision are still refused
+        assert_eq!(
+            engine.call_fn::<_, _, i64>("get_f", ()),
+            Err(EvalAltResult::ErrorMismatchOutputType("f32".to_string()))
+        );
+        assert_eq!(
+            engine.call_fn::<_, _, u32>("get", (-7i64,)),
+            Err(EvalAltResult::ErrorMismatchOutputType("i32".to_string()))
+        );
+    }
+
+    #[test]
+    fn switch_arms() {
+        let mut engine = Engine::new();
+        let script = "let r = 0; \
+                      switch x { 1 => { r = 10; }, \"a\" => { r = 20; }, 3 => { r = 30; }, _ => { r = 40; } } \
+                      r";
+
+        assert_eq!(
+            engine.eval::<i64>(&format!("let x = 3; {}", script)),
+            Ok(30)
+        );
+        assert_eq!(
+            engine.eval::<i64>(&format!("let x = \"a\"; {}", script)),
+            Ok(20)
+        );
+        assert_eq!(
+            engine.eval::<i64>(&format!("let x = 7; {}", script)),
+            Ok(40)
+        );
+        assert_eq!(
+            engine.eval::<i64>(&format!("let x = -3; {}", script.replace("3 =>", "-3 =>"))),
+            Ok(30)
+        );
+    }
+
+    #[test]
+    fn switch_arms_are_checked_when_parsed() {
+        let parse_error = |input: &str| parse(&mut lex(input).peekable()).err();
+
+        // refused even though the first arm matches
+        match parse_error("switch 1 { 1 => { }, _ => { }, 2 => { } }") {
+            Some(ParseError::SwitchDefaultNotLast) => (),
+            other => panic!("default arm before another one: {:?}", other),
+        }
+        for input in &[
+            "switch 1 { x => { } }",
+            "switch 1 { f(1) => { } }",
+            "switch 1 { -x => { } }",
+            "switch 1 { \"${x}\" => { } }",
+        ] {
+            match parse_error(input) {
+                Some(ParseError::MalformedSwitchArm) => (),
+                other => panic!("{} parsed with {:?}", input, other),
+            }
+        }
+    }
+
+    #[test]
+    fn shared_engine_between_threads() {
+        use std::thread::spawn;
+
+        let mut engine = Engine::new();
+        engine.consume("fn double(x) { x * 2 }").unwrap();
+        let engine = Arc::new(engine);
+
+        let handles: Vec<_> = (0..2)
+            .map(|i| {
+                let engine = engine.clone();
+                spawn(move || {
+                    engine
+                        .call_fn::<_, _, i64>("double", (i as i64 + 1,))
+                        .unwrap()
+                })
+            })
+            .collect();
+        let results: Vec<i64> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(results, vec![2, 4]);
+    }
+
+    #[test]
+    fn shared_engine_evaluates_from_several_threads() {
+        use std::thread::spawn;
+
+        let mut engine = Engine::new();
+        engine.register_fn("offset", |x: i64| x + 100);
+        engine.consume("fn double(x) { x * 2 }").unwrap();
+        let engine = Arc::new(engine);
+
+        /* `eval_expression` only borrows the engine, every thread has its own scope */
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let engine = engine.clone();
+                spawn(move || {
+                    let mut scope = Scope::new();
+                    scope.push_var("x", Dynamic::from_value(i as i64));
+                    (0..100)
+                        .map(|_| {
+                            engine
+                                .eval_expression::<i64>(&mut scope, "offset(double(x)) + x")
+                                .unwrap()
+                        })
+                        .collect::<Vec<i64>>()
+                })
+            })
+            .collect();
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            let expected = i as i64 * 3 + 100;
+            assert!(handle.join().unwrap().iter().all(|&x| x == expected));
+        }
+    }
+
+    #[test]
+    fn get_ref_clones_member_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CLONES: AtomicUsize = AtomicUsize::new(0);
+
+        struct Counted(Vec<i64>);
+
+        impl Clone for Counted {
+            fn clone(&self) -> Self {
+                CLONES.fetch_add(1, Ordering::SeqCst);
+                Counted(self.0.clone())
+            }
+        }
+
+        #[derive(Clone)]
+        struct Holder {
+            data: Counted,
+        }
+
+        let mut engine = Engine::new();
+        engine.register_type::<Holder>();
+        engine.register_get_ref("data", |h: &Holder| &h.data);
+        engine.register_fn("new_holder", || Holder {
+            data: Counted(vec![0; 10_000]),
+        });
+
+        let data = engine
+            .eval::<Counted>("let h = new_holder(); h.data")
+            .expect("data is readable");
+
+        assert_eq!(data.0.len(), 10_000);
+        assert_eq!(CLONES.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn out_of_range_index() {
+        let mut engine = Engine::new();
+
+        assert_eq!(array_index(-1), None);
+        assert_eq!(array_index(i64::MIN), None);
+        if cfg!(target_pointer_width = "32") {
+            assert_eq!(array_index(1 << 32), None);
+        } else {
+            assert_eq!(array_index(1 << 32), Some(1 << 32));
+        }
+        // 2^32 and -(2^63 - 1) used to wrap around to 0 and 1 on 32-bit targets
+        assert_eq!(
+            engine.eval::<i64>("let x = [1, 2, 3]; x[4294967296]"),
+            Err(EvalAltResult::ErrorIndexMismatch)
+        );
+        assert_eq!(
+            engine.eval::<i64>("let x = [1, 2, 3]; x[-9223372036854775807]"),
+            Err(EvalAltResult::ErrorIndexMismatch)
+        );
+
+        assert_eq!(
+            engine.eval::<i64>("let x = [1, 2, 3]; x[3]"),
+            Err(EvalAltResult::ErrorIndexMismatch)
+        );
+        assert_eq!(
+            engine.eval::<i64>("let x = [1, 2, 3]; x[-1]"),
+            Err(EvalAltResult::ErrorIndexMismatch)
+        );
+        assert_eq!(
+            engine.eval::<()>("let x = [1, 2, 3]; x[10] = 4"),
+            Err(EvalAltResult::ErrorIndexMismatch)
+        );
+        assert_eq!(
+            engine.eval::<i64>("let x = [1, 2, 3]; x[2] = 4; x[2]"),
+            Ok(4)
+        );
+    }
+
+    #[test]
+    fn index_fn_array_result() {
+        let mut engine = Engine::new();
+
+        assert_eq!(
+            engine.eval::<i64>("fn f() { return [1, 2]; } f()[1]"),
+            Ok(2)
+        );
+
+        let result = engine
+            .call_fn::<_, _, Vec<Dynamic>>("f", ())
+            .expect("f returns an array");
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].downcast_ref::<i64>(), Some(&1));
+    }
 }
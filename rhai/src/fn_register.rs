@@ -1,10 +1,25 @@
 use std::any::TypeId;
 
-use any::Any;
+use any::{Any, Dynamic};
 use engine::{Engine, EvalAltResult};
 
 pub trait RegisterFn<FN, ARGS, RET> {
     fn register_fn(&mut self, name: &str, f: FN);
+
+    /// Registers a function, telling whether it replaced one with the same argument types.
+    /// If the engine has `strict_registration` set, the existing function is kept and an error is returned.
+    fn try_register_fn(&mut self, name: &str, f: FN) -> Result<bool, EvalAltResult>;
+}
+
+/// Registers a fallible function, whose `Err` is returned as the result of the evaluation
+pub trait RegisterResultFn<FN, ARGS, RET> {
+    fn register_result_fn(&mut self, name: &str, f: FN);
+}
+
+/// Registers a function returning a `Dynamic`, which is passed to the script as is instead of
+/// being boxed again. This lets the function decide at runtime which type it returns.
+pub trait RegisterBoxFn<FN, ARGS> {
+    fn register_box_fn(&mut self, name: &str, f: FN);
 }
 
 pub struct Ref<A>(A);
@@ -23,7 +38,7 @@ macro_rules! def_register {
         impl<$($par,)* FN, RET> RegisterFn<FN, ($($mark,)*), RET> for Engine
         where
             $($par: Any + Clone,)*
-            FN: Fn($($param),*) -> RET + 'static,
+            FN: Fn($($param),*) -> RET + Send + Sync + 'static,
             RET: Any,
         {
             fn register_fn(&mut self, name: &str, f: FN) {
@@ -43,7 +58,67 @@ macro_rules! def_register {
 
                     // Call the user-supplied function using ($clone) to
                     // potentially clone the value, otherwise pass the reference.
-                    Ok(Box::new(f($(($clone)($par)),*)) as Box<Any>)
+                    Ok(Box::new(f($(($clone)($par)),*)) as Dynamic)
+                };
+                self.register_fn_raw(name.to_owned(), Some(vec![$(TypeId::of::<$par>()),*]), Box::new(fun));
+            }
+
+            fn try_register_fn(&mut self, name: &str, f: FN) -> Result<bool, EvalAltResult> {
+                let replaced = self.has_fn(name, &[$(TypeId::of::<$par>()),*]);
+
+                if replaced && self.strict_registration {
+                    return Err(EvalAltResult::ErrorFunctionRedefined(name.to_owned()));
+                }
+
+                RegisterFn::<FN, ($($mark,)*), RET>::register_fn(self, name, f);
+                Ok(replaced)
+            }
+        }
+
+        impl<$($par,)* FN, RET> RegisterResultFn<FN, ($($mark,)*), RET> for Engine
+        where
+            $($par: Any + Clone,)*
+            FN: Fn($($param),*) -> Result<RET, EvalAltResult> + Send + Sync + 'static,
+            RET: Any,
+        {
+            fn register_result_fn(&mut self, name: &str, f: FN) {
+                let fun = move |mut args: Vec<&mut Any>| {
+                    if args.len() != count_args!($($par)*) {
+                        return Err(EvalAltResult::ErrorFunctionArgMismatch);
+                    }
+
+                    let mut drain = args.drain(..);
+                    $(
+                    let $par = ((*drain.next().unwrap()).downcast_mut() as Option<&mut $par>)
+                        .ok_or(EvalAltResult::ErrorFunctionArgMismatch)?;
+                    )*
+
+                    // Same as `register_fn`, except that an `Err` is passed on as is.
+                    f($(($clone)($par)),*).map(|r| Box::new(r) as Dynamic)
+                };
+                self.register_fn_raw(name.to_owned(), Some(vec![$(TypeId::of::<$par>()),*]), Box::new(fun));
+            }
+        }
+
+        impl<$($par,)* FN> RegisterBoxFn<FN, ($($mark,)*)> for Engine
+        where
+            $($par: Any + Clone,)*
+            FN: Fn($($param),*) -> Dynamic + Send + Sync + 'static,
+        {
+            fn register_box_fn(&mut self, name: &str, f: FN) {
+                let fun = move |mut args: Vec<&mut Any>| {
+                    if args.len() != count_args!($($par)*) {
+                        return Err(EvalAltResult::ErrorFunctionArgMismatch);
+                    }
+
+                    let mut drain = args.drain(..);
+                    $(
+                    let $par = ((*drain.next().unwrap()).downcast_mut() as Option<&mut $par>)
+                        .ok_or(EvalAltResult::ErrorFunctionArgMismatch)?;
+                    )*
+
+                    // The returned box already holds the value, so it is not wrapped again.
+                    Ok(f($(($clone)($par)),*))
                 };
                 self.register_fn_raw(name.to_owned(), Some(vec![$(TypeId::of::<$par>()),*]), Box::new(fun));
             }
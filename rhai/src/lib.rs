@@ -53,6 +53,6 @@ mod engine;
 mod fn_register;
 mod parser;
 
-pub use any::Any;
-pub use engine::{Engine, EvalAltResult, Scope};
-pub use fn_register::RegisterFn;
+pub use any::{Any, AnyExt, Dynamic};
+pub use engine::{AnonFn, Engine, EvalAltResult, Scope};
+pub use fn_register::{RegisterBoxFn, RegisterFn, RegisterResultFn};
@@ -1,15 +1,21 @@
+use std::cell::RefCell;
 use std::char;
 use std::error::Error;
 use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
 use std::iter::Peekable;
-use std::str::Chars;
+use std::num::IntErrorKind;
+use std::str;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub enum LexError {
     UnexpectedChar,
     MalformedEscapeSequence,
     MalformedNumber,
+    NumberOutOfRange(String),
     MalformedChar,
+    MalformedInterpolation,
     Nothing,
 }
 
@@ -19,7 +25,9 @@ impl Error for LexError {
             LexError::UnexpectedChar => "Unexpected character in input",
             LexError::MalformedEscapeSequence => "Unexpected values in escape sequence",
             LexError::MalformedNumber => "Unexpected characters in number",
+            LexError::NumberOutOfRange(_) => "Number literal out of range",
             LexError::MalformedChar => "Char constant not a single character",
+            LexError::MalformedInterpolation => "'${' in string is not closed by '}'",
             LexError::Nothing => "This error is for internal use only",
         }
     }
@@ -27,7 +35,12 @@ impl Error for LexError {
 
 impl fmt::Display for LexError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.description())
+        match *self {
+            LexError::NumberOutOfRange(ref literal) => {
+                write!(f, "{}: {}", self.description(), literal)
+            }
+            _ => write!(f, "{}", self.description()),
+        }
     }
 }
 
@@ -45,6 +58,11 @@ pub enum ParseError {
     VarExpectsIdentifier,
     FnMissingName,
     FnMissingParams,
+    MissingWhile,
+    MalformedSwitchArm,
+    SwitchDefaultNotLast,
+    MalformedForLoop,
+    MalformedInterpolation,
 }
 
 impl Error for ParseError {
@@ -62,6 +80,11 @@ impl Error for ParseError {
             ParseError::VarExpectsIdentifier => "'var' expects the name of a variable",
             ParseError::FnMissingName => "Function declaration is missing name",
             ParseError::FnMissingParams => "Function declaration is missing parameters",
+            ParseError::MissingWhile => "Expected 'while'",
+            ParseError::MalformedSwitchArm => "Switch arm expects a constant, '=>' and a block",
+            ParseError::SwitchDefaultNotLast => "Switch arm '_' must be the last one",
+            ParseError::MalformedForLoop => "'for' expects a variable name, 'in' and an expression",
+            ParseError::MalformedInterpolation => "'${...}' in string must contain one expression",
         }
     }
 
@@ -88,7 +111,10 @@ pub enum Stmt {
     If(Box<Expr>, Box<Stmt>),
     IfElse(Box<Expr>, Box<Stmt>, Box<Stmt>),
     While(Box<Expr>, Box<Stmt>),
+    DoWhile(Box<Stmt>, Box<Expr>),
+    For(String, Box<Expr>, Box<Stmt>),
     Loop(Box<Stmt>),
+    Switch(Box<Expr>, Vec<(Option<Expr>, Stmt)>),
     Var(String, Option<Box<Expr>>),
     Block(Vec<Stmt>),
     Expr(Box<Expr>),
@@ -108,12 +134,21 @@ pub enum Expr {
     Assignment(Box<Expr>, Box<Expr>),
     Dot(Box<Expr>, Box<Expr>),
     Index(String, Box<Expr>),
+    IndexValue(Box<Expr>, Box<Expr>),
     Array(Vec<Expr>),
+    AnonFn(Arc<FnDef>),
     True,
     False,
     Unit,
 }
 
+/// A piece of an interpolated string: either text, or the source of an expression written as `${...}`
+#[derive(Debug, Clone)]
+pub enum StringPart {
+    Text(String),
+    Code(String),
+}
+
 #[derive(Debug, Clone)]
 pub enum Token {
     IntConst(i64),
@@ -121,6 +156,7 @@ pub enum Token {
     Identifier(String),
     CharConst(char),
     StringConst(String),
+    InterpolatedString(Vec<StringPart>),
     LCurly,
     RCurly,
     LParen,
@@ -138,13 +174,18 @@ pub enum Token {
     Comma,
     Period,
     Equals,
+    Arrow,
     True,
     False,
     Var,
     If,
     Else,
     While,
+    Do,
+    For,
+    In,
     Loop,
+    Switch,
     LessThan,
     GreaterThan,
     Bang,
@@ -185,6 +226,8 @@ impl Token {
         use self::Token::*;
 
         match *self {
+            LexErr(_)        | // nothing lexed yet - start of input
+            Semicolon        | // start of a statement
             LCurly           | // (+expr) - is unary
             // RCurly           | {expr} - expr not unary & is closing
             LParen           | // {-expr} - is unary
@@ -214,6 +257,7 @@ impl Token {
             And              |
             If               |
             While            |
+            In               |
             PlusAssign       |
             MinusAssign      |
             MultiplyAssign   |
@@ -278,16 +322,44 @@ impl Token {
 
 pub struct TokenIterator<'a> {
     last: Token,
-    char_stream: Peekable<Chars<'a>>,
+    char_stream: Peekable<Box<Iterator<Item = char> + 'a>>,
 }
 
 impl<'a> TokenIterator<'a> {
     pub fn parse_string_const(&mut self, enclosing_char: char) -> Result<String, LexError> {
+        let parts = self.parse_string_parts(enclosing_char, false)?;
+
+        Ok(parts
+            .into_iter()
+            .map(|part| match part {
+                StringPart::Text(s) => s,
+                StringPart::Code(_) => String::new(),
+            })
+            .collect())
+    }
+
+    /// Reads a literal up to `enclosing_char`. When `interpolate` is set, every `${...}`
+    /// outside of an escape sequence is cut out as code; `\$` is a literal dollar sign.
+    fn parse_string_parts(
+        &mut self,
+        enclosing_char: char,
+        interpolate: bool,
+    ) -> Result<Vec<StringPart>, LexError> {
+        let mut parts = Vec::new();
         let mut result = Vec::new();
         let mut escape = false;
 
         while let Some(nxt) = self.char_stream.next() {
             match nxt {
+                '$' if escape => {
+                    escape = false;
+                    result.push('$');
+                }
+                '$' if interpolate && self.char_stream.peek() == Some(&'{') => {
+                    self.char_stream.next();
+                    parts.push(StringPart::Text(result.drain(..).collect()));
+                    parts.push(StringPart::Code(self.parse_interpolated_code()?));
+                }
                 '\\' if !escape => escape = true,
                 '\\' if escape => {
                     escape = false;
@@ -381,8 +453,41 @@ impl<'a> TokenIterator<'a> {
             }
         }
 
-        let out: String = result.iter().cloned().collect();
-        Ok(out)
+        parts.push(StringPart::Text(result.into_iter().collect()));
+        Ok(parts)
+    }
+
+    /// Reads the source of an interpolated expression, up to the `}` closing its `${`
+    fn parse_interpolated_code(&mut self) -> Result<String, LexError> {
+        let mut code = String::new();
+        let mut depth = 1;
+
+        while let Some(nxt) = self.char_stream.next() {
+            match nxt {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(code);
+                    }
+                }
+                _ => (),
+            }
+            code.push(nxt);
+        }
+
+        Err(LexError::MalformedInterpolation)
+    }
+
+    /// An integer literal too large for `i64` is out of range, except `2^63` right after a
+    /// unary minus: it is read as `i64::MIN`, which the parser then doesn't negate again.
+    fn int_overflow(&self, out: &str, radix: u32, digits: String) -> Token {
+        match self.last {
+            Token::UnaryMinus if u64::from_str_radix(out, radix) == Ok(1 << 63) => {
+                Token::IntConst(i64::MIN)
+            }
+            _ => Token::LexErr(LexError::NumberOutOfRange(digits)),
+        }
     }
 
     fn inner_next(&mut self) -> Option<Token> {
@@ -395,7 +500,7 @@ impl<'a> TokenIterator<'a> {
 
                     while let Some(&nxt) = self.char_stream.peek() {
                         match nxt {
-                            '0'...'9' => {
+                            '0'...'9' | '_' => {
                                 result.push(nxt);
                                 self.char_stream.next();
                             }
@@ -404,7 +509,7 @@ impl<'a> TokenIterator<'a> {
                                 self.char_stream.next();
                                 while let Some(&nxt_float) = self.char_stream.peek() {
                                     match nxt_float {
-                                        '0'...'9' => {
+                                        '0'...'9' | '_' => {
                                             result.push(nxt_float);
                                             self.char_stream.next();
                                         }
@@ -417,7 +522,7 @@ impl<'a> TokenIterator<'a> {
                                 self.char_stream.next();
                                 while let Some(&nxt_hex) = self.char_stream.peek() {
                                     match nxt_hex {
-                                        '0'...'9' | 'a'...'f' | 'A'...'F' => {
+                                        '0'...'9' | 'a'...'f' | 'A'...'F' | '_' => {
                                             result.push(nxt_hex);
                                             self.char_stream.next();
                                         }
@@ -431,7 +536,7 @@ impl<'a> TokenIterator<'a> {
                                 self.char_stream.next();
                                 while let Some(&nxt_oct) = self.char_stream.peek() {
                                     match nxt_oct {
-                                        '0'...'8' => {
+                                        '0'...'8' | '_' => {
                                             result.push(nxt_oct);
                                             self.char_stream.next();
                                         }
@@ -459,22 +564,39 @@ impl<'a> TokenIterator<'a> {
                     }
 
                     if let Some(radix) = radix_base {
-                        let out: String = result
-                            .iter()
-                            .cloned()
-                            .skip(2)
-                            .filter(|c| c != &'_')
-                            .collect();
-                        if let Ok(val) = i64::from_str_radix(&out, radix) {
-                            return Some(Token::IntConst(val));
+                        let digits: String = result.iter().cloned().skip(2).collect();
+                        let out = match strip_digit_separators(&digits) {
+                            Some(out) => out,
+                            None => return Some(Token::LexErr(LexError::MalformedNumber)),
+                        };
+                        match i64::from_str_radix(&out, radix) {
+                            Ok(val) => return Some(Token::IntConst(val)),
+                            Err(ref e) if *e.kind() == IntErrorKind::PosOverflow => {
+                                return Some(self.int_overflow(&out, radix, digits))
+                            }
+                            Err(_) => (),
                         }
                     }
 
-                    let out: String = result.iter().cloned().collect();
-
-                    if let Ok(val) = out.parse::<i64>() {
-                        return Some(Token::IntConst(val));
+                    let digits: String = result.iter().cloned().collect();
+                    let out = match strip_digit_separators(&digits) {
+                        Some(out) => out,
+                        None => return Some(Token::LexErr(LexError::MalformedNumber)),
+                    };
+
+                    // Integers too large for i64 are reported rather than read as floats
+                    if !out.contains('.') {
+                        match out.parse::<i64>() {
+                            Ok(val) => return Some(Token::IntConst(val)),
+                            Err(ref e) if *e.kind() == IntErrorKind::PosOverflow => {
+                                return Some(self.int_overflow(&out, 10, digits))
+                            }
+                            Err(_) => (),
+                        }
                     } else if let Ok(val) = out.parse::<f64>() {
+                        if val.is_infinite() {
+                            return Some(Token::LexErr(LexError::NumberOutOfRange(digits)));
+                        }
                         return Some(Token::FloatConst(val));
                     }
                     return Some(Token::LexErr(LexError::MalformedNumber));
@@ -501,15 +623,26 @@ impl<'a> TokenIterator<'a> {
                         "if" => return Some(Token::If),
                         "else" => return Some(Token::Else),
                         "while" => return Some(Token::While),
+                        "do" => return Some(Token::Do),
+                        "for" => return Some(Token::For),
+                        "in" => return Some(Token::In),
                         "loop" => return Some(Token::Loop),
+                        "switch" => return Some(Token::Switch),
                         "break" => return Some(Token::Break),
                         "return" => return Some(Token::Return),
                         "fn" => return Some(Token::Fn),
                         x => return Some(Token::Identifier(x.to_string())),
                     }
                 }
-                '"' => match self.parse_string_const('"') {
-                    Ok(out) => return Some(Token::StringConst(out)),
+                '"' => match self.parse_string_parts('"', true) {
+                    Ok(mut parts) => {
+                        if parts.len() == 1 {
+                            if let Some(StringPart::Text(out)) = parts.pop() {
+                                return Some(Token::StringConst(out));
+                            }
+                        }
+                        return Some(Token::InterpolatedString(parts));
+                    }
                     Err(e) => return Some(Token::LexErr(e)),
                 },
                 '\'' => match self.parse_string_const('\'') {
@@ -610,6 +743,10 @@ impl<'a> TokenIterator<'a> {
                         self.char_stream.next();
                         return Some(Token::EqualTo);
                     }
+                    Some(&'>') => {
+                        self.char_stream.next();
+                        return Some(Token::Arrow);
+                    }
                     _ => return Some(Token::Equals),
                 },
                 '<' => match self.char_stream.peek() {
@@ -724,13 +861,111 @@ impl<'a> Iterator for TokenIterator<'a> {
     }
 }
 
+/// Removes the `_` separators of a number literal, or returns `None` if one of them
+/// doesn't sit between two digits.
+fn strip_digit_separators(digits: &str) -> Option<String> {
+    for part in digits.split('.') {
+        if part.starts_with('_') || part.ends_with('_') || part.contains("__") {
+            return None;
+        }
+    }
+
+    Some(digits.chars().filter(|c| *c != '_').collect())
+}
+
 pub fn lex(input: &str) -> TokenIterator {
+    lex_chars(input.chars())
+}
+
+/// Lex characters as they come, so the whole script doesn't need to be in memory
+pub fn lex_chars<'a, I: Iterator<Item = char> + 'a>(chars: I) -> TokenIterator<'a> {
+    let chars: Box<Iterator<Item = char> + 'a> = Box::new(chars);
+
     TokenIterator {
         last: Token::LexErr(LexError::Nothing),
-        char_stream: input.chars().peekable(),
+        char_stream: chars.peekable(),
+    }
+}
+
+/// Lex a script read from `reader`, through a buffer. The lexer only sees characters, so the
+/// first read error, or `InvalidData` for bytes that aren't UTF-8, ends the input and is
+/// stored into `error` for the caller to check once parsing is done
+pub fn lex_read<'a, R: Read + 'a>(
+    reader: R,
+    error: &'a RefCell<Option<io::Error>>,
+) -> TokenIterator<'a> {
+    lex_chars(ReadChars {
+        reader: BufReader::new(reader),
+        error: error,
+    })
+}
+
+/// Decodes UTF-8 characters from a buffered reader
+struct ReadChars<'a, R> {
+    reader: BufReader<R>,
+    error: &'a RefCell<Option<io::Error>>,
+}
+
+impl<'a, R: Read> ReadChars<'a, R> {
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        let mut bytes = [0u8; 4];
+
+        let first = match self.reader.fill_buf()?.first() {
+            Some(&b) => b,
+            None => return Ok(None),
+        };
+        let width = match first {
+            0x00..=0x7F => 1,
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 0,
+        };
+        if width == 0 {
+            return Err(not_utf8());
+        }
+
+        // A character may be split across two fills of the buffer
+        self.reader.read_exact(&mut bytes[..width]).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                not_utf8()
+            } else {
+                e
+            }
+        })?;
+
+        match str::from_utf8(&bytes[..width]) {
+            Ok(s) => Ok(s.chars().next()),
+            Err(_) => Err(not_utf8()),
+        }
     }
 }
 
+impl<'a, R: Read> Iterator for ReadChars<'a, R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        if self.error.borrow().is_some() {
+            return None;
+        }
+
+        match self.read_char() {
+            Ok(c) => c,
+            Err(e) => {
+                *self.error.borrow_mut() = Some(e);
+                None
+            }
+        }
+    }
+}
+
+fn not_utf8() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "stream did not contain valid UTF-8",
+    )
+}
+
 fn get_precedence(token: &Token) -> i32 {
     match *token {
         Token::Equals
@@ -819,6 +1054,31 @@ fn parse_index_expr<'a>(
     }
 }
 
+fn parse_value_index_expr<'a>(
+    lhs: Expr,
+    input: &mut Peekable<TokenIterator<'a>>,
+) -> Result<Expr, ParseError> {
+    let mut lhs_curr = lhs;
+
+    while let Some(&Token::LSquare) = input.peek() {
+        input.next();
+
+        let idx = match parse_expr(input) {
+            Ok(idx) => idx,
+            Err(_) => return Err(ParseError::MalformedIndexExpr),
+        };
+
+        match input.next() {
+            Some(Token::RSquare) => (),
+            _ => return Err(ParseError::MalformedIndexExpr),
+        }
+
+        lhs_curr = Expr::IndexValue(Box::new(lhs_curr), Box::new(idx));
+    }
+
+    Ok(lhs_curr)
+}
+
 fn parse_ident_expr<'a>(
     id: String,
     input: &mut Peekable<TokenIterator<'a>>,
@@ -826,7 +1086,8 @@ fn parse_ident_expr<'a>(
     match input.peek() {
         Some(&Token::LParen) => {
             input.next();
-            parse_call_expr(id, input)
+            let call = try!(parse_call_expr(id, input));
+            parse_value_index_expr(call, input)
         }
         Some(&Token::LSquare) => {
             input.next();
@@ -872,10 +1133,14 @@ fn parse_primary<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Expr, Pa
             Token::IntConst(ref x) => Ok(Expr::IntConst(*x)),
             Token::FloatConst(ref x) => Ok(Expr::FloatConst(*x)),
             Token::StringConst(ref s) => Ok(Expr::StringConst(s.clone())),
+            Token::InterpolatedString(ref parts) => parse_interpolated_string(parts),
             Token::CharConst(ref c) => Ok(Expr::CharConst(*c)),
             Token::Identifier(ref s) => parse_ident_expr(s.clone(), input),
             Token::LParen => parse_paren_expr(input),
             Token::LSquare => parse_array_expr(input),
+            Token::Fn => {
+                parse_fn_rest("anonymous".to_string(), input).map(|f| Expr::AnonFn(Arc::new(f)))
+            }
             Token::True => Ok(Expr::True),
             Token::False => Ok(Expr::False),
             Token::LexErr(le) => {
@@ -892,6 +1157,36 @@ fn parse_primary<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Expr, Pa
     }
 }
 
+/// Turns `"a ${x} b"` into `"a " + to_string(x) + " b"`
+fn parse_interpolated_string(parts: &[StringPart]) -> Result<Expr, ParseError> {
+    let mut result = Expr::StringConst(String::new());
+
+    for part in parts {
+        let expr = match *part {
+            StringPart::Text(ref s) if s.is_empty() => continue,
+            StringPart::Text(ref s) => Expr::StringConst(s.clone()),
+            StringPart::Code(ref code) => {
+                let mut input = lex(code).peekable();
+                let expr =
+                    parse_expr(&mut input).map_err(|_| ParseError::MalformedInterpolation)?;
+
+                if input.peek().is_some() {
+                    return Err(ParseError::MalformedInterpolation);
+                }
+
+                Expr::FnCall("to_string".to_string(), vec![expr])
+            }
+        };
+
+        result = match result {
+            Expr::StringConst(ref s) if s.is_empty() => expr,
+            _ => Expr::FnCall("+".to_string(), vec![result, expr]),
+        };
+    }
+
+    Ok(result)
+}
+
 fn parse_unary<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Expr, ParseError> {
     let tok = match input.peek() {
         Some(tok) => tok.clone(),
@@ -899,17 +1194,22 @@ fn parse_unary<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Expr, Pars
     };
 
     match tok {
+        // Unary operators nest, as in `- -x` or `!!x`
         Token::UnaryMinus => {
             input.next();
-            Ok(Expr::FnCall("-".to_string(), vec![parse_primary(input)?]))
+            match input.peek() {
+                // Only lexed right after a unary minus, the literal is already negated
+                Some(&Token::IntConst(i64::MIN)) => parse_primary(input),
+                _ => Ok(Expr::FnCall("-".to_string(), vec![parse_unary(input)?])),
+            }
         }
         Token::UnaryPlus => {
             input.next();
-            parse_primary(input)
+            parse_unary(input)
         }
         Token::Bang => {
             input.next();
-            Ok(Expr::FnCall("!".to_string(), vec![parse_primary(input)?]))
+            Ok(Expr::FnCall("!".to_string(), vec![parse_unary(input)?]))
         }
         _ => parse_primary(input),
     }
@@ -944,8 +1244,9 @@ fn parse_binop<'a>(
 
             if curr_prec < next_prec {
                 rhs = try!(parse_binop(input, curr_prec + 1, rhs));
-            } else if curr_prec >= 100 {
-                // Always bind right to left for precedence over 100
+            } else if curr_prec >= 100 || (curr_prec == 10 && next_prec == 10) {
+                // Always bind right to left for precedence over 100, and for
+                // assignments so that `a = b = 5` assigns `b` first
                 rhs = try!(parse_binop(input, curr_prec, rhs));
             }
 
@@ -1094,6 +1395,40 @@ fn parse_while<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Stmt, Pars
     Ok(Stmt::While(Box::new(guard), Box::new(body)))
 }
 
+fn parse_do_while<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Stmt, ParseError> {
+    input.next();
+
+    let body = try!(parse_block(input));
+
+    match input.next() {
+        Some(Token::While) => (),
+        _ => return Err(ParseError::MissingWhile),
+    }
+
+    let guard = try!(parse_expr(input));
+
+    Ok(Stmt::DoWhile(Box::new(body), Box::new(guard)))
+}
+
+fn parse_for<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Stmt, ParseError> {
+    input.next();
+
+    let name = match input.next() {
+        Some(Token::Identifier(ref s)) => s.clone(),
+        _ => return Err(ParseError::MalformedForLoop),
+    };
+
+    match input.next() {
+        Some(Token::In) => (),
+        _ => return Err(ParseError::MalformedForLoop),
+    }
+
+    let expr = try!(parse_expr(input));
+    let body = try!(parse_block(input));
+
+    Ok(Stmt::For(name, Box::new(expr), Box::new(body)))
+}
+
 fn parse_loop<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Stmt, ParseError> {
     input.next();
 
@@ -1102,6 +1437,76 @@ fn parse_loop<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Stmt, Parse
     Ok(Stmt::Loop(Box::new(body)))
 }
 
+fn parse_switch<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Stmt, ParseError> {
+    input.next();
+
+    let value = try!(parse_expr(input));
+
+    match input.next() {
+        Some(Token::LCurly) => (),
+        _ => return Err(ParseError::MissingLCurly),
+    }
+
+    let mut arms = Vec::new();
+
+    loop {
+        if let Some(&Token::RCurly) = input.peek() {
+            input.next();
+            break;
+        }
+        // No arm could ever be reached after the default one
+        if let Some(&(None, _)) = arms.last() {
+            return Err(ParseError::SwitchDefaultNotLast);
+        }
+
+        let pattern = match input.peek() {
+            Some(&Token::Identifier(ref s)) if s == "_" => {
+                input.next();
+                None
+            }
+            Some(_) => {
+                let pattern = try!(parse_unary(input));
+                if !is_literal(&pattern) {
+                    return Err(ParseError::MalformedSwitchArm);
+                }
+                Some(pattern)
+            }
+            None => return Err(ParseError::MissingRCurly),
+        };
+
+        match input.next() {
+            Some(Token::Arrow) => (),
+            _ => return Err(ParseError::MalformedSwitchArm),
+        }
+
+        let body = try!(parse_block(input));
+        arms.push((pattern, body));
+
+        if let Some(&Token::Comma) = input.peek() {
+            input.next();
+        }
+    }
+
+    Ok(Stmt::Switch(Box::new(value), arms))
+}
+
+/// Tells whether a switch pattern is a constant, possibly a negative number
+fn is_literal(expr: &Expr) -> bool {
+    match *expr {
+        Expr::IntConst(_)
+        | Expr::FloatConst(_)
+        | Expr::CharConst(_)
+        | Expr::StringConst(_)
+        | Expr::True
+        | Expr::False => true,
+        Expr::FnCall(ref op, ref args) if op == "-" && args.len() == 1 => match args[0] {
+            Expr::IntConst(_) | Expr::FloatConst(_) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
 fn parse_var<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Stmt, ParseError> {
     input.next();
 
@@ -1130,23 +1535,15 @@ fn parse_block<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Stmt, Pars
 
     let mut stmts = Vec::new();
 
-    let skip_body = match input.peek() {
-        Some(&Token::RCurly) => true,
-        _ => false,
-    };
+    skip_semicolons(input);
 
-    if !skip_body {
-        while let Some(_) = input.peek() {
-            stmts.push(try!(parse_stmt(input)));
-
-            if let Some(&Token::Semicolon) = input.peek() {
-                input.next();
-            }
-
-            if let Some(&Token::RCurly) = input.peek() {
-                break;
-            }
+    while let Some(_) = input.peek() {
+        if let Some(&Token::RCurly) = input.peek() {
+            break;
         }
+
+        stmts.push(try!(parse_stmt(input)));
+        skip_semicolons(input);
     }
 
     match input.peek() {
@@ -1158,6 +1555,12 @@ fn parse_block<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Stmt, Pars
     }
 }
 
+fn skip_semicolons<'a>(input: &mut Peekable<TokenIterator<'a>>) {
+    while let Some(&Token::Semicolon) = input.peek() {
+        input.next();
+    }
+}
+
 fn parse_expr_stmt<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Stmt, ParseError> {
     let expr = try!(parse_expr(input));
     Ok(Stmt::Expr(Box::new(expr)))
@@ -1167,7 +1570,10 @@ fn parse_stmt<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Stmt, Parse
     match input.peek() {
         Some(&Token::If) => parse_if(input),
         Some(&Token::While) => parse_while(input),
+        Some(&Token::Do) => parse_do_while(input),
+        Some(&Token::For) => parse_for(input),
         Some(&Token::Loop) => parse_loop(input),
+        Some(&Token::Switch) => parse_switch(input),
         Some(&Token::Break) => {
             input.next();
             Ok(Stmt::Break)
@@ -1196,6 +1602,14 @@ fn parse_fn<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<FnDef, ParseE
         _ => return Err(ParseError::FnMissingName),
     };
 
+    parse_fn_rest(name, input)
+}
+
+/// Parses the parameters and body of a function, whose name has already been read
+fn parse_fn_rest<'a>(
+    name: String,
+    input: &mut Peekable<TokenIterator<'a>>,
+) -> Result<FnDef, ParseError> {
     match input.peek() {
         Some(&Token::LParen) => {
             input.next();
@@ -1241,22 +1655,115 @@ fn parse_top_level<'a>(
     let mut stmts = Vec::new();
     let mut fndefs = Vec::new();
 
+    skip_semicolons(input);
+
     while let Some(_) = input.peek() {
         match input.peek() {
             Some(&Token::Fn) => fndefs.push(try!(parse_fn(input))),
             _ => stmts.push(try!(parse_stmt(input))),
         }
 
-        if let Some(&Token::Semicolon) = input.peek() {
-            input.next();
-        }
+        skip_semicolons(input);
     }
 
     Ok((stmts, fndefs))
 }
 
+/// Renders parsed statements one node per line, children being indented below their parent.
+/// Expressions are written on a single line.
+pub fn dump_ast(stmts: &[Stmt]) -> String {
+    let mut out = String::new();
+
+    for stmt in stmts {
+        dump_stmt(&mut out, stmt, 0);
+    }
+
+    out
+}
+
+/// Renders a function definition followed by its body, in the format of `dump_ast`.
+pub fn dump_fn_def(f: &FnDef) -> String {
+    let mut out = format!("Fn {}({})\n", f.name, f.params.join(", "));
+    dump_stmt(&mut out, &f.body, 1);
+    out
+}
+
+fn dump_line(out: &mut String, depth: usize, line: &str) {
+    for _ in 0..depth {
+        out.push_str("  ");
+    }
+    out.push_str(line);
+    out.push('\n');
+}
+
+fn dump_stmt(out: &mut String, stmt: &Stmt, depth: usize) {
+    match *stmt {
+        Stmt::If(ref guard, ref body) => {
+            dump_line(out, depth, &format!("If {:?}", guard));
+            dump_stmt(out, body, depth + 1);
+        }
+        Stmt::IfElse(ref guard, ref body, ref else_body) => {
+            dump_line(out, depth, &format!("If {:?}", guard));
+            dump_stmt(out, body, depth + 1);
+            dump_line(out, depth, "Else");
+            dump_stmt(out, else_body, depth + 1);
+        }
+        Stmt::While(ref guard, ref body) => {
+            dump_line(out, depth, &format!("While {:?}", guard));
+            dump_stmt(out, body, depth + 1);
+        }
+        Stmt::DoWhile(ref body, ref guard) => {
+            dump_line(out, depth, "Do");
+            dump_stmt(out, body, depth + 1);
+            dump_line(out, depth, &format!("While {:?}", guard));
+        }
+        Stmt::For(ref name, ref expr, ref body) => {
+            dump_line(out, depth, &format!("For {} In {:?}", name, expr));
+            dump_stmt(out, body, depth + 1);
+        }
+        Stmt::Loop(ref body) => {
+            dump_line(out, depth, "Loop");
+            dump_stmt(out, body, depth + 1);
+        }
+        Stmt::Switch(ref expr, ref arms) => {
+            dump_line(out, depth, &format!("Switch {:?}", expr));
+            for &(ref pattern, ref body) in arms {
+                match *pattern {
+                    Some(ref pattern) => dump_line(out, depth + 1, &format!("Case {:?}", pattern)),
+                    None => dump_line(out, depth + 1, "Default"),
+                }
+                dump_stmt(out, body, depth + 2);
+            }
+        }
+        Stmt::Var(ref name, Some(ref expr)) => {
+            dump_line(out, depth, &format!("Var {} = {:?}", name, expr))
+        }
+        Stmt::Var(ref name, None) => dump_line(out, depth, &format!("Var {}", name)),
+        Stmt::Block(ref stmts) => {
+            dump_line(out, depth, "Block");
+            for stmt in stmts {
+                dump_stmt(out, stmt, depth + 1);
+            }
+        }
+        Stmt::Expr(ref expr) => dump_line(out, depth, &format!("Expr {:?}", expr)),
+        Stmt::Break => dump_line(out, depth, "Break"),
+        Stmt::Return => dump_line(out, depth, "Return"),
+        Stmt::ReturnWithVal(ref expr) => dump_line(out, depth, &format!("Return {:?}", expr)),
+    }
+}
+
 pub fn parse<'a>(
     input: &mut Peekable<TokenIterator<'a>>,
 ) -> Result<(Vec<Stmt>, Vec<FnDef>), ParseError> {
     parse_top_level(input)
 }
+
+/// Parse one expression, which must be the whole input
+pub fn parse_expression<'a>(input: &mut Peekable<TokenIterator<'a>>) -> Result<Expr, ParseError> {
+    let expr = parse_expr(input)?;
+
+    match input.peek() {
+        Some(_) => Err(ParseError::BadInput),
+        None => Ok(expr),
+    }
+}
@@ -1,15 +1,58 @@
 //! A chain block.
 
-use bincode::serialize;
-use sha1::Sha1;
+use std::error::Error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 
+use canonical::{put_str, put_u64};
+use hash_algo::HashAlgo;
 use hash_content::HashContent;
 
-#[derive(Serialize, Deserialize, Clone)]
+/// Maximum size of the data of one block, in bytes.
+pub const MAX_BLOCK_DATA: usize = 1024 * 1024;
+
+/// Error returned when a block cannot be created.
+#[derive(Debug, PartialEq)]
+pub enum BlockError {
+    DataTooLarge(usize),
+    DifficultyTooHigh(usize),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BlockError::DataTooLarge(size) => write!(
+                f,
+                "the block data is {} bytes, more than the {} bytes allowed",
+                size, MAX_BLOCK_DATA
+            ),
+            BlockError::DifficultyTooHigh(difficulty) => write!(
+                f,
+                "a difficulty of {} is more than the digest length, no block can meet it",
+                difficulty
+            ),
+        }
+    }
+}
+
+impl Error for BlockError {}
+
+/// Blocks are equal when all their fields are, so a block whose digest doesn't match its content
+/// differs from the valid one. Hashing only uses the digest, as equal blocks share it.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct Block {
     content: HashContent,
     previous: String,
     current: String,
+    nonce: u64,
+    algo: HashAlgo,
+    difficulty: usize,
+}
+
+impl Hash for Block {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.current.hash(state);
+    }
 }
 
 impl Block {
@@ -22,17 +65,134 @@ impl Block {
     ///
     /// Returns:
     ///
-    /// new block
-    pub fn new(data: &Vec<u8>, previous: String) -> Block {
-        let content = HashContent::new(data.clone());
-        let bytes = serialize(&content).unwrap();
-        let digest = Sha1::from(bytes).hexdigest();
+    /// new block, or an error if the data is larger than `MAX_BLOCK_DATA`
+    pub fn new(data: &Vec<u8>, previous: String) -> Result<Block, BlockError> {
+        Block::new_with_algo(data, previous, HashAlgo::default())
+    }
+
+    /// Creates the block like `new`, with the given hashing algorithm.
+    ///
+    /// Args:
+    ///
+    /// `data` - the data of the block
+    /// `previous` - the digest of the previous block (empty if genesis)
+    /// `algo` - the hashing algorithm of the chain
+    ///
+    /// Returns:
+    ///
+    /// new block, or an error if the data is larger than `MAX_BLOCK_DATA`
+    pub fn new_with_algo(
+        data: &Vec<u8>,
+        previous: String,
+        algo: HashAlgo,
+    ) -> Result<Block, BlockError> {
+        Block::from_content(HashContent::new(data.clone()), previous, algo)
+    }
 
-        Block {
+    /// Creates the block from an already built content. The digest only depends
+    /// on the content, the previous digest and the algorithm.
+    ///
+    /// Args:
+    ///
+    /// `content` - the hashed content of the block
+    /// `previous` - the digest of the previous block (empty if genesis)
+    /// `algo` - the hashing algorithm of the chain
+    ///
+    /// Returns:
+    ///
+    /// new block, or an error if the data is larger than `MAX_BLOCK_DATA`
+    pub fn from_content(
+        content: HashContent,
+        previous: String,
+        algo: HashAlgo,
+    ) -> Result<Block, BlockError> {
+        if content.get_data_len() > MAX_BLOCK_DATA {
+            return Err(BlockError::DataTooLarge(content.get_data_len()));
+        }
+
+        let mut block = Block {
             content: content,
             previous: previous,
-            current: digest,
+            current: String::new(),
+            nonce: 0,
+            algo: algo,
+            difficulty: 0,
+        };
+        block.current = block.compute_hash();
+
+        Ok(block)
+    }
+
+    /// Creates the block like `new`, then searches the nonce giving a digest
+    /// that starts with `difficulty` zeros (proof of work). The difficulty is stored
+    /// into the block, so that it can be checked later on.
+    ///
+    /// Args:
+    ///
+    /// `data` - the data of the block
+    /// `previous` - the digest of the previous block (empty if genesis)
+    /// `difficulty` - the amount of leading hexadecimal zeros of the digest
+    ///
+    /// Returns:
+    ///
+    /// new mined block, or an error if the data is larger than `MAX_BLOCK_DATA`
+    /// or if the difficulty is higher than the digest length (the search would never end)
+    pub fn mine(data: &Vec<u8>, previous: String, difficulty: usize) -> Result<Block, BlockError> {
+        let mut block = Block::new(data, previous)?;
+        if difficulty > block.current.len() {
+            return Err(BlockError::DifficultyTooHigh(difficulty));
+        }
+
+        block.difficulty = difficulty;
+        block.current = block.compute_hash();
+
+        while !block.meets_difficulty(difficulty) {
+            block.nonce += 1;
+            block.current = block.compute_hash();
         }
+
+        Ok(block)
+    }
+
+    /// Checks the size of a block that has not been built by this node, e.g. received from a peer.
+    ///
+    /// Returns:
+    ///
+    /// true if the data is not larger than `MAX_BLOCK_DATA`
+    pub fn has_valid_size(&self) -> bool {
+        self.content.get_data_len() <= MAX_BLOCK_DATA
+    }
+
+    /// Checks the proof of work of the block.
+    ///
+    /// Args:
+    ///
+    /// `difficulty` - the amount of leading hexadecimal zeros the digest must have
+    ///
+    /// Returns:
+    ///
+    /// true if the digest starts with enough zeros
+    pub fn meets_difficulty(&self, difficulty: usize) -> bool {
+        self.current.len() >= difficulty && self.current.chars().take(difficulty).all(|c| c == '0')
+    }
+
+    /// Calculates the hash digest the block should have according to its previous digest and its content,
+    /// so that a block cannot be moved elsewhere into the chain without changing its digest.
+    ///
+    /// The hashed payload is the canonical encoding of the previous digest, the content, the nonce
+    /// and the difficulty.
+    ///
+    /// Returns:
+    ///
+    /// expected block digest as string
+    pub fn compute_hash(&self) -> String {
+        let mut bytes = Vec::new();
+        put_str(&mut bytes, &self.previous);
+        bytes.extend_from_slice(&self.content.to_bytes());
+        put_u64(&mut bytes, self.nonce);
+        put_u64(&mut bytes, self.difficulty as u64);
+
+        self.algo.hexdigest(&bytes)
     }
 
     /// Getter of the current block hash digest.
@@ -48,6 +208,42 @@ impl Block {
         &self.previous
     }
 
+    /// Getter of the proof of work nonce.
+    ///
+    /// Returns:
+    ///
+    /// nonce found while mining (0 if the block has not been mined)
+    pub fn get_nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    /// Getter of the difficulty the block was mined at.
+    ///
+    /// Returns:
+    ///
+    /// amount of leading hexadecimal zeros the digest must have (0 if the block has not been mined)
+    pub fn get_difficulty(&self) -> usize {
+        self.difficulty
+    }
+
+    /// Checks the proof of work against the difficulty stored into the block.
+    ///
+    /// Returns:
+    ///
+    /// true if the digest starts with at least as many zeros as the block claims
+    pub fn meets_own_difficulty(&self) -> bool {
+        self.meets_difficulty(self.difficulty)
+    }
+
+    /// Getter of the hashing algorithm.
+    ///
+    /// Returns:
+    ///
+    /// algorithm used for the block digest
+    pub fn get_algo(&self) -> HashAlgo {
+        self.algo
+    }
+
     /// Getter of the hashed content.
     ///
     /// Returns:
@@ -57,3 +253,137 @@ impl Block {
         &self.content
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mined_block_meets_difficulty() {
+        let block = Block::mine(&vec![1, 2, 3], String::new(), 2).unwrap();
+
+        assert!(block.get_current().starts_with("00"));
+        assert_eq!(block.get_current(), block.compute_hash());
+    }
+
+    #[test]
+    fn stored_difficulty_is_checked() {
+        use blocks::validate_chain;
+
+        /* proving more work than claimed is fine */
+        let block = Block::mine(&vec![1, 2, 3], String::new(), 1).unwrap();
+        assert_eq!(block.get_difficulty(), 1);
+        let mut humble = block.clone();
+        humble.difficulty = 0;
+        while !humble.compute_hash().starts_with('0') {
+            humble.nonce += 1;
+        }
+        humble.current = humble.compute_hash();
+        assert!(humble.meets_own_difficulty());
+        assert!(validate_chain(&[humble]));
+
+        /* claiming more work than proven is not */
+        let mut boastful = block.clone();
+        boastful.difficulty = 8;
+        boastful.current = boastful.compute_hash();
+        assert!(!boastful.meets_own_difficulty());
+        assert!(!validate_chain(&[boastful]));
+    }
+
+    #[test]
+    fn blocks_compare_and_hash() {
+        use std::collections::HashSet;
+
+        let build = |data: Vec<u8>| {
+            Block::from_content(
+                HashContent::with_timestamp(data, 1_500_000_000),
+                String::new(),
+                HashAlgo::Sha256,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(build(vec![1]), build(vec![1]));
+        assert_ne!(build(vec![1]), build(vec![2]));
+
+        let mut tampered = build(vec![1]);
+        tampered.content = HashContent::with_timestamp(vec![2], 1_500_000_000);
+        assert_ne!(tampered, build(vec![1]));
+
+        let set: HashSet<Block> = vec![build(vec![1]), build(vec![2]), build(vec![1])]
+            .into_iter()
+            .collect();
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&build(vec![2])));
+    }
+
+    #[test]
+    fn digest_depends_on_previous_and_content() {
+        let build = |data: Vec<u8>, previous: &str| {
+            Block::from_content(
+                HashContent::with_timestamp(data, 1_500_000_000),
+                previous.to_string(),
+                HashAlgo::Sha256,
+            )
+            .unwrap()
+        };
+
+        let block = build(vec![1, 2, 3], "ab");
+        assert_eq!(
+            block.get_current(),
+            build(vec![1, 2, 3], "ab").get_current()
+        );
+        assert_eq!(block.get_current(), block.compute_hash());
+
+        assert_ne!(
+            block.get_current(),
+            build(vec![1, 2, 4], "ab").get_current()
+        );
+        assert_ne!(
+            block.get_current(),
+            build(vec![1, 2, 3], "cd").get_current()
+        );
+    }
+
+    #[test]
+    fn unreachable_difficulty_is_an_error() {
+        let digest_len = HashAlgo::default().digest_len();
+
+        assert_eq!(
+            Block::mine(&vec![1], String::new(), digest_len + 1),
+            Err(BlockError::DifficultyTooHigh(digest_len + 1))
+        );
+        assert_eq!(
+            Block::mine(&vec![1], String::new(), 65),
+            Err(BlockError::DifficultyTooHigh(65))
+        );
+    }
+
+    #[test]
+    fn data_size_is_limited() {
+        let block = Block::new(&vec![0; MAX_BLOCK_DATA], String::new()).unwrap();
+        assert!(block.has_valid_size());
+
+        assert_eq!(
+            Block::new(&vec![0; MAX_BLOCK_DATA + 1], String::new()),
+            Err(BlockError::DataTooLarge(MAX_BLOCK_DATA + 1))
+        );
+
+        let mut oversized = block.clone();
+        oversized.content = HashContent::new(vec![0; MAX_BLOCK_DATA + 1]);
+        assert!(!oversized.has_valid_size());
+    }
+
+    #[test]
+    fn sha256_digest_is_stable() {
+        let block = Block::new(&vec![1, 2, 3], String::new()).unwrap();
+
+        assert_eq!(block.get_algo(), HashAlgo::Sha256);
+        assert_eq!(block.get_current().len(), 64);
+        assert_eq!(block.compute_hash(), block.get_current());
+
+        let mut other = block.clone();
+        other.algo = HashAlgo::Sha512;
+        assert_ne!(other.compute_hash(), block.get_current());
+    }
+}
@@ -1,17 +1,30 @@
 //! Blocks routines.
 
-use std::io::Write;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Result, Write};
 use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
+use std::thread::spawn;
 
-use bincode::serialize;
+use bincode::{deserialize, serialize};
 
-use block::Block;
+use time;
 
-use message::{Message, MessageLabel};
+use serde_json;
+
+use block::{Block, BlockError};
+
+use hash_algo::HashAlgo;
+
+use display::format_block;
+
+use message::{write_frame, Message, MessageLabel};
 
 use peers::create_stream;
 
+use transaction::transaction;
+
 /// Displays the blockchain blocks.
 ///
 /// Args:
@@ -20,82 +33,1183 @@ use peers::create_stream;
 pub fn list_blocks(chain: &Arc<Mutex<Vec<Block>>>) {
     let chain = chain.lock().unwrap();
 
-    for block in chain.iter() {
-        let content = block.get_content();
-        println!("Previous Hash: {}", block.get_previous());
-        println!("Hash: {}", block.get_current());
-        println!("Timestamp: {}", content.get_timestamp());
-        println!("Data: {:?} \n\n", content.get_data());
+    for (height, block) in chain.iter().enumerate() {
+        println!("{} \n\n", format_block(height, block));
     }
 }
 
-/// Tries to send the given block to all the given peers. Skip peer if timeout.
+/// Last block of the chain.
+///
+/// Args:
+///
+/// `chain` - the chain to read
+///
+/// Returns:
+///
+/// the last block, or `None` if the chain is empty
+pub fn tip(chain: &[Block]) -> Option<&Block> {
+    chain.last()
+}
+
+/// Height of the last block of the chain, the genesis being at height 0.
+///
+/// Args:
+///
+/// `chain` - the chain to read
+///
+/// Returns:
+///
+/// height of the tip, or `None` if the chain is empty
+pub fn height(chain: &[Block]) -> Option<u64> {
+    chain.len().checked_sub(1).map(|height| height as u64)
+}
+
+/// Copies the chain up to a given height, so that it can be extended independently
+/// (e.g. to build a competing fork, or to keep a snapshot).
+///
+/// Args:
+///
+/// `chain` - the chain to copy
+/// `height` - the height of the last block to keep, the genesis being at height 0
+///
+/// Returns:
+///
+/// the blocks from the genesis to `height` included, or the whole chain if it is not that high
+pub fn fork_from(chain: &[Block], height: u64) -> Vec<Block> {
+    let length = (height as usize).saturating_add(1).min(chain.len());
+    chain[..length].to_vec()
+}
+
+/// Read-only view of a chain, to go through its blocks and transactions with iterator combinators.
+/// The chain itself stays a `Vec<Block>`.
+#[derive(Clone, Copy)]
+pub struct Chain<'a> {
+    blocks: &'a [Block],
+}
+
+impl<'a> Chain<'a> {
+    /// Chain view constructor.
+    ///
+    /// Args:
+    ///
+    /// `blocks` - the blocks of the chain, from the genesis to the tip
+    ///
+    /// Returns:
+    ///
+    /// the view of the chain
+    pub fn new(blocks: &'a [Block]) -> Chain<'a> {
+        Chain { blocks: blocks }
+    }
+
+    /// Iterates over the blocks, from the genesis to the tip.
+    pub fn blocks_iter(&self) -> ::std::slice::Iter<'a, Block> {
+        self.blocks.iter()
+    }
+
+    /// Iterates over the transactions sealed into the blocks (see `assemble_block`), in chain order.
+    /// Blocks whose data is not a list of transactions, such as the genesis, are skipped.
+    pub fn tx_iter(&self) -> impl Iterator<Item = transaction> + 'a {
+        self.blocks.iter().flat_map(|block| {
+            deserialize::<Vec<transaction>>(&block.get_content().get_data()).unwrap_or_default()
+        })
+    }
+}
+
+/// Hashes of the blocks of the chain, from the genesis to the tip.
+///
+/// Args:
+///
+/// `chain` - the chain to read
+///
+/// Returns:
+///
+/// the hash of every block, in chain order
+pub fn block_hashes(chain: &[Block]) -> Vec<String> {
+    chain
+        .iter()
+        .map(|block| block.get_current().to_string())
+        .collect()
+}
+
+/// Height of the last block shared by the local chain and a remote one, so that only
+/// the blocks after it have to be transferred when the chains diverge.
+///
+/// Args:
+///
+/// `local` - the local chain
+/// `remote_hashes` - the hashes of the remote chain, from the genesis to the tip
+///
+/// Returns:
+///
+/// height of the last common block (also 0 when even the genesis differs,
+/// `shares_genesis` tells both cases apart)
+pub fn common_height(local: &[Block], remote_hashes: &[String]) -> u64 {
+    let shared = local
+        .iter()
+        .zip(remote_hashes.iter())
+        .take_while(|&(block, hash)| block.get_current() == hash)
+        .count();
+
+    shared.saturating_sub(1) as u64
+}
+
+/// Tells whether the local chain and a remote one start with the same genesis block.
+///
+/// Args:
+///
+/// `local` - the local chain
+/// `remote_hashes` - the hashes of the remote chain, from the genesis to the tip
+///
+/// Returns:
+///
+/// true if both chains have the same genesis
+pub fn shares_genesis(local: &[Block], remote_hashes: &[String]) -> bool {
+    match (local.first(), remote_hashes.first()) {
+        (Some(block), Some(hash)) => block.get_current() == hash,
+        _ => false,
+    }
+}
+
+/// Maximum amount of peers the block is sent to at the same time.
+const BROADCAST_WORKERS: usize = 4;
+
+/// Tries to send the given block to all the given peers, at most `BROADCAST_WORKERS` at a time.
+/// Duplicated peers are only sent the block once. Skip peer if timeout.
 ///
 /// Args:
 ///
 /// `peers` - list of peers
 /// `block` - the block object to send
-pub fn broadcast_block(peers: &Vec<String>, block: Block) {
-    /* we voluntary halt the program if serialization and stream buffer write fails;
-    in fact, if these problem happen, that means something is clearly wrong */
+///
+/// Returns:
+///
+/// the peers the block could not be sent to
+pub fn broadcast_block(peers: &Vec<String>, block: Block) -> Vec<String> {
+    /* we voluntary halt the program if serialization fails;
+    in fact, if this problem happens, that means something is clearly wrong */
 
     let message = Message::new(vec![block], MessageLabel::SendBlock);
 
-    let bytes = serialize(&message).unwrap();
+    let bytes = Arc::new(message.to_bytes());
 
-    for peer in peers.iter() {
-        let address_part: Vec<&str> = peer.split(':').collect();
-        let address = address_part.get(0).unwrap();
+    let mut known = HashSet::new();
+    let pending: Vec<String> = peers
+        .iter()
+        .filter(|peer| known.insert(peer.as_str()))
+        .cloned()
+        .collect();
+    let workers = pending.len().min(BROADCAST_WORKERS);
 
-        let mut stream = match create_stream(&peer) {
-            Some(stream) => stream,
-            None => {
-                println!("Cannot connect to {}.", address);
-                continue;
-            }
-        };
+    let pending = Arc::new(Mutex::new(pending));
+    let failures = Arc::new(Mutex::new(Vec::new()));
 
-        stream.write(&bytes).unwrap();
-        println!("Block sent to {}.", address);
+    let handles: Vec<_> = (0..workers)
+        .map(|_| {
+            let pending = pending.clone();
+            let failures = failures.clone();
+            let bytes = bytes.clone();
+
+            spawn(move || loop {
+                let peer = match pending.lock().unwrap().pop() {
+                    Some(peer) => peer,
+                    None => break,
+                };
+
+                let sent = match create_stream(&peer) {
+                    Some(mut stream) => write_frame(&mut stream, &bytes).is_ok(),
+                    None => false,
+                };
+
+                if sent {
+                    println!("Block sent to {}.", peer);
+                } else {
+                    println!("Cannot send the block to {}.", peer);
+                    failures.lock().unwrap().push(peer);
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
     }
 
     println!("Block creation broadcast terminated.");
+
+    let failures = failures.lock().unwrap();
+    failures.clone()
+}
+
+/// How far ahead of the validator's clock a block timestamp may be, in seconds.
+pub const MAX_TIMESTAMP_DRIFT: i64 = 2 * 60 * 60;
+
+/// Checks that every block digest matches its content and that every block refers to the previous one,
+/// with timestamps at most `MAX_TIMESTAMP_DRIFT` ahead of the current time.
+///
+/// Args:
+///
+/// `chain` - the chain to check
+///
+/// Returns:
+///
+/// true if the chain is valid
+pub fn validate_chain(chain: &[Block]) -> bool {
+    validate_chain_with_drift(chain, MAX_TIMESTAMP_DRIFT)
+}
+
+/// Checks the chain like `validate_chain`, with a custom tolerance for timestamps in the future.
+///
+/// Args:
+///
+/// `chain` - the chain to check
+/// `max_drift` - how far ahead of the current time a block timestamp may be, in seconds
+///
+/// Returns:
+///
+/// true if the chain is valid
+pub fn validate_chain_with_drift(chain: &[Block], max_drift: i64) -> bool {
+    match chain.first() {
+        Some(genesis) => {
+            genesis.get_previous().is_empty()
+                && validate_suffix_with_drift(chain, None, genesis.get_algo(), max_drift)
+        }
+        None => true,
+    }
+}
+
+/// Checks that no block is older than the one before it, nor newer than a given time.
+///
+/// Args:
+///
+/// `blocks` - the blocks to check, in chain order
+/// `reference` - a block preceding them, if any, that the first one must not be older than
+/// `latest` - the latest accepted timestamp, in seconds
+///
+/// Returns:
+///
+/// true if the timestamps never go backwards and none is after `latest`
+fn validate_timestamps(blocks: &[Block], reference: Option<&Block>, latest: i64) -> bool {
+    let timestamp = |block: &Block| block.get_content().get_timestamp();
+    let mut earliest = reference.map(timestamp).unwrap_or(i64::MIN);
+
+    for block in blocks {
+        if timestamp(block) < earliest || timestamp(block) > latest {
+            return false;
+        }
+        earliest = timestamp(block);
+    }
+
+    true
+}
+
+/// Checks a contiguous part of a chain, whose first block refers to a block that is not part of it.
+/// Every block digest must meet the difficulty the block was mined at, and the timestamps must
+/// follow the reference block and be at most `MAX_TIMESTAMP_DRIFT` ahead of the current time.
+/// There is no retargeting, so no block may declare a lower difficulty than the one before it.
+///
+/// Args:
+///
+/// `blocks` - the blocks to check
+/// `reference` - the last local block before them (e.g. the one they refer to), if any
+/// `algo` - the hashing algorithm every block must use
+///
+/// Returns:
+///
+/// true if every block is valid and refers to the previous one
+pub fn validate_suffix(blocks: &[Block], reference: Option<&Block>, algo: HashAlgo) -> bool {
+    validate_suffix_with_drift(blocks, reference, algo, MAX_TIMESTAMP_DRIFT)
+}
+
+fn validate_suffix_with_drift(
+    blocks: &[Block],
+    reference: Option<&Block>,
+    algo: HashAlgo,
+    max_drift: i64,
+) -> bool {
+    let latest = time::now_utc().to_timespec().sec + max_drift;
+    if !validate_timestamps(blocks, reference, latest) {
+        return false;
+    }
+
+    let mut previous = match blocks.first() {
+        Some(block) => block.get_previous(),
+        None => return true,
+    };
+    let mut difficulty = reference.map(Block::get_difficulty).unwrap_or(0);
+
+    for block in blocks.iter() {
+        if block.get_previous() != previous
+            || block.get_current() != block.compute_hash()
+            || block.get_algo() != algo
+            || !block.has_valid_size()
+            || block.get_difficulty() < difficulty
+            || !block.meets_own_difficulty()
+        {
+            return false;
+        }
+
+        previous = block.get_current();
+        difficulty = block.get_difficulty();
+    }
+
+    true
+}
+
+/// Checks a chain that may have been pruned: the genesis and the retained suffix are checked separately,
+/// as the blocks linking them are gone.
+///
+/// Args:
+///
+/// `chain` - the chain to check
+///
+/// Returns:
+///
+/// true if both the genesis and the retained suffix are valid
+pub fn validate_pruned_chain(chain: &[Block]) -> bool {
+    if chain.is_empty() {
+        return true;
+    }
+
+    validate_chain(&chain[..1])
+        && validate_suffix(&chain[1..], Some(&chain[0]), chain[0].get_algo())
+}
+
+/// Drops the oldest blocks of the chain except the genesis, for light nodes that don't need the full history.
+/// Heights displayed afterwards are positions into the pruned chain.
+///
+/// Args:
+///
+/// `chain` - the chain to prune
+/// `keep_last` - the amount of blocks to keep, genesis included
+///
+/// Returns:
+///
+/// the amount of dropped blocks
+pub fn prune(chain: &mut Vec<Block>, keep_last: usize) -> usize {
+    let keep_last = keep_last.max(1);
+    if chain.len() <= keep_last {
+        return 0;
+    }
+
+    let dropped = chain.len() - keep_last;
+    chain.drain(1..1 + dropped);
+    dropped
+}
+
+/// Counts the leading zero bits of an hexadecimal digest.
+///
+/// Args:
+///
+/// `hash` - the hexadecimal digest
+///
+/// Returns:
+///
+/// amount of leading zero bits
+pub fn hash_leading_zeros(hash: &str) -> u32 {
+    let mut zeros = 0;
+
+    for c in hash.chars() {
+        match c.to_digit(16) {
+            Some(0) => zeros += 4,
+            Some(digit) => return zeros + digit.leading_zeros() - 28,
+            None => break,
+        }
+    }
+
+    zeros
+}
+
+/// Sums the work of every block of the chain, the work of one block being 2^(leading zero bits of its digest).
+///
+/// Args:
+///
+/// `chain` - the chain to weigh
+///
+/// Returns:
+///
+/// total work of the chain
+pub fn chain_work(chain: &[Block]) -> u128 {
+    chain.iter().fold(0u128, |work, block| {
+        let zeros = hash_leading_zeros(block.get_current());
+        work.saturating_add(1u128.checked_shl(zeros).unwrap_or(u128::MAX))
+    })
+}
+
+/// Summary of the chain, for operators checking the health of the node.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChainStats {
+    pub count: u64,
+    pub data_bytes: u64,
+    /* the timestamps are `None` for an empty chain */
+    pub earliest_timestamp: Option<i64>,
+    pub latest_timestamp: Option<i64>,
+    /* `None` when there are less than two blocks */
+    pub average_interval: Option<f64>,
+}
+
+/// Computes the summary of the chain.
+///
+/// Args:
+///
+/// `chain` - the chain to summarize
+///
+/// Returns:
+///
+/// the block count, the total size of the blocks data, the earliest and latest timestamps,
+/// and the average amount of seconds between two consecutive blocks
+pub fn stats(chain: &[Block]) -> ChainStats {
+    let timestamps = chain
+        .iter()
+        .map(|block| block.get_content().get_timestamp());
+
+    let average_interval = match (chain.first(), chain.last()) {
+        (Some(first), Some(last)) if chain.len() > 1 => {
+            let elapsed = last.get_content().get_timestamp() - first.get_content().get_timestamp();
+            Some(elapsed as f64 / (chain.len() - 1) as f64)
+        }
+        _ => None,
+    };
+
+    ChainStats {
+        count: chain.len() as u64,
+        data_bytes: chain
+            .iter()
+            .map(|block| block.get_content().get_data_len() as u64)
+            .sum(),
+        earliest_timestamp: timestamps.clone().min(),
+        latest_timestamp: timestamps.max(),
+        average_interval: average_interval,
+    }
+}
+
+/// Replaces the local chain by a remote one, only if the remote chain is valid and has more work.
+///
+/// Args:
+///
+/// `chain` - the local chain to update
+/// `remote_chain` - the chain received from a peer
+///
+/// Returns:
+///
+/// true if the local chain has been replaced
+pub fn adopt_chain(chain: &Arc<Mutex<Vec<Block>>>, remote_chain: Vec<Block>) -> bool {
+    if !validate_chain(&remote_chain) {
+        println!("The remote chain is invalid, rejected.");
+        return false;
+    }
+
+    let mut chain = chain.lock().unwrap();
+
+    if chain_work(&remote_chain) > chain_work(&chain) {
+        *chain = remote_chain;
+        println!("The local chain is outdated compared to the remote one, replaced.");
+        true
+    } else {
+        println!("The local chain is up-to-date compared to the remote one.");
+        false
+    }
+}
+
+/// Splices the blocks a peer has after the last block shared with the local chain, only if
+/// they are valid and have more work than the local blocks they replace. Blocks starting
+/// from a genesis are a whole remote chain, handled as by `adopt_chain`.
+///
+/// Args:
+///
+/// `chain` - the local chain to update
+/// `suffix` - the blocks received from a peer, following one of the local blocks
+///
+/// Returns:
+///
+/// true if the local chain has been updated
+pub fn adopt_suffix(chain: &Arc<Mutex<Vec<Block>>>, suffix: Vec<Block>) -> bool {
+    let previous = match suffix.first() {
+        Some(first) => first.get_previous().to_string(),
+        None => {
+            println!("The local chain is up-to-date compared to the remote one.");
+            return false;
+        }
+    };
+    if previous.is_empty() {
+        return adopt_chain(chain, suffix);
+    }
+
+    let mut chain = chain.lock().unwrap();
+
+    let common = match chain
+        .iter()
+        .position(|block| block.get_current() == previous)
+    {
+        Some(common) => common,
+        None => {
+            println!("The remote blocks do not follow the local chain, rejected.");
+            return false;
+        }
+    };
+
+    if !validate_suffix(&suffix, Some(&chain[common]), chain[common].get_algo()) {
+        println!("The remote blocks are invalid, rejected.");
+        return false;
+    }
+
+    if chain_work(&suffix) > chain_work(&chain[common + 1..]) {
+        chain.truncate(common + 1);
+        chain.extend(suffix);
+        println!("The local chain is outdated compared to the remote one, updated.");
+        true
+    } else {
+        println!("The local chain is up-to-date compared to the remote one.");
+        false
+    }
+}
+
+/// Handles a block built on the same previous block as the tip of the chain, e.g. mined by a peer at the same height.
+/// The tip is replaced by the candidate (one-block reorganization) if the candidate is valid and has more work.
+///
+/// Args:
+///
+/// `chain` - the local chain to update
+/// `candidate` - the block competing with the tip
+///
+/// Returns:
+///
+/// true if the tip has been replaced
+pub fn handle_competing_block(chain: &mut Vec<Block>, candidate: Block) -> bool {
+    /* the block both the tip and the candidate refer to */
+    let parent = chain.len().checked_sub(2).map(|i| &chain[i]);
+    let replace = match tip(chain) {
+        Some(tip) => {
+            candidate.get_previous() == tip.get_previous()
+                && candidate.get_current() != tip.get_current()
+                && validate_suffix(::std::slice::from_ref(&candidate), parent, tip.get_algo())
+                && chain_work(::std::slice::from_ref(&candidate)) > chain_work(::std::slice::from_ref(tip))
+        }
+        None => false,
+    };
+
+    if replace {
+        chain.pop();
+        chain.push(candidate);
+    }
+
+    replace
+}
+
+/// Builds a block from the pending transactions of the pool. Takes up to `max_txs` verified
+/// transactions, in order, and removes them from the pool; transactions that fail the
+/// verification are dropped as they will never become valid.
+///
+/// Args:
+///
+/// `mempool` - the pending transactions
+/// `previous` - the digest of the previous block (empty if genesis)
+/// `max_txs` - the maximum amount of transactions of the block
+///
+/// Returns:
+///
+/// new block containing the serialized transactions, or an error if they are larger than `MAX_BLOCK_DATA`
+pub fn assemble_block(
+    mempool: &mut Vec<transaction>,
+    previous: String,
+    max_txs: usize,
+) -> ::std::result::Result<Block, BlockError> {
+    let mut selected: Vec<transaction> = Vec::new();
+
+    while selected.len() < max_txs && !mempool.is_empty() {
+        let transac = mempool.remove(0);
+
+        if transac.verify() {
+            selected.push(transac);
+        }
+    }
+
+    let data = serialize(&selected).unwrap();
+    Block::new(&data, previous)
+}
+
+/// Copies the digest and hashing algorithm of the last block of the chain, so that the lock
+/// is released right away.
+///
+/// Args:
+///
+/// `chain` - the chain to read
+///
+/// Returns:
+///
+/// digest of the last block (empty if the chain is empty) and algorithm of the chain
+pub fn tip_digest(chain: &Arc<Mutex<Vec<Block>>>) -> (String, HashAlgo) {
+    match tip(&chain.lock().unwrap()) {
+        Some(block) => (block.get_current().to_string(), block.get_algo()),
+        None => (String::new(), HashAlgo::default()),
+    }
+}
+
+/// Creates a block with the given data at the tip of the chain. The block is built
+/// without holding the chain lock; if another block has been appended meanwhile,
+/// the block is built again on the new tip.
+///
+/// Args:
+///
+/// `chain` - the chain to update
+/// `data` - the data of the new block
+///
+/// Returns:
+///
+/// the appended block, or an error if the data is larger than `MAX_BLOCK_DATA`
+pub fn append_block(
+    chain: &Arc<Mutex<Vec<Block>>>,
+    data: &Vec<u8>,
+) -> ::std::result::Result<Block, BlockError> {
+    loop {
+        let (previous, algo) = tip_digest(chain);
+        let block = Block::new_with_algo(data, previous.clone(), algo)?;
+
+        let mut chain = chain.lock().unwrap();
+        let current_tip = tip(&chain).map(|block| block.get_current()).unwrap_or("");
+
+        if current_tip == previous {
+            chain.push(block.clone());
+            return Ok(block);
+        }
+    }
 }
 
 /// Adds one block to the chain from a received message. Takes the first block of the chain. Panics if an error occurs.
+/// Blocks whose data is larger than `MAX_BLOCK_DATA` are rejected.
 ///
 /// Args:
 ///
 /// `chain` - the chain to update
 /// `message` - the message from where extract the unique block
-pub fn add_block_from_message(chain: &Arc<Mutex<Vec<Block>>>, message: &Message) {
+///
+/// Returns:
+///
+/// true if the block has been added
+pub fn add_block_from_message(chain: &Arc<Mutex<Vec<Block>>>, message: &Message) -> bool {
     let block = message.get_blocks().first().unwrap();
 
+    if !block.has_valid_size() {
+        println!("Received block is too large, rejected.");
+        return false;
+    }
+
     let mut chain = chain.lock().unwrap();
+
+    /* a block at the same height as the tip is never appended after it */
+    if tip(&chain).map_or(false, |tip| tip.get_previous() == block.get_previous()) {
+        let replaced = handle_competing_block(&mut chain, (*block).clone());
+
+        if replaced {
+            println!("Received block has more work than the tip of the chain, replaced.");
+        } else {
+            println!("Received block competes with the tip of the chain, rejected.");
+        }
+
+        return replaced;
+    }
+
     chain.push((*block).clone());
 
     println!("Received block added into the chain.");
+    true
+}
+
+/// Sends the local chain to another node through the given stream: first the blocks count
+/// as a big-endian `u64`, then every block in its own frame, so the chain size is not bounded
+/// by a single read buffer.
+///
+/// Args:
+///
+/// `stream` - the stream where data must be written
+/// `chain` - the chain to use
+pub fn send_chain_to_stream(stream: TcpStream, chain: &Arc<Mutex<Vec<Block>>>) -> Result<()> {
+    println!("Chain requested.");
+
+    let blocks = chain.lock().unwrap().clone();
+    send_blocks(stream, &blocks)?;
+
+    println!("Chain sent.");
+
+    Ok(())
 }
 
-/// Sends the local chain to another node through the given stream.
+/// Sends the blocks following the last one shared with the remote chain, in the same format
+/// as `send_chain_to_stream`. The whole chain is sent if even the genesis differs.
 ///
 /// Args:
 ///
 /// `stream` - the stream where data must be written
 /// `chain` - the chain to use
-pub fn send_last_block_to_stream(mut stream: TcpStream, chain: &Arc<Mutex<Vec<Block>>>) {
-    println!("Last block requested.");
+/// `remote_hashes` - the hashes of the remote chain, from the genesis to the tip
+pub fn send_suffix_to_stream(
+    stream: TcpStream,
+    chain: &Arc<Mutex<Vec<Block>>>,
+    remote_hashes: &[String],
+) -> Result<()> {
+    println!("Blocks after the common height requested.");
+
+    let blocks = chain.lock().unwrap().clone();
+    let start = if shares_genesis(&blocks, remote_hashes) {
+        common_height(&blocks, remote_hashes) as usize + 1
+    } else {
+        0
+    };
+    send_blocks(stream, &blocks[start..])?;
+
+    println!("{} blocks sent.", blocks.len() - start);
+
+    Ok(())
+}
 
-    let mut message = Message::new(Vec::new(), MessageLabel::SendBlock);
+/// Writes the blocks count as a big-endian `u64`, then every block in its own frame.
+fn send_blocks(mut stream: TcpStream, blocks: &[Block]) -> Result<()> {
+    stream.write_all(&(blocks.len() as u64).to_be_bytes())?;
 
+    for block in blocks.iter() {
+        let bytes = serialize(block).unwrap();
+        write_frame(&mut stream, &bytes)?;
+    }
+
+    Ok(())
+}
+
+/// Exports the chain into a human-readable file, one JSON object per block and per line.
+///
+/// Args:
+///
+/// `chain` - the chain to export
+/// `path` - the path of the file to write
+pub fn export_jsonl(chain: &Arc<Mutex<Vec<Block>>>, path: &str) -> Result<()> {
     let chain = chain.lock().unwrap();
+    let mut file = BufWriter::new(File::create(path)?);
+
+    for block in chain.iter() {
+        serde_json::to_writer(&mut file, block)?;
+        file.write_all(b"\n")?;
+    }
+
+    file.flush()
+}
+
+/// Imports a chain previously written by `export_jsonl`. Empty lines are ignored.
+///
+/// Args:
+///
+/// `path` - the path of the file to read
+///
+/// Returns:
+///
+/// the blocks of the file, in order
+pub fn import_jsonl(path: &str) -> Result<Vec<Block>> {
+    let file = BufReader::new(File::open(path)?);
+    let mut blocks = Vec::new();
+
+    for line in file.lines() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        blocks.push(serde_json::from_str(&line)?);
+    }
 
-    if !chain.is_empty() {
-        message.set_blocks(chain.clone());
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use hash_content::HashContent;
+
+    /// Builds a chain of blocks without leading zeros, so its work is its length.
+    fn build_chain(length: u8) -> Vec<Block> {
+        let mut chain: Vec<Block> = Vec::new();
+        for i in 0..length {
+            let previous = match chain.last() {
+                Some(block) => block.get_current().to_string(),
+                None => String::new(),
+            };
+            let block = (0..)
+                .map(|salt: u32| {
+                    Block::new(&vec![i, salt as u8, (salt >> 8) as u8], previous.clone())
+                        .unwrap()
+                })
+                .find(|block| hash_leading_zeros(block.get_current()) == 0)
+                .unwrap();
+            chain.push(block);
+        }
+        chain
+    }
+
+    #[test]
+    fn competing_block_with_more_work_replaces_tip() {
+        let mut chain = build_chain(3);
+        let previous = chain[1].get_current().to_string();
+
+        let weaker = (0..)
+            .map(|salt: u8| Block::new(&vec![9, salt], previous.clone()).unwrap())
+            .find(|block| hash_leading_zeros(block.get_current()) == 0)
+            .unwrap();
+        assert!(!handle_competing_block(&mut chain, weaker));
+
+        let stronger = Block::mine(&vec![9], previous.clone(), 2).unwrap();
+        assert!(handle_competing_block(&mut chain, stronger.clone()));
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain[2], stronger);
+        assert!(validate_chain(&chain));
+
+        /* a block extending the tip does not compete with it */
+        let next = Block::mine(&vec![10], stronger.get_current().to_string(), 3).unwrap();
+        assert!(!handle_competing_block(&mut chain, next));
+        assert_eq!(chain.len(), 3);
     }
 
-    let bytes = serialize(&message).unwrap();
-    stream.write(&bytes).unwrap();
+    #[test]
+    fn difficulty_cannot_be_lowered() {
+        let genesis = Block::mine(&vec![0], String::new(), 1).unwrap();
+        let previous = genesis.get_current().to_string();
+
+        let harder = Block::mine(&vec![1], previous.clone(), 2).unwrap();
+        assert!(validate_chain(&[genesis.clone(), harder]));
+
+        /* a valid proof of work, but at a difficulty lower than the chain one */
+        let easier = Block::mine(&vec![2], previous.clone(), 0).unwrap();
+        assert!(easier.meets_own_difficulty());
+        assert!(!validate_chain(&[genesis.clone(), easier.clone()]));
+        assert!(!validate_suffix(&[easier], Some(&genesis), genesis.get_algo()));
+    }
+
+    #[test]
+    fn prune_keeps_genesis_and_latest_blocks() {
+        let mut chain = build_chain(100);
+        let genesis = chain[0].clone();
+        let latest = chain[91..].to_vec();
+
+        assert_eq!(prune(&mut chain, 10), 90);
+
+        assert_eq!(chain.len(), 10);
+        assert_eq!(chain[0], genesis);
+        assert_eq!(&chain[1..], &latest[..]);
+        assert!(!validate_chain(&chain));
+        assert!(validate_pruned_chain(&chain));
+
+        assert_eq!(prune(&mut chain, 10), 0);
+        assert_eq!(chain.len(), 10);
+    }
+
+    #[test]
+    fn tip_and_height() {
+        let empty: Vec<Block> = Vec::new();
+        assert!(tip(&empty).is_none());
+        assert_eq!(height(&empty), None);
+
+        let chain = build_chain(3);
+        assert_eq!(tip(&chain), Some(&chain[2]));
+        assert_eq!(height(&chain), Some(2));
+        assert_eq!(height(&chain[..1]), Some(0));
+    }
+
+    #[test]
+    fn fork_keeps_prefix_up_to_height() {
+        let chain = build_chain(5);
+
+        let mut fork = fork_from(&chain, 2);
+        assert_eq!(fork.len(), 3);
+        assert_eq!(fork[2].get_current(), chain[2].get_current());
+        assert!(validate_chain(&fork));
+
+        let next = Block::new(&vec![42], fork[2].get_current().to_string()).unwrap();
+        fork.push(next);
+        assert!(validate_chain(&fork));
+        assert_eq!(chain.len(), 5);
 
-    println!("Last block sent.");
+        assert_eq!(fork_from(&chain, 10), chain);
+        assert!(fork_from(&[], 0).is_empty());
+    }
+
+    #[test]
+    fn diverging_chains_report_common_height() {
+        let local = build_chain(7);
+
+        let mut remote = fork_from(&local, 4);
+        while remote.len() < 8 {
+            let previous = remote.last().unwrap().get_current().to_string();
+            let salt = remote.len() as u8;
+            remote.push(Block::new(&vec![42, salt], previous).unwrap());
+        }
+        let remote_hashes = block_hashes(&remote);
+
+        assert_ne!(remote[5].get_current(), local[5].get_current());
+        assert_eq!(common_height(&local, &remote_hashes), 4);
+        assert!(shares_genesis(&local, &remote_hashes));
+
+        assert_eq!(common_height(&local, &block_hashes(&local)), 6);
+        assert_eq!(common_height(&local[..3], &remote_hashes), 2);
+        assert_eq!(common_height(&local, &[]), 0);
+        assert!(!shares_genesis(&local, &[]));
+    }
+
+    #[test]
+    fn leading_zeros_of_hash() {
+        assert_eq!(hash_leading_zeros("f0"), 0);
+        assert_eq!(hash_leading_zeros("1f"), 3);
+        assert_eq!(hash_leading_zeros("00a"), 8);
+        assert_eq!(hash_leading_zeros("000"), 12);
+    }
+
+    /// Builds a block following `previous` with the given timestamp.
+    fn block_at(previous: &Block, timestamp: i64) -> Block {
+        Block::from_content(
+            HashContent::with_timestamp(vec![1], timestamp),
+            previous.get_current().to_string(),
+            previous.get_algo(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn stats_of_small_chain() {
+        let genesis = Block::from_content(
+            HashContent::with_timestamp(vec![0, 0, 0], 1_500_000_000),
+            String::new(),
+            HashAlgo::Sha256,
+        )
+        .unwrap();
+        let second = block_at(&genesis, 1_500_000_010);
+        let third = block_at(&second, 1_500_000_040);
+
+        let summary = stats(&[genesis.clone(), second, third]);
+        assert_eq!(summary.count, 3);
+        assert_eq!(summary.data_bytes, 5);
+        assert_eq!(summary.earliest_timestamp, Some(1_500_000_000));
+        assert_eq!(summary.latest_timestamp, Some(1_500_000_040));
+        assert_eq!(summary.average_interval, Some(20.0));
+
+        let single = stats(&[genesis]);
+        assert_eq!(single.count, 1);
+        assert_eq!(single.latest_timestamp, Some(1_500_000_000));
+        assert_eq!(single.average_interval, None);
+
+        let empty = stats(&[]);
+        assert_eq!(empty.count, 0);
+        assert_eq!(empty.earliest_timestamp, None);
+        assert_eq!(empty.average_interval, None);
+    }
+
+    #[test]
+    fn block_older_than_its_parent_is_invalid() {
+        let genesis = Block::from_content(
+            HashContent::with_timestamp(vec![0], 1_500_000_000),
+            String::new(),
+            HashAlgo::Sha256,
+        )
+        .unwrap();
+
+        assert!(validate_chain(&[genesis.clone(), block_at(&genesis, 1_500_000_000)]));
+        assert!(!validate_chain(&[genesis.clone(), block_at(&genesis, 1_499_999_999)]));
+    }
+
+    #[test]
+    fn suffix_timestamps_are_checked() {
+        let genesis = Block::new(&vec![0], String::new()).unwrap();
+        let now = genesis.get_content().get_timestamp();
+        let tip = block_at(&genesis, now);
+        let algo = genesis.get_algo();
+
+        let older = block_at(&tip, now - 1);
+        assert!(validate_suffix(&[older.clone()], None, algo));
+        assert!(!validate_suffix(&[older], Some(&tip), algo));
+
+        let far = block_at(&tip, now + 2 * MAX_TIMESTAMP_DRIFT);
+        assert!(!validate_suffix(&[far], Some(&tip), algo));
+        let near = block_at(&tip, now + 60);
+        assert!(validate_suffix(&[near], Some(&tip), algo));
+    }
+
+    #[test]
+    fn suffix_must_follow_the_local_chain() {
+        let chain = Arc::new(Mutex::new(Vec::new()));
+        append_block(&chain, &vec![0]).unwrap();
+        let local = chain.lock().unwrap().clone();
+
+        let other = Block::new(&vec![1], String::new()).unwrap();
+        let detached = Block::new(&vec![2], other.get_current().to_string()).unwrap();
+        assert!(!adopt_suffix(&chain, vec![detached]));
+
+        let invalid = Block::new_with_algo(
+            &vec![3],
+            local[0].get_current().to_string(),
+            HashAlgo::Sha512,
+        )
+        .unwrap();
+        assert!(!adopt_suffix(&chain, vec![invalid]));
+        assert_eq!(*chain.lock().unwrap(), local);
+
+        let next = Block::new(&vec![4], local[0].get_current().to_string()).unwrap();
+        assert!(adopt_suffix(&chain, vec![next.clone()]));
+        assert_eq!(*chain.lock().unwrap(), vec![local[0].clone(), next]);
+    }
+
+    #[test]
+    fn block_slightly_in_the_future_is_tolerated() {
+        let genesis = Block::new(&vec![0], String::new()).unwrap();
+        let now = genesis.get_content().get_timestamp();
+
+        let chain = [genesis.clone(), block_at(&genesis, now + 60)];
+        assert!(validate_chain(&chain));
+        assert!(!validate_chain_with_drift(&chain, 0));
+
+        let far = [genesis.clone(), block_at(&genesis, now + 2 * MAX_TIMESTAMP_DRIFT)];
+        assert!(!validate_chain(&far));
+    }
+
+    #[test]
+    fn chain_mixing_hash_algorithms_is_invalid() {
+        let genesis = Block::new(&vec![0], String::new()).unwrap();
+        let next = Block::new_with_algo(
+            &vec![1],
+            genesis.get_current().to_string(),
+            HashAlgo::Sha512,
+        )
+        .unwrap();
+
+        assert!(validate_chain(&[genesis.clone()]));
+        assert!(!validate_chain(&[genesis, next]));
+    }
+
+    #[test]
+    fn heavier_shorter_chain_is_adopted() {
+        let chain = Arc::new(Mutex::new(build_chain(5)));
+
+        let genesis = Block::mine(&vec![0], String::new(), 3).unwrap();
+        let tip = Block::mine(&vec![1], genesis.get_current().to_string(), 3).unwrap();
+        let remote = vec![genesis, tip];
+
+        assert!(chain_work(&remote) > chain_work(&chain.lock().unwrap()));
+        assert!(adopt_chain(&chain, remote));
+        assert_eq!(chain.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn invalid_remote_chain_is_rejected() {
+        let local = build_chain(2);
+        let chain = Arc::new(Mutex::new(local.clone()));
+
+        let mut remote = build_chain(3);
+        remote.push(Block::new(&vec![3], "not the previous digest".to_string()).unwrap());
+        assert!(!validate_chain(&remote));
+
+        assert!(!adopt_chain(&chain, remote));
+
+        let chain = chain.lock().unwrap();
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[1].get_current(), local[1].get_current());
+    }
+
+    #[test]
+    fn longer_valid_remote_chain_is_adopted() {
+        let chain = Arc::new(Mutex::new(build_chain(2)));
+
+        assert!(adopt_chain(&chain, build_chain(3)));
+        assert_eq!(chain.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn assemble_block_takes_at_most_max_txs() {
+        let mut mempool: Vec<transaction> = (0..5)
+            .map(|i| {
+                let mut transac = transaction::default();
+                transac.value = vec![i];
+                transac.sign("this is a passphrase");
+                transac
+            })
+            .collect();
+
+        let block = assemble_block(&mut mempool, String::new(), 3).unwrap();
+        let sealed: Vec<transaction> = deserialize(&block.get_content().get_data()).unwrap();
+
+        assert_eq!(sealed.len(), 3);
+        assert_eq!(sealed[0].value, vec![0]);
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(mempool[0].value, vec![3]);
+    }
+
+    #[test]
+    fn tx_iter_yields_transactions_in_chain_order() {
+        let mut mempool: Vec<transaction> = (0..3)
+            .map(|i| {
+                let mut transac = transaction::default();
+                transac.value = vec![i];
+                transac.sign("this is a passphrase");
+                transac
+            })
+            .collect();
+        let expected = mempool.clone();
+
+        let genesis = Block::new(&vec![1, 2, 3], String::new()).unwrap();
+        let first = assemble_block(&mut mempool, genesis.get_current().to_string(), 2).unwrap();
+        let second = assemble_block(&mut mempool, first.get_current().to_string(), 2).unwrap();
+        let chain = vec![genesis, first, second];
+
+        let view = Chain::new(&chain);
+        assert_eq!(view.tx_iter().collect::<Vec<_>>(), expected);
+        assert_eq!(view.blocks_iter().count(), 3);
+        assert_eq!(
+            view.tx_iter().filter(|transac| transac.value[0] > 0).count(),
+            2
+        );
+    }
+
+    #[test]
+    fn broadcast_reaches_each_peer_once() {
+        use message::read_frame;
+        use std::net::TcpListener;
+
+        let listeners: Vec<TcpListener> = (0..3)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let addresses: Vec<String> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap().to_string())
+            .collect();
+        let peers = vec![
+            addresses[0].clone(),
+            addresses[1].clone(),
+            addresses[0].clone(),
+            addresses[2].clone(),
+            addresses[1].clone(),
+        ];
+
+        let block = Block::new(&vec![1, 2, 3], String::new()).unwrap();
+        assert!(broadcast_block(&peers, block.clone()).is_empty());
+
+        /* every connection has been made when broadcast_block returns */
+        for listener in listeners.iter() {
+            listener.set_nonblocking(true).unwrap();
+
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.set_nonblocking(false).unwrap();
+            let message = Message::from_bytes(&read_frame(&mut stream).unwrap()).unwrap();
+            assert_eq!(message.get_blocks()[0].get_current(), block.get_current());
+
+            assert!(listener.accept().is_err());
+        }
+    }
+
+    #[test]
+    fn jsonl_round_trip() {
+        let blocks = build_chain(3);
+        let chain = Arc::new(Mutex::new(blocks.clone()));
+
+        let path = ::std::env::temp_dir().join("rust-blockchain-jsonl-round-trip.jsonl");
+        let path = path.to_str().unwrap();
+
+        export_jsonl(&chain, path).unwrap();
+        let imported = import_jsonl(path).unwrap();
+        ::std::fs::remove_file(path).unwrap();
+
+        assert_eq!(imported, blocks);
+    }
 }
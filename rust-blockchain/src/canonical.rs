@@ -0,0 +1,71 @@
+//! Canonical encoding of the signed and hashed payloads.
+//!
+//! Fields are written one after the other in a fixed order chosen by the caller. Integers
+//! are fixed-width big-endian, byte strings and strings are prefixed by their length as a
+//! big-endian `u32`. Unlike bincode, this layout does not depend on any library default,
+//! so signatures and digests stay valid across versions.
+
+/// Appends an unsigned integer.
+///
+/// Args:
+///
+/// `buffer` - the payload being encoded
+/// `value` - the integer to append, as 8 big-endian bytes
+pub fn put_u64(buffer: &mut Vec<u8>, value: u64) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Appends a signed integer.
+///
+/// Args:
+///
+/// `buffer` - the payload being encoded
+/// `value` - the integer to append, as 8 big-endian bytes (two's complement)
+pub fn put_i64(buffer: &mut Vec<u8>, value: i64) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Appends a byte string, prefixed by its length.
+///
+/// Args:
+///
+/// `buffer` - the payload being encoded
+/// `bytes` - the bytes to append
+pub fn put_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    buffer.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(bytes);
+}
+
+/// Appends a string as its UTF-8 bytes, prefixed by their length.
+///
+/// Args:
+///
+/// `buffer` - the payload being encoded
+/// `string` - the string to append
+pub fn put_str(buffer: &mut Vec<u8>, string: &str) {
+    put_bytes(buffer, string.as_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn integers_are_big_endian_and_strings_length_prefixed() {
+        let mut buffer = Vec::new();
+        put_u64(&mut buffer, 0x0102);
+        put_i64(&mut buffer, -2);
+        put_str(&mut buffer, "ab");
+        put_bytes(&mut buffer, &[]);
+
+        assert_eq!(
+            buffer,
+            vec![
+                0, 0, 0, 0, 0, 0, 1, 2, //
+                0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, //
+                0, 0, 0, 2, b'a', b'b', //
+                0, 0, 0, 0,
+            ]
+        );
+    }
+}
@@ -2,6 +2,11 @@
 
 use std::io::stdin;
 
+use bincode::deserialize;
+
+use block::Block;
+use transaction::transaction;
+
 /// Clear the whole terminal content and generate the default content (bars and titles). Refactored as used multiple times.
 pub fn clear_screen() {
     println!("");
@@ -34,3 +39,61 @@ pub fn set_cursor_into_logs() {
 pub fn set_cursor_into_input() {
     //println!("{}", Goto(0, get_terminal_height() - 2));
 }
+
+/// Renders one block as labeled lines, without trailing line break.
+/// The transactions count is only displayed if the block data holds transactions.
+///
+/// Args:
+///
+/// `height` - the position of the block into the chain (0 for genesis)
+/// `block` - the block to render
+///
+/// Returns:
+///
+/// multi-line representation of the block
+pub fn format_block(height: usize, block: &Block) -> String {
+    let content = block.get_content();
+    let data = content.get_data();
+
+    let mut lines = vec![
+        format!("Height: {}", height),
+        format!("Timestamp: {}", content.get_timestamp()),
+        format!("Previous Hash: {}", block.get_previous()),
+        format!("Hash: {}", block.get_current()),
+        format!("Nonce: {}", block.get_nonce()),
+        format!("Difficulty: {}", block.get_difficulty()),
+    ];
+
+    if let Ok(transactions) = deserialize::<Vec<transaction>>(&data) {
+        lines.push(format!("Transactions: {}", transactions.len()));
+    }
+
+    lines.push(format!("Data: {:?}", data));
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bincode::serialize;
+
+    #[test]
+    fn format_block_shows_height_and_hashes() {
+        let genesis = Block::new(&vec![1, 2, 3], String::new()).unwrap();
+        let transactions = vec![transaction::default(), transaction::default()];
+        let block = Block::new(
+            &serialize(&transactions).unwrap(),
+            genesis.get_current().to_string(),
+        )
+        .unwrap();
+
+        let formatted = format_block(1, &block);
+
+        assert!(formatted.contains("Height: 1"));
+        assert!(formatted.contains(&format!("Previous Hash: {}", genesis.get_current())));
+        assert!(formatted.contains(&format!("Hash: {}", block.get_current())));
+        assert!(formatted.contains("Transactions: 2"));
+    }
+}
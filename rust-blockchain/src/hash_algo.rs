@@ -0,0 +1,43 @@
+//! Hashing algorithms of the chain blocks.
+
+use hex;
+use sha2::{Digest, Sha256, Sha512};
+
+/// Algorithm used to compute the digest of the blocks. Every block of a chain uses the
+/// algorithm of the genesis block.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum HashAlgo {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgo {
+    /// Hashes the given bytes.
+    ///
+    /// Args:
+    ///
+    /// `bytes` - the bytes to hash
+    ///
+    /// Returns:
+    ///
+    /// hexadecimal digest
+    pub fn hexdigest(&self, bytes: &[u8]) -> String {
+        match *self {
+            HashAlgo::Sha256 => hex::encode(Sha256::digest(bytes)),
+            HashAlgo::Sha512 => hex::encode(Sha512::digest(bytes)),
+        }
+    }
+
+    /// Length of the hexadecimal digests, which is also the highest reachable difficulty.
+    ///
+    /// Returns:
+    ///
+    /// amount of hexadecimal digits of a digest
+    pub fn digest_len(&self) -> usize {
+        match *self {
+            HashAlgo::Sha256 => 64,
+            HashAlgo::Sha512 => 128,
+        }
+    }
+}
@@ -2,7 +2,9 @@
 
 use time;
 
-#[derive(Serialize, Deserialize, Clone)]
+use canonical::{put_bytes, put_i64};
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct HashContent {
     timestamp: i64,
     data: Vec<u8>,
@@ -19,8 +21,22 @@ impl HashContent {
     ///
     /// hash content with current timestamp and given data
     pub fn new(data: Vec<u8>) -> HashContent {
+        HashContent::with_timestamp(data, time::now_utc().to_timespec().sec)
+    }
+
+    /// Creates a hash content with a known timestamp.
+    ///
+    /// Args:
+    ///
+    /// `data` - the data to store into the block hash content
+    /// `timestamp` - the block creation timestamp, in seconds
+    ///
+    /// Returns:
+    ///
+    /// hash content with given timestamp and data
+    pub fn with_timestamp(data: Vec<u8>, timestamp: i64) -> HashContent {
         HashContent {
-            timestamp: time::now_utc().to_timespec().sec,
+            timestamp: timestamp,
             data: data,
         }
     }
@@ -42,4 +58,26 @@ impl HashContent {
     pub fn get_data(&self) -> Vec<u8> {
         self.data.clone()
     }
+
+    /// Encodes the content for hashing: the timestamp, then the data prefixed by its length.
+    ///
+    /// Returns:
+    ///
+    /// the canonical encoding of the content
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        put_i64(&mut buffer, self.timestamp);
+        put_bytes(&mut buffer, &self.data);
+
+        buffer
+    }
+
+    /// Getter of the data size, without copying the data.
+    ///
+    /// Returns:
+    ///
+    /// block data size, in bytes
+    pub fn get_data_len(&self) -> usize {
+        self.data.len()
+    }
 }
@@ -1,12 +1,178 @@
 //! Displays help.
 
+/// Runs one command with its arguments, which are at least as many as its arity.
+/// Returns false to stop the main loop.
+pub type Handler<C> = fn(&mut C, &[&str]) -> bool;
+
+/// A command of the node main loop, as listed by `list_commands`. `C` is the state
+/// its handler works on.
+pub struct Command<C: 'static> {
+    pub name: &'static str,
+    /* minimum amount of arguments, the last one may contain spaces */
+    pub arity: usize,
+    pub params: &'static str,
+    pub description: &'static str,
+    pub example: Option<&'static str>,
+    pub handler: Handler<C>,
+}
+
+/// Help entry a script registered for one of its own commands, listed after the
+/// commands of the main loop.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptCommand {
+    pub name: String,
+    pub params: String,
+    pub description: String,
+}
+
+/// Looks a command up by name.
+///
+/// Args:
+///
+/// `commands` - the commands of the main loop
+/// `name` - the name of the command
+///
+/// Returns:
+///
+/// the command, or `None` if there is no such command
+pub fn find_command<'a, C>(commands: &'a [Command<C>], name: &str) -> Option<&'a Command<C>> {
+    commands.iter().find(|command| command.name == name)
+}
+
+/// Adds the help of a script command, or replaces it if the script already registered one
+/// with the same name.
+///
+/// Args:
+///
+/// `commands` - the commands of the main loop, which cannot be replaced
+/// `script_commands` - the help entries registered by scripts
+/// `command` - the new help entry
+///
+/// Returns:
+///
+/// false if a command of the main loop already has this name
+pub fn register_script_command<C>(
+    commands: &[Command<C>],
+    script_commands: &mut Vec<ScriptCommand>,
+    command: ScriptCommand,
+) -> bool {
+    if find_command(commands, &command.name).is_some() {
+        return false;
+    }
+
+    script_commands.retain(|other| other.name != command.name);
+    script_commands.push(command);
+    true
+}
+
+fn format_command(name: &str, params: &str, description: &str, example: Option<&str>) -> String {
+    let mut text = if params.is_empty() {
+        format!("{} - {}\n", name, description)
+    } else {
+        format!("{} {} - {}\n", name, params, description)
+    };
+
+    if let Some(example) = example {
+        text += &format!("Example: {}\n", example);
+    }
+
+    text + "\n"
+}
+
+/// Formats the help of the main loop commands, then of the script ones.
+pub fn format_commands<C>(commands: &[Command<C>], script_commands: &[ScriptCommand]) -> String {
+    let mut text = String::new();
+
+    for command in commands {
+        text += &format_command(
+            command.name,
+            command.params,
+            command.description,
+            command.example,
+        );
+    }
+    for command in script_commands {
+        text += &format_command(&command.name, &command.params, &command.description, None);
+    }
+
+    text
+}
+
 /// List available commands.
-pub fn list_commands() {
-    println!("add_block [data] - append a block into the local blockchain");
-    println!("Example: add_block 10 \n");
-    println!("list_blocks - list the local chain blocks\n");
-    println!("add_peer - add one node as a peer");
-    println!("Example: add_peer 172.17.0.10\n");
-    println!("list_peers - list the peers\n");
-    println!("exit - quit the program");
+pub fn list_commands<C>(commands: &[Command<C>], script_commands: &[ScriptCommand]) {
+    print!("{}", format_commands(commands, script_commands));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stop(_: &mut (), _: &[&str]) -> bool {
+        false
+    }
+
+    const COMMANDS: &[Command<()>] = &[
+        Command {
+            name: "add_peer",
+            arity: 1,
+            params: "[address]",
+            description: "add one node as a peer",
+            example: Some("add_peer 172.17.0.10"),
+            handler: stop,
+        },
+        Command {
+            name: "exit",
+            arity: 0,
+            params: "",
+            description: "quit the program",
+            example: None,
+            handler: stop,
+        },
+    ];
+
+    fn script_command(name: &str, description: &str) -> ScriptCommand {
+        ScriptCommand {
+            name: name.to_string(),
+            params: "[name]".to_string(),
+            description: description.to_string(),
+        }
+    }
+
+    #[test]
+    fn commands_are_found_by_name() {
+        assert_eq!(find_command(COMMANDS, "add_peer").unwrap().arity, 1);
+        let exit = find_command(COMMANDS, "exit").unwrap();
+        assert!(!(exit.handler)(&mut (), &[]));
+        assert!(find_command(COMMANDS, "unknown").is_none());
+    }
+
+    #[test]
+    fn scripts_extend_the_help() {
+        let mut script_commands = Vec::new();
+
+        assert!(!register_script_command(
+            COMMANDS,
+            &mut script_commands,
+            script_command("exit", "quit")
+        ));
+        assert!(register_script_command(
+            COMMANDS,
+            &mut script_commands,
+            script_command("greet", "greet someone")
+        ));
+        assert!(register_script_command(
+            COMMANDS,
+            &mut script_commands,
+            script_command("greet", "say hello")
+        ));
+        assert_eq!(script_commands, vec![script_command("greet", "say hello")]);
+
+        assert_eq!(
+            format_commands(COMMANDS, &script_commands),
+            "add_peer [address] - add one node as a peer\n\
+             Example: add_peer 172.17.0.10\n\n\
+             exit - quit the program\n\n\
+             greet [name] - say hello\n\n"
+        );
+    }
 }
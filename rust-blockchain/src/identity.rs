@@ -1,13 +1,31 @@
 //copy from ark ecosystem
+use std::sync::atomic::{AtomicU8, Ordering};
+
 use bs58;
 use hex;
+use rand::{OsRng, Rng};
 use ripemd160::{Digest, Ripemd160};
-use secp256k1::{All, Error, Message, PublicKey, Secp256k1, SecretKey};
+use secp256k1::{All, Error, Message, PublicKey, Secp256k1, SecretKey, Signature};
 use sha2::Sha256;
 
 lazy_static! {
     pub static ref SECP256K1: Secp256k1<All> = Secp256k1::new();
 }
+/// Version byte prepended to addresses when none is given.
+pub const DEFAULT_NETWORK_VERSION: u8 = 0;
+
+static NETWORK_VERSION: AtomicU8 = AtomicU8::new(DEFAULT_NETWORK_VERSION);
+
+/// Network version used by the address functions when they are given `None`.
+pub fn network_version() -> u8 {
+    NETWORK_VERSION.load(Ordering::Relaxed)
+}
+
+/// Changes the network version used by the address functions when they are given `None`.
+pub fn set_network_version(network_version: u8) {
+    NETWORK_VERSION.store(network_version, Ordering::Relaxed);
+}
+
 //private key
 pub type PrivateKey = SecretKey;
 
@@ -15,13 +33,109 @@ pub fn privatekey_from_passphrase(passphrase: &str) -> PrivateKey {
     PrivateKey::from_slice(&Sha256::digest(passphrase.as_bytes())[..]).unwrap()
 }
 
+/// Creates a fresh identity from the operating system random number generator.
+pub fn generate_keypair() -> (PrivateKey, PublicKey) {
+    let mut rng = OsRng::new().expect("no secure random number generator");
+    SECP256K1.generate_keypair(&mut rng)
+}
+
+/// Creates a random passphrase (32 random bytes as hex) for `privatekey_from_passphrase`,
+/// so that a generated identity can be written down and restored.
+pub fn generate_passphrase() -> String {
+    let mut rng = OsRng::new().expect("no secure random number generator");
+    let mut bytes = [0u8; 32];
+    rng.fill_bytes(&mut bytes);
+    hex::encode(&bytes[..])
+}
+
+/// Encodes the 32 bytes of the private key as lowercase hex.
+pub fn privatekey_to_hex(private_key: &PrivateKey) -> String {
+    hex::encode(&private_key[..])
+}
+
+/// Decodes a private key written by `privatekey_to_hex`.
+pub fn privatekey_from_hex(private_key: &str) -> Result<PrivateKey, Error> {
+    let bytes = hex::decode(private_key).map_err(|_| Error::InvalidSecretKey)?;
+    PrivateKey::from_slice(&bytes)
+}
+
 pub fn privatekey_to_signature(bytes: &[u8], passphrase: &str) -> String {
+    hex::encode(sign_low_s(bytes, passphrase).serialize_der())
+}
+/// Signs `bytes` like `privatekey_to_signature`, but encodes the signature in its compact
+/// form: R then S, 32 bytes each, as lowercase hex.
+pub fn sign_message_compact(bytes: &[u8], passphrase: &str) -> String {
+    hex::encode(&sign_low_s(bytes, passphrase).serialize_compact()[..])
+}
+fn sign_low_s(bytes: &[u8], passphrase: &str) -> Signature {
     let key = privatekey_from_passphrase(passphrase);
     let hash = &Sha256::digest(&bytes);
     let msg = Message::from_slice(&hash).unwrap();
-    let sig = SECP256K1.sign(&msg, &key);
+    let mut sig = SECP256K1.sign(&msg, &key);
+    sig.normalize_s();
+    sig
+}
+/// Tells whether the signature uses the lower of its two possible S values,
+/// the only form accepted by `signature_verify`.
+pub fn signature_is_low_s(signature: &Signature) -> bool {
+    let mut normalized = *signature;
+    normalized.normalize_s();
+    normalized == *signature
+}
+/// Checks a hex signature of `bytes` against a hex public key. The encoding is told by the
+/// length: exactly 64 bytes is a compact signature, anything else must be DER, so a 64 bytes
+/// DER signature is never accepted (`privatekey_to_signature` practically never makes one).
+/// Malformed keys or signatures (e.g. received from a peer) are reported as invalid,
+/// and so are high-S signatures, so that a transaction has a single valid signature.
+pub fn signature_verify(bytes: &[u8], signature: &str, public_key: &str) -> bool {
+    let hash = Sha256::digest(&bytes);
+    let msg = match Message::from_slice(&hash) {
+        Ok(msg) => msg,
+        Err(_) => return false,
+    };
 
-    hex::encode(sig.serialize_der())
+    let sig = match decode_signature(signature) {
+        Some(sig) => sig,
+        None => return false,
+    };
+    if !signature_is_low_s(&sig) {
+        return false;
+    }
+    let pk = match hex::decode(public_key).map(|bytes| PublicKey::from_slice(&bytes)) {
+        Ok(Ok(pk)) => pk,
+        _ => return false,
+    };
+
+    SECP256K1.verify(&msg, &sig, &pk).is_ok()
+}
+/// Re-encodes a hex signature accepted by `signature_verify` as DER, the form transactions
+/// are stored and relayed in. The compact and DER encodings of one signature then give the
+/// same transaction.
+///
+/// Args:
+///
+/// `signature` - the hex signature, compact or DER
+///
+/// Returns:
+///
+/// the hex DER signature, or `None` if the signature is malformed
+pub fn signature_to_der(signature: &str) -> Option<String> {
+    decode_signature(signature).map(|sig| hex::encode(sig.serialize_der()))
+}
+/* exactly 64 bytes is a compact signature, anything else must be DER */
+fn decode_signature(signature: &str) -> Option<Signature> {
+    let raw = hex::decode(signature).ok()?;
+
+    if raw.len() == 64 {
+        Signature::from_compact(&raw).ok()
+    } else {
+        Signature::from_der(&raw).ok()
+    }
+}
+/// Checks a hex compact signature of `bytes`, as made by `sign_message_compact`.
+/// DER signatures are refused.
+pub fn verify_message_compact(bytes: &[u8], signature: &str, public_key: &str) -> bool {
+    signature.len() == 128 && signature_verify(bytes, signature, public_key)
 }
 //public key
 pub fn publickkey_from_passphrase(passphrase: &str) -> PublicKey {
@@ -29,8 +143,15 @@ pub fn publickkey_from_passphrase(passphrase: &str) -> PublicKey {
     PublicKey::from_secret_key(&SECP256K1, &private_key)
 }
 
-pub fn publickey_from_hex(public_key: &str) -> PublicKey {
-    PublicKey::from_slice(hex::decode(public_key).unwrap().as_slice()).unwrap()
+/// Encodes the public key in its compressed form (33 bytes) as lowercase hex.
+pub fn publickey_to_hex(public_key: &PublicKey) -> String {
+    hex::encode(&public_key.serialize()[..])
+}
+
+/// Decodes a public key written by `publickey_to_hex`.
+pub fn publickey_from_hex(public_key: &str) -> Result<PublicKey, Error> {
+    let bytes = hex::decode(public_key).map_err(|_| Error::InvalidPublicKey)?;
+    PublicKey::from_slice(&bytes)
 }
 
 pub fn publickey_from_private_key(private_key: &PrivateKey) -> PublicKey {
@@ -39,12 +160,9 @@ pub fn publickey_from_private_key(private_key: &PrivateKey) -> PublicKey {
 
 //address
 pub fn address_from_public_key(public_key: &PublicKey, network_version: Option<u8>) -> String {
-    let network_version = match network_version {
-        Some(network_version) => network_version,
-        None => 0,
-    };
+    let network_version = network_version.unwrap_or_else(self::network_version);
 
-    let bytes = hex::decode(public_key.to_string()).unwrap();
+    let bytes = public_key.serialize();
 
     let ripemd160 = Ripemd160::digest(&bytes);
     let mut data = vec![];
@@ -61,10 +179,7 @@ pub fn address_from_private_key(private_key: &PrivateKey, network_version: Optio
     address_from_public_key(&public_key, network_version)
 }
 pub fn address_validate(address: &str, network_version: Option<u8>) -> bool {
-    let network_version = match network_version {
-        Some(network_version) => network_version,
-        None => 0,
-    };
+    let network_version = network_version.unwrap_or_else(self::network_version);
 
     let bytes = bs58::decode(address).into_vec().unwrap();
 
@@ -73,6 +188,32 @@ pub fn address_validate(address: &str, network_version: Option<u8>) -> bool {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::sync::{Mutex, MutexGuard};
+
+    /* tests depending on the network version hold this lock */
+    static NETWORK_VERSION_LOCK: Mutex<()> = Mutex::new(());
+
+    /* sets the network version until dropped, then restores the default one,
+    even if the test panics */
+    struct NetworkVersionGuard {
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl NetworkVersionGuard {
+        fn set(network_version: u8) -> NetworkVersionGuard {
+            let lock = NETWORK_VERSION_LOCK
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            set_network_version(network_version);
+            NetworkVersionGuard { _lock: lock }
+        }
+    }
+
+    impl Drop for NetworkVersionGuard {
+        fn drop(&mut self) {
+            set_network_version(DEFAULT_NETWORK_VERSION);
+        }
+    }
 
     #[test]
     fn private_key_from_passphrase() {
@@ -91,6 +232,115 @@ mod test {
         );
     }
     #[test]
+    fn generated_keypairs_are_random() {
+        let (private_key, public_key) = generate_keypair();
+        let (other_private_key, other_public_key) = generate_keypair();
+
+        assert_ne!(private_key, other_private_key);
+        assert_ne!(public_key, other_public_key);
+        assert_eq!(publickey_from_private_key(&private_key), public_key);
+
+        let passphrase = generate_passphrase();
+        assert_eq!(passphrase.len(), 64);
+        assert_ne!(passphrase, generate_passphrase());
+    }
+    #[test]
+    fn der_and_compact_signatures() {
+        let passphrase = "this is a top secret passphrase";
+        let public_key = publickey_to_hex(&publickkey_from_passphrase(passphrase));
+
+        let der = privatekey_to_signature(b"data", passphrase);
+        let compact = sign_message_compact(b"data", passphrase);
+        assert_eq!(compact.len(), 128);
+        assert_ne!(der.len(), 128);
+
+        /* both encodings of the same signature */
+        let sig = Signature::from_der(&hex::decode(&der).unwrap()).unwrap();
+        assert_eq!(hex::encode(&sig.serialize_compact()[..]), compact);
+
+        assert!(signature_verify(b"data", &der, &public_key));
+        assert!(signature_verify(b"data", &compact, &public_key));
+        assert!(verify_message_compact(b"data", &compact, &public_key));
+        assert!(!verify_message_compact(b"data", &der, &public_key));
+        assert!(!signature_verify(b"other data", &compact, &public_key));
+
+        /* one byte more or less is neither a compact nor a DER signature */
+        assert!(!signature_verify(b"data", &compact[..126], &public_key));
+        let longer = format!("{}00", compact);
+        assert!(!signature_verify(b"data", &longer, &public_key));
+    }
+    #[test]
+    fn private_key_hex_round_trip() {
+        let private_key = privatekey_from_passphrase("this is a top secret passphrase");
+        let encoded = privatekey_to_hex(&private_key);
+
+        assert_eq!(privatekey_from_hex(&encoded), Ok(private_key));
+        assert!(privatekey_from_hex("not hex").is_err());
+    }
+    #[test]
+    fn public_key_hex_round_trip() {
+        let public_key = publickkey_from_passphrase("this is a top secret passphrase");
+        let encoded = publickey_to_hex(&public_key);
+
+        assert_eq!(encoded, public_key.to_string());
+        assert_eq!(publickey_from_hex(&encoded), Ok(public_key));
+        assert!(publickey_from_hex("not hex").is_err());
+        assert!(publickey_from_hex("00").is_err());
+    }
+    #[test]
+    fn signatures_are_low_s() {
+        /* order of the secp256k1 curve */
+        const ORDER: [u8; 32] = [
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xfe, 0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c,
+            0xd0, 0x36, 0x41, 0x41,
+        ];
+
+        let passphrase = "this is a top secret passphrase";
+        let public_key = publickey_to_hex(&publickkey_from_passphrase(passphrase));
+        let signature = privatekey_to_signature(b"data", passphrase);
+
+        let sig = Signature::from_der(&hex::decode(&signature).unwrap()).unwrap();
+        assert!(signature_is_low_s(&sig));
+        assert!(signature_verify(b"data", &signature, &public_key));
+
+        /* same signature with S replaced by ORDER - S */
+        let mut compact = sig.serialize_compact();
+        let mut borrow = 0i16;
+        for i in (0..32).rev() {
+            let diff = ORDER[i] as i16 - compact[32 + i] as i16 - borrow;
+            borrow = if diff < 0 { 1 } else { 0 };
+            compact[32 + i] = (diff + 256 * borrow) as u8;
+        }
+        let high_s = Signature::from_compact(&compact).unwrap();
+        assert!(!signature_is_low_s(&high_s));
+        assert!(!signature_verify(
+            b"data",
+            &hex::encode(high_s.serialize_der()),
+            &public_key
+        ));
+    }
+    #[test]
+    fn address_without_version_uses_the_network_one() {
+        let passphrase = "this is a top secret passphrase";
+
+        let address = {
+            let _guard = NetworkVersionGuard::set(DEFAULT_NETWORK_VERSION);
+            let address = address_from_passphrase(passphrase, None);
+            assert!(address_validate(&address, None));
+            address
+        };
+        let bytes = bs58::decode(&address).into_vec().unwrap();
+        assert_eq!(bytes[0], DEFAULT_NETWORK_VERSION);
+
+        let _guard = NetworkVersionGuard::set(0x1e);
+        assert!(!address_validate(&address, None));
+
+        let address = address_from_passphrase(passphrase, None);
+        assert_eq!(address, address_from_passphrase(passphrase, Some(0x1e)));
+        assert!(address_validate(&address, None));
+    }
+    #[test]
     fn test_address_from_passphrase() {
         let address = address_from_passphrase("this is a top secret passphrase", Some(0x1e));
         assert_eq!(address, "2r8UNhjyYhwqakcFLoUcLKrBmPm7f");
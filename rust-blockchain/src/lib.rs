@@ -1,11 +1,12 @@
 extern crate bincode;
-extern crate sha1;
 extern crate time;
 
 extern crate serde;
+extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 
+extern crate rand;
 extern crate secp256k1;
 extern crate sha2;
 #[macro_use]
@@ -16,24 +17,30 @@ extern crate ripemd160;
 
 pub mod block;
 pub mod blocks;
+pub mod canonical;
 pub mod display;
+pub mod hash_algo;
 pub mod hash_content;
 pub mod help;
 pub mod identity;
 pub mod message;
 pub mod peers;
+pub mod rate_limit;
 pub mod transaction;
 
-use std::io::Read;
-use std::net::TcpListener;
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread::spawn;
-
-use bincode::deserialize;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
 
 use block::Block;
 
-use blocks::{add_block_from_message, broadcast_block, list_blocks, send_last_block_to_stream};
+use blocks::{
+    add_block_from_message, broadcast_block, list_blocks, send_chain_to_stream,
+    send_suffix_to_stream,
+};
 
 use peers::{create_stream, get_chain_from_stream, list_peers};
 
@@ -41,21 +48,80 @@ use help::list_commands;
 
 use display::{clear_screen, get_input, set_cursor_into_input, set_cursor_into_logs};
 
-use message::{Message, MessageLabel};
+use message::{read_frame, Message, MessageLabel};
+
+use rate_limit::{ConnectionLimit, RateLimiter, DEFAULT_CONNECTION_LIMIT};
+
+use transaction::transaction_module;
 
 const LISTENING_PORT: &str = "10000";
 
+/// Time waited between two checks of the listener when no connection is pending.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
 /// Handle incoming TCP connections with other nodes.
 ///
 /// Args:
 ///
 /// `chain` - the chain to manipulate
-pub fn handle_incoming_connections(chain: Arc<Mutex<Vec<Block>>>) {
+/// `transactions` - the transactions pool, shared with the commands thread
+/// `shutdown` - stops listening once set to true
+pub fn handle_incoming_connections(
+    chain: Arc<Mutex<Vec<Block>>>,
+    transactions: Arc<Mutex<transaction_module>>,
+    shutdown: Arc<AtomicBool>,
+) {
     let address = format!("0.0.0.0:{}", LISTENING_PORT);
     let listener = TcpListener::bind(address).unwrap();
 
-    /* blocks until data is received */
-    for income in listener.incoming() {
+    accept_connections(listener, chain, transactions, shutdown, DEFAULT_CONNECTION_LIMIT);
+}
+
+/// Handle incoming TCP connections with other nodes on an already bound listener,
+/// until the shutdown flag is set. Connections from an address exceeding the limit are dropped.
+///
+/// Args:
+///
+/// `listener` - the listener accepting the connections
+/// `chain` - the chain to manipulate
+/// `transactions` - the transactions pool, shared with the commands thread
+/// `shutdown` - stops listening once set to true
+/// `limit` - how many connections one address may open
+pub fn accept_connections(
+    listener: TcpListener,
+    chain: Arc<Mutex<Vec<Block>>>,
+    transactions: Arc<Mutex<transaction_module>>,
+    shutdown: Arc<AtomicBool>,
+    limit: ConnectionLimit,
+) {
+    let mut limiter = RateLimiter::new(limit);
+
+    /* the listener is polled, so that the flag is checked even if no node connects */
+    listener
+        .set_nonblocking(true)
+        .expect("cannot set the listener as non-blocking");
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let stream = match listener.accept() {
+            Ok((stream, peer)) => {
+                if !limiter.allow(peer.ip(), Instant::now()) {
+                    println!("Connection from {} dropped: too many connections.", peer.ip());
+                    continue;
+                }
+                stream
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                sleep(ACCEPT_POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                /* e.g. too many open files: retrying at once would only fail again */
+                println!("Incoming connection cannot be accepted: {}.", e);
+                sleep(ACCEPT_POLL_INTERVAL);
+                continue;
+            }
+        };
+
         /* TODO: display message when receive a connection;
         should use mutex as it must modify the content
         of the main text area (so the cursor position
@@ -64,25 +130,153 @@ pub fn handle_incoming_connections(chain: Arc<Mutex<Vec<Block>>>) {
         clear_screen();
         set_cursor_into_logs();
 
-        let mut stream = income.unwrap();
+        if stream.set_nonblocking(false).is_ok() {
+            handle_stream(stream, &chain, &transactions);
+        }
+
+        set_cursor_into_input();
+    }
+}
+
+/// Handle the message received through one incoming connection.
+///
+/// Args:
+///
+/// `stream` - the stream opened by the remote node
+/// `chain` - the chain to manipulate
+/// `transactions` - the pool receiving the transactions sent by the remote node
+pub fn handle_stream(
+    mut stream: TcpStream,
+    chain: &Arc<Mutex<Vec<Block>>>,
+    transactions: &Arc<Mutex<transaction_module>>,
+) {
+    /* blocks until the whole message is received */
+    let message = match read_frame(&mut stream) {
+        Ok(bytes) => match Message::from_bytes(&bytes) {
+            Ok(message) => message,
+            Err(e) => {
+                println!("Received message cannot be decoded: {}.", e);
+                return;
+            }
+        },
+        Err(e) => {
+            println!("Received message cannot be read: {}.", e);
+            return;
+        }
+    };
+    let label = message.get_label();
+
+    if label == &MessageLabel::AskForAllBlocks {
+        if send_chain_to_stream(stream, chain).is_err() {
+            println!("Chain cannot be sent.");
+        }
+    } else if label == &MessageLabel::AskForBlocksAfter {
+        if send_suffix_to_stream(stream, chain, message.get_hashes()).is_err() {
+            println!("Blocks cannot be sent.");
+        }
+    } else if label == &MessageLabel::SendBlock {
+        add_block_from_message(chain, &message);
+    } else if label == &MessageLabel::SendTransaction {
+        let mut transactions = transactions.lock().unwrap();
+
+        for transac in message.get_transactions() {
+            if let Err(e) = transactions.receive_transaction(transac) {
+                println!("Received transaction is refused: {}.", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::mpsc::channel;
 
-        const MESSAGE_MAX_LENGTH: usize = 20;
-        let mut buffer: Vec<u8> = vec![0; MESSAGE_MAX_LENGTH];
+    use message::write_frame;
 
-        /* blocks until data is received  */
-        stream
-            .read(&mut buffer)
-            .expect("Received message is too long.");
+    #[test]
+    fn listener_stops_on_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let chain = Arc::new(Mutex::new(Vec::new()));
+        let transactions = Arc::new(Mutex::new(transaction_module::new()));
+        let shutdown = Arc::new(AtomicBool::new(false));
 
-        let message: Message = deserialize(&buffer).unwrap();
-        let label = message.get_label();
+        let (sender, receiver) = channel();
+        let thread_shutdown = shutdown.clone();
+        let handle = spawn(move || {
+            accept_connections(
+                listener,
+                chain,
+                transactions,
+                thread_shutdown,
+                DEFAULT_CONNECTION_LIMIT,
+            );
+            sender.send(()).unwrap();
+        });
 
-        if label == &MessageLabel::AskForAllBlocks {
-            send_last_block_to_stream(stream, &chain);
-        } else if label == &MessageLabel::SendBlock {
-            add_block_from_message(&chain, &message);
+        shutdown.store(true, Ordering::SeqCst);
+
+        assert!(receiver.recv_timeout(Duration::from_secs(2)).is_ok());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn connections_over_the_limit_are_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let chain = Arc::new(Mutex::new(Vec::new()));
+        let transactions = Arc::new(Mutex::new(transaction_module::new()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        /* the bucket is not refilled while the test runs */
+        let limit = ConnectionLimit {
+            interval: Duration::from_secs(3600),
+            ..DEFAULT_CONNECTION_LIMIT
+        };
+        let thread_shutdown = shutdown.clone();
+        let handle = spawn(move || {
+            accept_connections(listener, chain, transactions, thread_shutdown, limit);
+        });
+
+        /* a handled connection gets the chain, a dropped one is closed without any answer */
+        for _ in 0..limit.burst {
+            let stream = TcpStream::connect(address).unwrap();
+            assert_eq!(get_chain_from_stream(stream).unwrap(), Vec::new());
         }
+        let stream = TcpStream::connect(address).unwrap();
+        assert!(get_chain_from_stream(stream).is_err());
 
-        set_cursor_into_input();
+        shutdown.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn received_transaction_lands_in_shared_pool() {
+        let mut module = transaction_module::new();
+        let signed = module
+            .create_and_broadcast_transaction(
+                "from".to_string(),
+                "to".to_string(),
+                vec![1, 2, 3],
+                "this is a passphrase",
+            )
+            .unwrap();
+        let mut forged = signed.clone();
+        forged.value = vec![4, 5, 6];
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let message = Message::with_transactions(vec![signed.clone(), forged]);
+        write_frame(&mut client, &message.to_bytes()).unwrap();
+
+        let chain = Arc::new(Mutex::new(Vec::new()));
+        let transactions = Arc::new(Mutex::new(transaction_module::new()));
+        let (stream, _) = listener.accept().unwrap();
+        handle_stream(stream, &chain, &transactions);
+
+        let mut transactions = transactions.lock().unwrap();
+        assert_eq!(transactions.get_current(), &vec![signed.clone()]);
+        assert_eq!(transactions.get_mempool_mut(), &mut vec![signed]);
     }
 }
@@ -1,16 +1,58 @@
 //! A message sent over the network for peers communication.
 
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Result, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bincode::{deserialize, serialize};
+
 use block::Block;
+use transaction::transaction;
+
+/// Frames longer than this are refused unless another maximum is set: enough for a block
+/// of `MAX_BLOCK_DATA` bytes along with its header, or for a batch of transactions.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+static MAX_FRAME_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_FRAME_SIZE);
+
+/// Maximum length of the frames accepted by `read_frame`.
+pub fn max_frame_size() -> usize {
+    MAX_FRAME_SIZE.load(Ordering::Relaxed)
+}
 
-#[derive(Serialize, Deserialize, PartialEq)]
+/// Changes the maximum length of the frames accepted by `read_frame`.
+pub fn set_max_frame_size(size: usize) {
+    MAX_FRAME_SIZE.store(size, Ordering::Relaxed);
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub enum MessageLabel {
     AskForAllBlocks,
+    AskForBlocksAfter,
     SendBlock,
+    SendTransaction,
+}
+
+/// Error returned when received bytes cannot be decoded as a message.
+#[derive(Debug)]
+pub struct MessageError {
+    reason: String,
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "malformed message: {}", self.reason)
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+impl Error for MessageError {}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct Message {
     blocks: Vec<Block>,
+    transactions: Vec<transaction>,
+    hashes: Vec<String>,
     label: MessageLabel,
 }
 
@@ -28,10 +70,48 @@ impl Message {
     pub fn new(blocks: Vec<Block>, label: MessageLabel) -> Message {
         Message {
             blocks: blocks,
+            transactions: Vec::new(),
+            hashes: Vec::new(),
             label: label,
         }
     }
 
+    /// Creates a message sending transactions to a peer.
+    ///
+    /// Args:
+    ///
+    /// `transactions` - the transactions to include into the message
+    ///
+    /// Returns:
+    ///
+    /// the new message, labelled `SendTransaction`
+    pub fn with_transactions(transactions: Vec<transaction>) -> Message {
+        Message {
+            blocks: Vec::new(),
+            transactions: transactions,
+            hashes: Vec::new(),
+            label: MessageLabel::SendTransaction,
+        }
+    }
+
+    /// Creates a message asking a peer for the blocks it has after the last one both chains share.
+    ///
+    /// Args:
+    ///
+    /// `hashes` - the hashes of the local chain, from the genesis to the tip
+    ///
+    /// Returns:
+    ///
+    /// the new message, labelled `AskForBlocksAfter`
+    pub fn with_hashes(hashes: Vec<String>) -> Message {
+        Message {
+            blocks: Vec::new(),
+            transactions: Vec::new(),
+            hashes: hashes,
+            label: MessageLabel::AskForBlocksAfter,
+        }
+    }
+
     /// Getter of the label
     ///
     /// Return:
@@ -50,6 +130,24 @@ impl Message {
         &self.blocks
     }
 
+    /// Getter of the transactions array
+    ///
+    /// Return:
+    ///
+    /// the transactions array
+    pub fn get_transactions(&self) -> &Vec<transaction> {
+        &self.transactions
+    }
+
+    /// Getter of the block hashes array
+    ///
+    /// Return:
+    ///
+    /// the block hashes array
+    pub fn get_hashes(&self) -> &Vec<String> {
+        &self.hashes
+    }
+
     /// Setter of the blocks array
     ///
     /// Args:
@@ -58,4 +156,148 @@ impl Message {
     pub fn set_blocks(&mut self, blocks: Vec<Block>) {
         self.blocks = blocks;
     }
+
+    /// Encodes the message to be sent over the network.
+    ///
+    /// Returns:
+    ///
+    /// the message bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        /* a message only holds plain data, so the serialization cannot fail */
+        serialize(self).unwrap()
+    }
+
+    /// Decodes a message received from the network.
+    ///
+    /// Args:
+    ///
+    /// `bytes` - the received bytes
+    ///
+    /// Returns:
+    ///
+    /// the message, or an error if the bytes are not a valid message
+    pub fn from_bytes(bytes: &[u8]) -> ::std::result::Result<Message, MessageError> {
+        deserialize(bytes).map_err(|e| MessageError {
+            reason: e.to_string(),
+        })
+    }
+}
+
+/// Writes one frame into the stream: the bytes length as a big-endian `u32`, then the bytes.
+///
+/// Args:
+///
+/// `stream` - the stream where data must be written
+/// `bytes` - the frame content
+pub fn write_frame<W: Write>(stream: &mut W, bytes: &[u8]) -> Result<()> {
+    stream.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    stream.write_all(bytes)
+}
+
+/// Reads one frame written by `write_frame` from the stream. Blocks until the whole frame is received.
+/// Frames longer than `max_frame_size()` are refused.
+///
+/// Args:
+///
+/// `stream` - the stream to read from
+///
+/// Returns:
+///
+/// the frame content
+pub fn read_frame<R: Read>(stream: &mut R) -> Result<Vec<u8>> {
+    read_frame_limited(stream, max_frame_size())
+}
+
+/// Reads one frame like `read_frame`, with the given maximum length. The length comes from
+/// the remote node, so it is checked before the buffer is allocated.
+///
+/// Args:
+///
+/// `stream` - the stream to read from
+/// `max_size` - the maximum length of the frame content, in bytes
+///
+/// Returns:
+///
+/// the frame content, or an `InvalidData` error if the frame is longer than `max_size`
+/// (the rest of the frame is not read, so the connection must be closed)
+pub fn read_frame_limited<R: Read>(stream: &mut R, max_size: usize) -> Result<Vec<u8>> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "frame of {} bytes exceeds the maximum of {}",
+                length, max_size
+            ),
+        ));
+    }
+
+    let mut buffer = vec![0; length];
+    stream.read_exact(&mut buffer)?;
+
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn every_label_round_trips() {
+        let block = Block::new(&vec![1, 2, 3], String::new()).unwrap();
+
+        for label in vec![MessageLabel::AskForAllBlocks, MessageLabel::SendBlock] {
+            let message = Message::new(vec![block.clone()], label);
+            let decoded = Message::from_bytes(&message.to_bytes()).unwrap();
+
+            assert_eq!(decoded, message);
+        }
+
+        let message = Message::with_transactions(vec![transaction::default()]);
+        let decoded = Message::from_bytes(&message.to_bytes()).unwrap();
+
+        assert_eq!(decoded, message);
+
+        let message = Message::with_hashes(vec![block.get_current().to_string()]);
+        let decoded = Message::from_bytes(&message.to_bytes()).unwrap();
+
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected_before_reading() {
+        use std::io::Cursor;
+
+        let mut bytes = u32::MAX.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&[1, 2, 3]);
+        let mut stream = Cursor::new(bytes);
+
+        let error = read_frame_limited(&mut stream, 1024).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        /* only the length prefix has been consumed */
+        assert_eq!(stream.position(), 4);
+
+        let mut frame = Vec::new();
+        write_frame(&mut frame, &[7; 1024]).unwrap();
+        assert_eq!(
+            read_frame_limited(&mut Cursor::new(&frame), 1024).unwrap(),
+            vec![7; 1024]
+        );
+        assert!(read_frame_limited(&mut Cursor::new(&frame), 1023).is_err());
+    }
+
+    #[test]
+    fn truncated_message_is_an_error() {
+        let message = Message::new(
+            vec![Block::new(&vec![1, 2, 3], String::new()).unwrap()],
+            MessageLabel::SendBlock,
+        );
+        let bytes = message.to_bytes();
+
+        assert!(Message::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Message::from_bytes(&[]).is_err());
+    }
 }
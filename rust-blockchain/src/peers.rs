@@ -1,16 +1,21 @@
 //! Peers routines.
 
-use std::io::{Read, Write};
+use std::io::Read;
 use std::net::{SocketAddr, TcpStream};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use bincode::{deserialize, serialize};
+use bincode::{deserialize, Result};
 
-use message::{Message, MessageLabel};
+use message::{read_frame, write_frame, Message, MessageLabel};
 
 use block::Block;
 
+use blocks::{adopt_suffix, block_hashes};
+
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// Check the given address and returns a stream to communicate with the specified node. Handles errors with output messages.
 ///
 /// Args:
@@ -44,7 +49,39 @@ pub fn create_stream(address: &str) -> Option<TcpStream> {
     Some(stream)
 }
 
-/// Creates a new peer.
+/// Checks that a peer can be joined, without any output.
+///
+/// Args:
+///
+/// `address` - the node address in format IP:PORT
+///
+/// Returns:
+///
+/// true if a connection has been opened within a short timeout
+pub fn ping(address: &str) -> bool {
+    match SocketAddr::from_str(address) {
+        Ok(socket_address) => TcpStream::connect_timeout(&socket_address, PING_TIMEOUT).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Removes the peers that cannot be joined anymore.
+///
+/// Args:
+///
+/// `peers` - the list of peers to clean
+///
+/// Returns:
+///
+/// the amount of removed peers
+pub fn prune_dead_peers(peers: &mut Vec<String>) -> usize {
+    let count = peers.len();
+    peers.retain(|peer| ping(peer));
+
+    count - peers.len()
+}
+
+/// Asks the remote node for its whole chain and receives it block by block.
 ///
 /// Args:
 ///
@@ -52,26 +89,76 @@ pub fn create_stream(address: &str) -> Option<TcpStream> {
 ///
 /// Returns:
 ///
-/// the received remote chain
-pub fn get_chain_from_stream(mut stream: TcpStream) -> Vec<Block> {
+/// the received remote chain, or an error if the transfer is interrupted or malformed
+pub fn get_chain_from_stream(mut stream: TcpStream) -> Result<Vec<Block>> {
     let message = Message::new(Vec::new(), MessageLabel::AskForAllBlocks);
 
-    let bytes = serialize(&message).unwrap();
+    write_frame(&mut stream, &message.to_bytes())?;
+
+    receive_blocks(stream)
+}
 
-    stream.write(&bytes).unwrap();
+/// Sends the hashes of the local chain to the remote node, and receives the blocks it has
+/// after the last common one (its whole chain if even the genesis differs).
+///
+/// Args:
+///
+/// `stream` - the stream opened to the peer
+/// `hashes` - the hashes of the local chain, from the genesis to the tip
+///
+/// Returns:
+///
+/// the received blocks, or an error if the transfer is interrupted or malformed
+pub fn get_suffix_from_stream(mut stream: TcpStream, hashes: Vec<String>) -> Result<Vec<Block>> {
+    let message = Message::with_hashes(hashes);
+
+    write_frame(&mut stream, &message.to_bytes())?;
 
+    receive_blocks(stream)
+}
+
+/// Reads the blocks count, then every block, as written by the remote node.
+fn receive_blocks(mut stream: TcpStream) -> Result<Vec<Block>> {
     println!("Waiting for reply...");
 
-    /* TODO: explain why a message maximum size is 80 bytes long */
-    const ONE_BLOCK_MESSAGE_MAX_LENGTH: usize = 80;
+    let mut count = [0; 8];
+    stream.read_exact(&mut count)?;
+    let count = u64::from_be_bytes(count);
+
+    /* the count comes from the remote node, so the chain is not
+    preallocated with it */
+    let mut chain = Vec::new();
 
-    let mut buffer: Vec<u8> = vec![0; ONE_BLOCK_MESSAGE_MAX_LENGTH];
-    stream
-        .read(&mut buffer)
-        .expect("Received message is too long.");
+    for _ in 0..count {
+        let bytes = read_frame(&mut stream)?;
+        chain.push(deserialize(&bytes)?);
+    }
+
+    Ok(chain)
+}
 
-    let message: Message = deserialize(&buffer).unwrap();
-    message.get_blocks().clone()
+/// Sends the local hashes to a peer, receives the blocks it has after the last common one,
+/// and splices them in if they are better than the local ones.
+/// The local chain is not locked while waiting for the remote blocks.
+///
+/// Args:
+///
+/// `chain` - the local chain
+/// `stream` - the stream opened to the peer
+///
+/// Returns:
+///
+/// true if the local chain has been updated
+pub fn sync_chain(chain: &Arc<Mutex<Vec<Block>>>, stream: TcpStream) -> bool {
+    let hashes = block_hashes(&chain.lock().unwrap());
+
+    match get_suffix_from_stream(stream, hashes) {
+        Ok(suffix) => adopt_suffix(chain, suffix),
+        Err(_) => {
+            println!("The remote blocks cannot be received.");
+            false
+        }
+    }
 }
 
 /// Displays all the peers.
@@ -84,3 +171,195 @@ pub fn list_peers(peers: &Vec<String>) {
         println!("{}", peer);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use blocks::append_block;
+    use handle_stream;
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread::spawn;
+    use transaction::transaction_module;
+
+    #[test]
+    fn dead_peers_are_pruned() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live = listener.local_addr().unwrap().to_string();
+
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+
+        let mut peers = vec![closed, live.clone(), "not an address".to_string()];
+
+        assert_eq!(prune_dead_peers(&mut peers), 2);
+        assert_eq!(peers, vec![live]);
+    }
+
+    #[test]
+    fn transfer_long_chain() {
+        let mut blocks: Vec<Block> = Vec::new();
+        for i in 0..100 {
+            let previous = match blocks.last() {
+                Some(block) => block.get_current().to_string(),
+                None => String::new(),
+            };
+            blocks.push(Block::new(&vec![i as u8; 20_000], previous).unwrap());
+        }
+        let chain = Arc::new(Mutex::new(blocks.clone()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_stream(stream, &chain, &Arc::new(Mutex::new(transaction_module::new())));
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let received = get_chain_from_stream(stream).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(received, blocks);
+    }
+
+    #[test]
+    fn longer_chain_sends_only_the_missing_suffix() {
+        let remote_chain = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..5 {
+            append_block(&remote_chain, &vec![i]).unwrap();
+        }
+        let remote_blocks = remote_chain.lock().unwrap().clone();
+
+        /* the local node only has the first two blocks of the remote chain */
+        let local_blocks = remote_blocks[..2].to_vec();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_stream(
+                stream,
+                &remote_chain,
+                &Arc::new(Mutex::new(transaction_module::new())),
+            );
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let received = get_suffix_from_stream(stream, block_hashes(&local_blocks)).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(received, remote_blocks[2..].to_vec());
+    }
+
+    #[test]
+    fn sync_splices_the_remote_suffix() {
+        let remote_chain = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..5 {
+            append_block(&remote_chain, &vec![i]).unwrap();
+        }
+        let remote_blocks = remote_chain.lock().unwrap().clone();
+
+        /* the local node shares two blocks with the peer, then has a shorter fork */
+        let chain = Arc::new(Mutex::new(remote_blocks[..2].to_vec()));
+        append_block(&chain, &vec![9]).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_stream(
+                stream,
+                &remote_chain,
+                &Arc::new(Mutex::new(transaction_module::new())),
+            );
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        assert!(sync_chain(&chain, stream));
+        server.join().unwrap();
+
+        assert_eq!(*chain.lock().unwrap(), remote_blocks);
+    }
+
+    #[test]
+    fn invalid_chain_served_by_a_peer_is_refused() {
+        let remote_chain = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..3 {
+            append_block(&remote_chain, &vec![i]).unwrap();
+        }
+        /* more work than the local chain, but not linked to the previous block */
+        remote_chain
+            .lock()
+            .unwrap()
+            .push(Block::new(&vec![3], "not the previous digest".to_string()).unwrap());
+
+        let chain = Arc::new(Mutex::new(Vec::new()));
+        append_block(&chain, &vec![9]).unwrap();
+        let local_blocks = chain.lock().unwrap().clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_stream(
+                stream,
+                &remote_chain,
+                &Arc::new(Mutex::new(transaction_module::new())),
+            );
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        assert!(!sync_chain(&chain, stream));
+        server.join().unwrap();
+
+        assert_eq!(*chain.lock().unwrap(), local_blocks);
+    }
+
+    #[test]
+    fn slow_sync_does_not_block_local_blocks() {
+        let chain = Arc::new(Mutex::new(Vec::new()));
+        append_block(&chain, &vec![0]).unwrap();
+
+        let remote_chain = Arc::new(Mutex::new(Vec::new()));
+        append_block(&remote_chain, &vec![1]).unwrap();
+
+        /* the peer only answers once the local block has been added */
+        let (accepted_sender, accepted) = channel();
+        let (release, released) = channel::<()>();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            accepted_sender.send(()).unwrap();
+            /* slow network */
+            released.recv().unwrap();
+            handle_stream(
+                stream,
+                &remote_chain,
+                &Arc::new(Mutex::new(transaction_module::new())),
+            );
+        });
+
+        let sync_chain_ref = chain.clone();
+        let stream = TcpStream::connect(address).unwrap();
+        let sync = spawn(move || sync_chain(&sync_chain_ref, stream));
+        accepted.recv().unwrap();
+
+        /* the sync is waiting for the peer, adding a block must not wait for it */
+        let (added_sender, added) = channel();
+        let append_chain = chain.clone();
+        spawn(move || {
+            append_block(&append_chain, &vec![2]).unwrap();
+            added_sender.send(()).unwrap();
+        });
+        /* the timeout only keeps a regression from hanging the test suite */
+        assert!(added.recv_timeout(Duration::from_secs(30)).is_ok());
+
+        release.send(()).unwrap();
+        sync.join().unwrap();
+        server.join().unwrap();
+    }
+}
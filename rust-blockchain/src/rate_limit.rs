@@ -0,0 +1,138 @@
+//! Limits how often one remote address may open a connection to the node.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How many connections one address may open: `burst` at once, then one more every `interval`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectionLimit {
+    pub burst: u32,
+    pub interval: Duration,
+}
+
+/// Limit applied by the node unless another one is given.
+pub const DEFAULT_CONNECTION_LIMIT: ConnectionLimit = ConnectionLimit {
+    burst: 20,
+    interval: Duration::from_millis(100),
+};
+
+/// Amount of addresses above which the ones back to a full bucket are forgotten.
+const MAX_TRACKED_ADDRESSES: usize = 1024;
+
+/// Token bucket per remote address.
+pub struct RateLimiter {
+    limit: ConnectionLimit,
+    /* available tokens, and when the bucket was last refilled */
+    buckets: HashMap<IpAddr, (u32, Instant)>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter that has not seen any connection yet.
+    ///
+    /// Args:
+    ///
+    /// `limit` - the connections allowed per address
+    ///
+    /// Returns:
+    ///
+    /// the new limiter
+    pub fn new(limit: ConnectionLimit) -> RateLimiter {
+        RateLimiter {
+            limit: limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token from the bucket of the address, if there is one left.
+    ///
+    /// Args:
+    ///
+    /// `address` - the address the connection comes from
+    /// `now` - when the connection has been accepted
+    ///
+    /// Returns:
+    ///
+    /// true if the connection may be handled, false if it must be dropped
+    pub fn allow(&mut self, address: IpAddr, now: Instant) -> bool {
+        if self.buckets.len() >= MAX_TRACKED_ADDRESSES {
+            self.forget_idle(now);
+        }
+
+        let limit = self.limit;
+        let bucket = self
+            .buckets
+            .entry(address)
+            .or_insert((limit.burst, now));
+        refill(bucket, limit, now);
+
+        if bucket.0 == 0 {
+            return false;
+        }
+
+        bucket.0 -= 1;
+        true
+    }
+
+    /// Drops the buckets that are full again, they behave like unknown addresses.
+    fn forget_idle(&mut self, now: Instant) {
+        let limit = self.limit;
+        self.buckets.retain(|_, bucket| {
+            refill(bucket, limit, now);
+            bucket.0 < limit.burst
+        });
+    }
+}
+
+/// Adds the tokens earned since the last refill, up to the burst.
+fn refill(bucket: &mut (u32, Instant), limit: ConnectionLimit, now: Instant) {
+    if limit.interval == Duration::from_secs(0) {
+        bucket.0 = limit.burst;
+        return;
+    }
+
+    let elapsed = now.saturating_duration_since(bucket.1);
+    let earned = elapsed.as_nanos() / limit.interval.as_nanos();
+    if earned == 0 {
+        return;
+    }
+
+    bucket.0 = (u128::from(bucket.0) + earned).min(u128::from(limit.burst)) as u32;
+    bucket.1 = if bucket.0 == limit.burst {
+        now
+    } else {
+        bucket.1 + limit.interval * earned as u32
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn connections_beyond_the_rate_are_rejected() {
+        let limit = ConnectionLimit {
+            burst: 2,
+            interval: Duration::from_secs(1),
+        };
+        let mut limiter = RateLimiter::new(limit);
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.allow(peer, start));
+        assert!(limiter.allow(peer, start));
+        assert!(!limiter.allow(peer, start));
+        assert!(!limiter.allow(peer, start + Duration::from_millis(500)));
+
+        /* other addresses have their own bucket */
+        assert!(limiter.allow(other, start));
+
+        /* one token is earned per interval */
+        assert!(limiter.allow(peer, start + Duration::from_secs(1)));
+        assert!(!limiter.allow(peer, start + Duration::from_secs(1)));
+        assert!(limiter.allow(peer, start + Duration::from_secs(10)));
+        assert!(limiter.allow(peer, start + Duration::from_secs(10)));
+        assert!(!limiter.allow(peer, start + Duration::from_secs(10)));
+    }
+}
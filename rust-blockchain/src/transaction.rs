@@ -1,43 +1,126 @@
 use bincode::{deserialize, serialize};
+use canonical::{put_bytes, put_str};
 use identity::*;
-use secp256k1::Signature;
-use sha2::{Digest, Sha256};
 use bs58;
+use serde_json;
+use std::error::Error;
+use std::fmt;
 
-#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+/* the wire names are pinned, so that renaming a field does not change the JSON
+of the transactions; bincode only depends on the order of the fields */
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 pub struct transaction {
+    #[serde(rename = "from")]
     pub from: String,
+    #[serde(rename = "to")]
     pub to: String,
+    #[serde(rename = "value")]
     pub value: Vec<u8>,
+    #[serde(rename = "sender_public_key")]
     pub sender_public_key: String,
+    #[serde(rename = "signature")]
     pub signature: String,
 }
+/// Error returned when a transaction cannot be created.
+#[derive(Debug, PartialEq)]
+pub enum TransactionError {
+    EmptyValue,
+    InvalidSignature,
+    AlreadyKnown,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TransactionError::EmptyValue => write!(f, "the transaction has no value"),
+            TransactionError::InvalidSignature => write!(f, "the transaction signature is invalid"),
+            TransactionError::AlreadyKnown => write!(f, "the transaction is already known"),
+        }
+    }
+}
+
+impl Error for TransactionError {}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct transaction_module {
     current: Vec<transaction>,
+    mempool: Vec<transaction>,
 }
 
 impl transaction_module {
     pub fn new() -> Self {
-        transaction_module { current: vec![] }
+        transaction_module {
+            current: vec![],
+            mempool: vec![],
+        }
     }
 
-    pub fn create_and_broadcast_transaction(&mut self, from: String, to: String) -> Result<(), ()> {
+    /// Creates a transaction, signs its value with the passphrase of the sender
+    /// and adds it to the local transactions and to the mempool.
+    ///
+    /// Args:
+    ///
+    /// `from` - the address of the sender
+    /// `to` - the address of the receiver
+    /// `value` - the transferred bytes, signed by the sender
+    /// `passphrase` - the passphrase of the sender
+    ///
+    /// Returns:
+    ///
+    /// the created transaction, or the reason why it cannot be created
+    pub fn create_and_broadcast_transaction(
+        &mut self,
+        from: String,
+        to: String,
+        value: Vec<u8>,
+        passphrase: &str,
+    ) -> Result<transaction, TransactionError> {
+        if value.is_empty() {
+            return Err(TransactionError::EmptyValue);
+        }
+
         let mut transac = transaction::new();
         transac.from = from;
         transac.to = to;
+        transac.value = value;
+        transac.sign(passphrase);
 
-        //todo verify transaction
+        if !transac.verify() {
+            return Err(TransactionError::InvalidSignature);
+        }
 
-        self.current.push(transac);
+        self.current.push(transac.clone());
+        self.mempool.push(transac.clone());
 
-        //todo broadcast
+        //todo broadcast, once peers accept a transaction message
 
-        Ok(())
+        Ok(transac)
     }
 
-    pub fn receive_transaction(&mut self, transac: &transaction) {
+    /// Adds a transaction received from a peer, if its signature is valid and it is not
+    /// known yet. The transaction is kept with a DER signature, so that the same transaction
+    /// relayed with its signature re-encoded is not added twice.
+    ///
+    /// Args:
+    ///
+    /// `transac` - the received transaction
+    ///
+    /// Returns:
+    ///
+    /// nothing if the transaction has been added, or the reason why it has not
+    pub fn receive_transaction(&mut self, transac: &transaction) -> Result<(), TransactionError> {
+        if !transac.verify() {
+            return Err(TransactionError::InvalidSignature);
+        }
+
+        let transac = transac.with_der_signature();
+        if self.current.contains(&transac) || self.mempool.contains(&transac) {
+            return Err(TransactionError::AlreadyKnown);
+        }
+
         self.current.push(transac.clone());
+        self.mempool.push(transac);
+        Ok(())
     }
 
     pub fn list_transaction_local(&self) {
@@ -49,6 +132,10 @@ impl transaction_module {
     pub fn get_current(&self) -> &Vec<transaction> {
         &self.current
     }
+    /// Transactions waiting to be sealed into a block.
+    pub fn get_mempool_mut(&mut self) -> &mut Vec<transaction> {
+        &mut self.mempool
+    }
 }
 impl transaction {
     fn new() -> Self {
@@ -56,26 +143,74 @@ impl transaction {
             ..Default::default()
         }
     }
+    /// Signs the canonical encoding of the transaction, once the sender public key is set.
     pub fn sign(&mut self, passphrase: &str) -> &Self {
         let private_key = privatekey_from_passphrase(passphrase);
         let public_key = publickey_from_private_key(&private_key);
-        self.sender_public_key = public_key.to_string();
-        self.signature = privatekey_to_signature(self.value.as_slice(), passphrase);
+        self.sender_public_key = publickey_to_hex(&public_key);
+        self.signature = privatekey_to_signature(&self.to_bytes(), passphrase);
         self
     }
+    /// Signs the transaction like `sign`, with a compact signature instead of a DER one.
+    /// `verify` accepts both.
+    pub fn sign_compact(&mut self, passphrase: &str) -> &Self {
+        let private_key = privatekey_from_passphrase(passphrase);
+        let public_key = publickey_from_private_key(&private_key);
+        self.sender_public_key = publickey_to_hex(&public_key);
+        self.signature = sign_message_compact(&self.to_bytes(), passphrase);
+        self
+    }
+    /// Copy of the transaction whose signature is encoded as DER, see `signature_to_der`.
+    /// A malformed signature is kept as it is.
+    pub fn with_der_signature(&self) -> transaction {
+        let mut transac = self.clone();
+        if let Some(signature) = signature_to_der(&self.signature) {
+            transac.signature = signature;
+        }
+        transac
+    }
+    /// Checks the signature of the transaction against its sender public key.
+    pub fn verify(&self) -> bool {
+        self.internal_verify(&self.sender_public_key, &self.signature, &self.to_bytes())
+    }
     fn internal_verify(&self, sender_public_key: &str, signature: &str, bytes: &[u8]) -> bool {
-        let hash = Sha256::digest(&bytes);
-        let msg = secp256k1::Message::from_slice(&hash).unwrap();
-
-        let sig = Signature::from_der(&hex::decode(signature).unwrap()).unwrap();
-        let pk = publickey_from_hex(&sender_public_key);
-        
-        SECP256K1.verify(&msg, &sig, &pk).is_ok()
+        signature_verify(bytes, signature, sender_public_key)
     }
+    /// Encodes the signed part of the transaction, every field but the signature, in this order:
+    /// `from`, `to`, `value` and `sender_public_key`, each prefixed by its length.
+    ///
+    /// Returns:
+    ///
+    /// the canonical encoding of the transaction
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut buffer = vec![];
+        put_str(&mut buffer, &self.from);
+        put_str(&mut buffer, &self.to);
+        put_bytes(&mut buffer, &self.value);
+        put_str(&mut buffer, &self.sender_public_key);
 
-        return buffer;
+        buffer
+    }
+    /// Encodes the transaction as a JSON object, whose keys are the field names.
+    ///
+    /// Returns:
+    ///
+    /// the JSON text of the transaction
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("a transaction is always serializable")
+    }
+    /// Decodes a transaction written by `to_json`, e.g. received from an API client.
+    /// The signature is not checked, `verify` does.
+    ///
+    /// Args:
+    ///
+    /// `json` - the JSON object, with every field of the transaction
+    ///
+    /// Returns:
+    ///
+    /// the transaction, or an error if the JSON is malformed or a field is missing
+    pub fn from_json(json: &str) -> Result<transaction, serde_json::Error> {
+        serde_json::from_str(json)
     }
 }
 
@@ -92,11 +227,194 @@ mod test {
         transac.value = bytes;
         
         transac.sign(passphrase);
-        transac.sender_public_key = identity::publickkey_from_passphrase(passphrase).to_string();
+        transac.sender_public_key = identity::publickey_to_hex(&identity::publickkey_from_passphrase(passphrase));
         
-        let result = transac.internal_verify(&transac.sender_public_key, &transac.signature, transac.value.as_slice());
+        let result = transac.internal_verify(&transac.sender_public_key, &transac.signature, &transac.to_bytes());
         assert!(result);
         let result = transac.internal_verify(&transac.sender_public_key, &transac.signature, &[1,2]);
         assert!(!result);
     }
+
+    #[test]
+    fn created_transaction_is_signed() {
+        let mut module = transaction_module::new();
+
+        let transac = module
+            .create_and_broadcast_transaction(
+                "from".to_string(),
+                "to".to_string(),
+                vec![1, 2, 3],
+                "this is a passphrase",
+            )
+            .unwrap();
+
+        assert!(!transac.signature.is_empty());
+        assert!(transac.verify());
+        assert_eq!(module.get_current().len(), 1);
+        assert_eq!(module.get_mempool_mut().len(), 1);
+
+        let result = module.create_and_broadcast_transaction(
+            "from".to_string(),
+            "to".to_string(),
+            vec![],
+            "this is a passphrase",
+        );
+        assert_eq!(result.unwrap_err(), TransactionError::EmptyValue);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let mut module = transaction_module::new();
+        let transac = module
+            .create_and_broadcast_transaction("a".to_string(), "b".to_string(), vec![1, 2], "p")
+            .unwrap();
+
+        let json = transac.to_json();
+        assert!(json.contains("\"sender_public_key\""));
+
+        let decoded = transaction::from_json(&json).unwrap();
+        assert_eq!(decoded, transac);
+        assert!(decoded.verify());
+    }
+
+    #[test]
+    fn wire_format_is_stable() {
+        let transac = transaction {
+            from: "a".to_string(),
+            to: "b".to_string(),
+            value: vec![1, 2],
+            sender_public_key: "k".to_string(),
+            signature: "s".to_string(),
+        };
+
+        assert_eq!(
+            transac.to_json(),
+            r#"{"from":"a","to":"b","value":[1,2],"sender_public_key":"k","signature":"s"}"#
+        );
+
+        /* every field is its length as a little-endian u64, then its bytes, in declaration order */
+        let mut expected = Vec::new();
+        for field in &[&b"a"[..], b"b", &[1, 2], b"k", b"s"] {
+            expected.extend_from_slice(&(field.len() as u64).to_le_bytes());
+            expected.extend_from_slice(field);
+        }
+        assert_eq!(serialize(&transac).unwrap(), expected);
+        assert_eq!(deserialize::<transaction>(&expected).unwrap(), transac);
+    }
+
+    #[test]
+    fn malformed_json_is_rejected() {
+        assert!(transaction::from_json("not json").is_err());
+        assert!(transaction::from_json(r#"{"from": "a", "to": "b", "value": [1]}"#).is_err());
+        assert!(transaction::from_json(
+            r#"{"from": "a", "to": "b", "value": "x", "sender_public_key": "", "signature": ""}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn canonical_layout() {
+        let mut transac = transaction::new();
+        transac.from = "ab".to_string();
+        transac.to = "c".to_string();
+        transac.value = vec![1, 2, 3];
+        transac.sender_public_key = "d".to_string();
+        transac.signature = "not part of the layout".to_string();
+
+        assert_eq!(
+            transac.to_bytes(),
+            vec![
+                0, 0, 0, 2, b'a', b'b', //
+                0, 0, 0, 1, b'c', //
+                0, 0, 0, 3, 1, 2, 3, //
+                0, 0, 0, 1, b'd',
+            ]
+        );
+
+        let mut signed = transac.clone();
+        signed.sign("this is a passphrase");
+        signed.to = "e".to_string();
+        assert!(!signed.verify());
+    }
+
+    #[test]
+    fn verify_garbage_hex() {
+        let mut transac = transaction::new();
+        transac.value = vec![1, 2, 3];
+        transac.sign("this is a passphrase");
+
+        let mut garbage = transac.clone();
+        garbage.signature = "not hex at all".to_string();
+        assert!(!garbage.verify());
+
+        let mut garbage = transac.clone();
+        garbage.sender_public_key = "zz".to_string();
+        assert!(!garbage.verify());
+
+        assert!(transac.verify());
+    }
+
+    #[test]
+    fn compact_signature_verifies() {
+        let mut transac = transaction::new();
+        transac.value = vec![1, 2, 3];
+        transac.sign_compact("this is a passphrase");
+        assert_eq!(transac.signature.len(), 128);
+        assert!(transac.verify());
+
+        let mut der = transac.clone();
+        der.sign("this is a passphrase");
+        assert_eq!(der.sender_public_key, transac.sender_public_key);
+        assert!(der.verify());
+
+        transac.value = vec![1, 2, 4];
+        assert!(!transac.verify());
+    }
+
+    #[test]
+    fn re_encoded_signature_is_the_same_transaction() {
+        use secp256k1::Signature;
+
+        let mut module = transaction_module::new();
+        let signed = module
+            .create_and_broadcast_transaction(
+                "from".to_string(),
+                "to".to_string(),
+                vec![1, 2, 3],
+                "this is a passphrase",
+            )
+            .unwrap();
+
+        /* a relay re-encodes the DER signature in its compact form */
+        let der = hex::decode(&signed.signature).unwrap();
+        let mut re_encoded = signed.clone();
+        re_encoded.signature =
+            hex::encode(&Signature::from_der(&der).unwrap().serialize_compact()[..]);
+        assert_ne!(re_encoded, signed);
+        assert!(re_encoded.verify());
+
+        let mut peer = transaction_module::new();
+        assert_eq!(peer.receive_transaction(&re_encoded), Ok(()));
+        assert_eq!(
+            peer.receive_transaction(&signed),
+            Err(TransactionError::AlreadyKnown)
+        );
+        assert_eq!(
+            peer.receive_transaction(&re_encoded),
+            Err(TransactionError::AlreadyKnown)
+        );
+        assert_eq!(peer.get_current(), &vec![signed.clone()]);
+        assert_eq!(peer.get_mempool_mut(), &mut vec![signed]);
+    }
+
+    #[test]
+    fn verify_truncated_der_signature() {
+        let mut transac = transaction::new();
+        transac.value = vec![1, 2, 3];
+        transac.sign("this is a passphrase");
+
+        let len = transac.signature.len();
+        transac.signature.truncate(len / 2);
+        assert!(!transac.verify());
+    }
 }
@@ -1,56 +1,553 @@
 use std::env;
 use std::fmt::Display;
+use std::io::{self, BufRead, Write};
 
 extern crate bincode;
+extern crate hex;
 extern crate rhai;
 extern crate rust_blockchain;
-use rhai::{Engine, RegisterFn};
+use rhai::{Any, Engine, EvalAltResult, RegisterFn, RegisterResultFn, Scope};
 
-fn showit<T: Display>(x: &mut T) -> () {
+fn showit<T: Display>(x: &mut T) {
     println!("{}", x)
 }
 
-fn main() {
-    for fname in env::args().skip(1) {
-        let mut engine = Engine::new();
+fn new_engine() -> Engine {
+    new_engine_listening_on(&format!("0.0.0.0:{}", LISTENING_PORT))
+}
+
+/// Builds the engine of the interpreter, whose node listens on the given address once started.
+fn new_engine_listening_on(listen_address: &str) -> Engine {
+    let mut engine = Engine::new();
+
+    engine.register_fn("print", showit as fn(x: &mut i32) -> ());
+    engine.register_fn("print", showit as fn(x: &mut i64) -> ());
+    engine.register_fn("print", showit as fn(x: &mut u32) -> ());
+    engine.register_fn("print", showit as fn(x: &mut u64) -> ());
+    engine.register_fn("print", showit as fn(x: &mut f32) -> ());
+    engine.register_fn("print", showit as fn(x: &mut f64) -> ());
+    engine.register_fn("print", showit as fn(x: &mut bool) -> ());
+    engine.register_fn("print", showit as fn(x: &mut String) -> ());
+
+    engine.register_result_fn("parse_int", |s: String| {
+        s.trim()
+            .parse::<i64>()
+            .map_err(|_| EvalAltResult::ErrorRuntime(format!("not an integer: {:?}", s)))
+    });
+    engine.register_result_fn("hex_decode", |s: String| {
+        decode_hex(&s).map(|bytes| {
+            bytes
+                .into_iter()
+                .map(|b| Box::new(b as i64) as Box<dyn Any>)
+                .collect::<Vec<_>>()
+        })
+    });
+
+    register_blockchain_and_init(&mut engine, listen_address.to_string());
+
+    engine
+}
+
+/// Decodes an hexadecimal script argument.
+fn decode_hex(s: &str) -> Result<Vec<u8>, EvalAltResult> {
+    hex::decode(s).map_err(|_| EvalAltResult::ErrorRuntime(format!("not hexadecimal: {:?}", s)))
+}
+
+/// Formats a value returned by the REPL, nothing is printed for `()`.
+fn format_value(value: &dyn Any) -> Option<String> {
+    if value.is::<()>() {
+        None
+    } else if let Some(x) = value.downcast_ref::<i64>() {
+        Some(x.to_string())
+    } else if let Some(x) = value.downcast_ref::<f64>() {
+        Some(x.to_string())
+    } else if let Some(x) = value.downcast_ref::<bool>() {
+        Some(x.to_string())
+    } else if let Some(x) = value.downcast_ref::<String>() {
+        Some(format!("{:?}", x))
+    } else if let Some(x) = value.downcast_ref::<char>() {
+        Some(format!("{:?}", x))
+    } else {
+        Some("<value>".to_string())
+    }
+}
 
-        engine.register_fn("print", showit as fn(x: &mut i32) -> ());
-        engine.register_fn("print", showit as fn(x: &mut i64) -> ());
-        engine.register_fn("print", showit as fn(x: &mut u32) -> ());
-        engine.register_fn("print", showit as fn(x: &mut u64) -> ());
-        engine.register_fn("print", showit as fn(x: &mut f32) -> ());
-        engine.register_fn("print", showit as fn(x: &mut f64) -> ());
-        engine.register_fn("print", showit as fn(x: &mut bool) -> ());
-        engine.register_fn("print", showit as fn(x: &mut String) -> ());
+/// Evaluates every input line against one scope kept between lines, and writes
+/// each result or error to the output.
+fn repl<R: BufRead, W: Write>(engine: &mut Engine, input: R, output: &mut W) -> io::Result<()> {
+    let mut scope = Scope::new();
 
-        register_blockchain_and_init(&mut engine);
+    for line in input.lines() {
+        let line = line?;
 
-        match engine.eval_file::<()>(&fname) {
-            Ok(_) => (),
-            Err(e) => println!("Error: {}", e),
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match engine.eval_with_scope_raw(&mut scope, &line) {
+            Ok(value) => {
+                if let Some(text) = format_value(value.as_ref()) {
+                    writeln!(output, "{}", text)?;
+                }
+            }
+            Err(e) => writeln!(output, "Error: {}", e)?,
         }
     }
-    loop {}
+
+    Ok(())
+}
+
+/// Evaluates a script file and writes its result, if it isn't `()`, or its error to the output.
+fn run_file<W: Write>(engine: &mut Engine, fname: &str, output: &mut W) -> io::Result<()> {
+    match engine.eval_file_raw(fname) {
+        Ok(value) => {
+            if let Some(text) = format_value(value.as_ref()) {
+                writeln!(output, "{}", text)?;
+            }
+        }
+        Err(e) => writeln!(output, "Error: {}", e)?,
+    }
+
+    Ok(())
+}
+
+/// Writes how a script file was parsed, without evaluating it.
+fn dump_file<W: Write>(engine: &Engine, fname: &str, output: &mut W) -> io::Result<()> {
+    let contents = std::fs::read_to_string(fname)?;
+
+    match engine.dump_ast(&contents) {
+        Ok(dump) => write!(output, "{}", dump),
+        Err(e) => writeln!(output, "Error: {}", e),
+    }
+}
+
+fn main() {
+    let mut files: Vec<String> = env::args().skip(1).collect();
+
+    if files.first().map(String::as_str) == Some("--dump-ast") {
+        let engine = new_engine();
+
+        for fname in files.drain(1..) {
+            dump_file(&engine, &fname, &mut io::stdout()).unwrap();
+        }
+        return;
+    }
+
+    if files.is_empty() {
+        let mut engine = new_engine();
+        let stdin = io::stdin();
+
+        repl(&mut engine, stdin.lock(), &mut io::stdout()).unwrap();
+        return;
+    }
+
+    for fname in files {
+        let mut engine = new_engine();
+
+        run_file(&mut engine, &fname, &mut io::stdout()).unwrap();
+    }
+
+    /* the blockchain commands are run by background threads */
+    loop {
+        park();
+    }
 }
 
 use bincode::serialize;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
-use std::thread::spawn;
+use std::thread::{park, spawn, JoinHandle};
 
+use rust_blockchain::accept_connections;
 use rust_blockchain::block::Block;
-use rust_blockchain::blocks::{broadcast_block, list_blocks};
-use rust_blockchain::handle_incoming_connections;
-use rust_blockchain::help::list_commands;
-use rust_blockchain::peers::{create_stream, get_chain_from_stream, list_peers};
-use rust_blockchain::transaction::{transaction, transaction_module};
+use rust_blockchain::blocks::{append_block, height, list_blocks, tip};
+use rust_blockchain::hash_algo::HashAlgo;
+use rust_blockchain::help::{
+    find_command, list_commands, register_script_command, Command, ScriptCommand,
+};
+use rust_blockchain::identity::signature_verify;
+use rust_blockchain::peers::{create_stream, list_peers, prune_dead_peers, sync_chain};
+use rust_blockchain::rate_limit::DEFAULT_CONNECTION_LIMIT;
+use rust_blockchain::transaction::transaction_module;
 const LISTENING_PORT: &str = "10000";
 
-fn register_blockchain_and_init(engine: &mut Engine) {
+/// Shutdown flag, thread and bound address of the running listener.
+type NodeHandle = (Arc<AtomicBool>, JoinHandle<()>, SocketAddr);
+
+/// Copy of one block handed to scripts, so that they can walk the chain
+/// without holding its lock.
+#[derive(Clone)]
+struct ScriptBlock {
+    height: i64,
+    hash: String,
+    previous: String,
+    data: String,
+    bytes: Vec<u8>,
+}
+
+/// Runs `f` with the chain locked. Script functions only access the chain through it, and `f`
+/// must not call the engine or any other script function: the lock is not reentrant, so
+/// calling one that locks the chain again would deadlock.
+fn with_chain<T, F: FnOnce(&mut Vec<Block>) -> T>(chain: &Mutex<Vec<Block>>, f: F) -> T {
+    f(&mut chain.lock().unwrap())
+}
+
+/// State the commands of the main loop work on.
+struct CommandContext {
+    chain: Arc<Mutex<Vec<Block>>>,
+    peers: Vec<String>,
+    transactions: Arc<Mutex<transaction_module>>,
+    /* help entries registered by scripts, listed by the help command */
+    script_commands: Arc<Mutex<Vec<ScriptCommand>>>,
+}
+
+/// Every command of the main loop. The loop only runs the commands found here,
+/// so that the help always lists all of them.
+const COMMANDS: &[Command<CommandContext>] = &[
+    Command {
+        name: "add_block",
+        arity: 1,
+        params: "[data]",
+        description: "append a block into the local blockchain",
+        example: Some("add_block 10"),
+        handler: add_block_command,
+    },
+    Command {
+        name: "list_blocks",
+        arity: 0,
+        params: "",
+        description: "list the local chain blocks",
+        example: None,
+        handler: list_blocks_command,
+    },
+    Command {
+        name: "add_peer",
+        arity: 1,
+        params: "[address]",
+        description: "add one node as a peer",
+        example: Some("add_peer 172.17.0.10"),
+        handler: add_peer_command,
+    },
+    Command {
+        name: "list_peers",
+        arity: 0,
+        params: "",
+        description: "list the peers",
+        example: None,
+        handler: list_peers_command,
+    },
+    Command {
+        name: "cleanup_peers",
+        arity: 0,
+        params: "",
+        description: "remove the peers that cannot be joined",
+        example: None,
+        handler: cleanup_peers_command,
+    },
+    Command {
+        name: "create_and_broadcast_transaction",
+        arity: 4,
+        params: "[from] [to] [value_hex] [passphrase]",
+        description: "sign a transaction and add it to the local transactions",
+        example: Some("create_and_broadcast_transaction alice bob 0a my passphrase"),
+        handler: create_and_broadcast_transaction_command,
+    },
+    Command {
+        name: "list_transaction_local",
+        arity: 0,
+        params: "",
+        description: "list the local transactions",
+        example: None,
+        handler: list_transaction_local_command,
+    },
+    Command {
+        name: "add_block_from_local_transactions",
+        arity: 0,
+        params: "",
+        description: "append a block holding the local transactions",
+        example: None,
+        handler: add_block_from_local_transactions_command,
+    },
+    Command {
+        name: "help",
+        arity: 0,
+        params: "",
+        description: "list the commands",
+        example: None,
+        handler: help_command,
+    },
+    Command {
+        name: "exit",
+        arity: 0,
+        params: "",
+        description: "quit the program",
+        example: None,
+        handler: exit_command,
+    },
+];
+
+fn add_block_command(context: &mut CommandContext, args: &[&str]) -> bool {
+    let data_vec: Vec<u8> = args[0].as_bytes().to_vec();
+    match append_block(&context.chain, &data_vec) {
+        Ok(_) => println!("New block added."),
+        Err(e) => println!("The block cannot be created: {}.", e),
+    }
+
+    //todo broadcast_block(&peers, block);
+    true
+}
+
+fn list_blocks_command(context: &mut CommandContext, _: &[&str]) -> bool {
+    println!("list blocks.");
+    list_blocks(&context.chain);
+    true
+}
+
+fn add_peer_command(context: &mut CommandContext, args: &[&str]) -> bool {
+    let full_address = format!("{}:{}", args[0], LISTENING_PORT);
+    context.peers.push(full_address.clone());
+
+    println!("Address {} added to peers list.", args[0]);
+
+    if let Some(stream) = create_stream(&full_address) {
+        sync_chain(&context.chain, stream);
+    }
+    true
+}
+
+fn list_peers_command(context: &mut CommandContext, _: &[&str]) -> bool {
+    list_peers(&context.peers);
+    true
+}
+
+fn cleanup_peers_command(context: &mut CommandContext, _: &[&str]) -> bool {
+    let removed = prune_dead_peers(&mut context.peers);
+
+    println!("{} unreachable peer(s) removed.", removed);
+    true
+}
+
+fn create_and_broadcast_transaction_command(context: &mut CommandContext, args: &[&str]) -> bool {
+    let from = args[0].to_string();
+    let to = args[1].to_string();
+    let value = hex::decode(args[2]).unwrap_or_default();
+    let passphrase = args[3..].join(" ");
+
+    let result = context
+        .transactions
+        .lock()
+        .unwrap()
+        .create_and_broadcast_transaction(from, to, value, &passphrase);
+    if let Err(e) = result {
+        println!("The transaction cannot be created: {}.", e);
+    }
+    true
+}
+
+fn list_transaction_local_command(context: &mut CommandContext, _: &[&str]) -> bool {
+    context.transactions.lock().unwrap().list_transaction_local();
+    true
+}
+
+fn add_block_from_local_transactions_command(context: &mut CommandContext, _: &[&str]) -> bool {
+    let data_vec = serialize(context.transactions.lock().unwrap().get_current()).unwrap();
+    match append_block(&context.chain, &data_vec) {
+        Ok(_) => println!("New block added."),
+        Err(e) => println!("The block cannot be created: {}.", e),
+    }
+
+    //todo broadcast_block(&peers, block);
+    true
+}
+
+fn help_command(context: &mut CommandContext, _: &[&str]) -> bool {
+    list_commands(COMMANDS, &context.script_commands.lock().unwrap());
+    true
+}
+
+fn exit_command(_: &mut CommandContext, _: &[&str]) -> bool {
+    false
+}
+
+fn register_blockchain_and_init(engine: &mut Engine, listen_address: String) {
     let chain: Arc<Mutex<Vec<Block>>> = Arc::new(Mutex::new(Vec::new()));
-    let mut peers: Vec<String> = Vec::new();
+    /* transactions received by the listener land in the same pool as the local ones */
+    let transactions = Arc::new(Mutex::new(transaction_module::new()));
+    let script_commands = Arc::new(Mutex::new(Vec::new()));
+
+    let mut context = CommandContext {
+        chain: chain.clone(),
+        peers: Vec::new(),
+        transactions: transactions.clone(),
+        script_commands: script_commands.clone(),
+    };
+
+    /* the listener is bound before returning, so the node is reachable
+    as soon as start_node() returns true */
+    let node_chain = chain.clone();
+    let node_transactions = transactions.clone();
+    let node: Arc<Mutex<Option<NodeHandle>>> = Arc::new(Mutex::new(None));
+    let start_node_node = node.clone();
+    let start_node_fn = move || {
+        let mut node = start_node_node.lock().unwrap();
+        if node.is_some() {
+            println!("The node is already started.");
+            return false;
+        }
 
-    let listener_chain = chain.clone();
-    //todo spawn(|| handle_incoming_connections(listener_chain));
+        let bound = TcpListener::bind(&listen_address)
+            .and_then(|listener| listener.local_addr().map(|address| (listener, address)));
+        match bound {
+            Ok((listener, address)) => {
+                let chain = node_chain.clone();
+                let transactions = node_transactions.clone();
+                let shutdown = Arc::new(AtomicBool::new(false));
+                let thread_shutdown = shutdown.clone();
+                let handle = spawn(move || {
+                    accept_connections(
+                        listener,
+                        chain,
+                        transactions,
+                        thread_shutdown,
+                        DEFAULT_CONNECTION_LIMIT,
+                    )
+                });
+                *node = Some((shutdown, handle, address));
+                true
+            }
+            Err(e) => {
+                println!("The node cannot listen on {}: {}", listen_address, e);
+                false
+            }
+        }
+    };
+    engine.register_fn("start_node", start_node_fn);
+
+    /* empty if the node is not started */
+    let node_address_node = node.clone();
+    let node_address_fn = move || match *node_address_node.lock().unwrap() {
+        Some((_, _, address)) => address.to_string(),
+        None => String::new(),
+    };
+    engine.register_fn("node_address", node_address_fn);
+
+    /* returns once the listener is closed, so the node can be started again right away */
+    let stop_node_fn = move || match node.lock().unwrap().take() {
+        Some((shutdown, handle, _)) => {
+            shutdown.store(true, Ordering::SeqCst);
+            handle.join().is_ok()
+        }
+        None => {
+            println!("The node is not started.");
+            false
+        }
+    };
+    engine.register_fn("stop_node", stop_node_fn);
+
+    let difficulty = Arc::new(AtomicUsize::new(0));
+
+    let set_difficulty = difficulty.clone();
+    /* mined blocks use the default algorithm, no digest of it has more zeros than its length */
+    let set_difficulty_fn = move |n: i64| {
+        let max_difficulty = HashAlgo::default().digest_len();
+        if n < 0 || n as u64 > max_difficulty as u64 {
+            return Err(EvalAltResult::ErrorRuntime(format!(
+                "difficulty must be between 0 and {}",
+                max_difficulty
+            )));
+        }
+
+        set_difficulty.store(n as usize, Ordering::SeqCst);
+        Ok(())
+    };
+    engine.register_result_fn("set_difficulty", set_difficulty_fn);
+
+    let current_difficulty = difficulty.clone();
+    let current_difficulty_fn = move || current_difficulty.load(Ordering::SeqCst) as i64;
+    engine.register_fn("current_difficulty", current_difficulty_fn);
+
+    /* the proof-of-work is done without holding the chain mutex; the block is
+    mined again if the tip changed meanwhile, so it always refers to the current tip.
+    Peers refuse a block easier than the tip, so the tip difficulty is a minimum */
+    let mine_chain = chain.clone();
+    let mine_block_fn = move |data_hex: String| {
+        let data_vec = decode_hex(&data_hex)?;
+
+        loop {
+            let (previous_digest, tip_difficulty) = with_chain(&mine_chain, |chain| {
+                tip(chain)
+                    .map(|block| (block.get_current().to_string(), block.get_difficulty()))
+                    .unwrap_or_default()
+            });
+
+            let block = Block::mine(
+                &data_vec,
+                previous_digest.clone(),
+                difficulty.load(Ordering::SeqCst).max(tip_difficulty),
+            )
+            .map_err(|e| EvalAltResult::ErrorRuntime(e.to_string()))?;
+            let digest = block.get_current().to_string();
+
+            let mined_height = with_chain(&mine_chain, |chain| {
+                let current_tip = tip(chain).map(|block| block.get_current()).unwrap_or("");
+                if current_tip != previous_digest {
+                    return None;
+                }
+
+                chain.push(block);
+                height(chain)
+            });
+
+            if let Some(mined_height) = mined_height {
+                println!("New block mined at height {}: {}", mined_height, digest);
+                return Ok(digest);
+            }
+        }
+    };
+    engine.register_result_fn("mine_block", mine_block_fn);
+
+    let verify_signature_fn = |public_key: String, signature: String, data_hex: String| {
+        decode_hex(&data_hex).map(|bytes| signature_verify(&bytes, &signature, &public_key))
+    };
+    engine.register_result_fn("verify_signature", verify_signature_fn);
+
+    engine.register_type::<ScriptBlock>();
+    engine.register_get("height", |b: &mut ScriptBlock| b.height);
+    engine.register_get("hash", |b: &mut ScriptBlock| b.hash.clone());
+    engine.register_get("previous", |b: &mut ScriptBlock| b.previous.clone());
+    engine.register_get("data", |b: &mut ScriptBlock| b.data.clone());
+    /* array of the data bytes, so that scripts can read `block.bytes[i]` */
+    engine.register_get("bytes", |b: &mut ScriptBlock| {
+        b.bytes
+            .iter()
+            .map(|&byte| Box::new(i64::from(byte)) as Box<dyn Any>)
+            .collect::<Vec<_>>()
+    });
+
+    let get_chain_chain = chain.clone();
+    let get_chain_fn = move || {
+        with_chain(&get_chain_chain, |chain| {
+            chain
+                .iter()
+                .enumerate()
+                .map(|(height, block)| {
+                    Box::new(ScriptBlock {
+                        height: height as i64,
+                        hash: block.get_current().to_string(),
+                        previous: block.get_previous().to_string(),
+                        data: hex::encode(block.get_content().get_data()),
+                        bytes: block.get_content().get_data(),
+                    }) as Box<dyn Any>
+                })
+                .collect::<Vec<_>>()
+        })
+    };
+    engine.register_fn("get_chain", get_chain_fn);
+
+    let chain_length_chain = chain.clone();
+    let chain_length_fn = move || with_chain(&chain_length_chain, |chain| chain.len() as i64);
+    engine.register_fn("chain_length", chain_length_fn);
 
     let (tx1, rx) = mpsc::channel();
     let tx2 = mpsc::Sender::clone(&tx1);
@@ -59,6 +556,8 @@ fn register_blockchain_and_init(engine: &mut Engine) {
     let tx5 = mpsc::Sender::clone(&tx1);
     let tx6 = mpsc::Sender::clone(&tx1);
     let tx7 = mpsc::Sender::clone(&tx1);
+    let tx8 = mpsc::Sender::clone(&tx1);
+    let tx9 = mpsc::Sender::clone(&tx1);
 
     let add_block_fn = move |data: String| {
         let cmd = format!("add_block {}", data);
@@ -82,6 +581,11 @@ fn register_blockchain_and_init(engine: &mut Engine) {
     };
     engine.register_fn("list_peers", list_peers_fn);
 
+    let cleanup_peers_fn = move || {
+        tx8.send("cleanup_peers".to_owned()).unwrap();
+    };
+    engine.register_fn("cleanup_peers", cleanup_peers_fn);
+
     let add_block_from_local_transactions_fn = move || {
         tx7.send("add_block_from_local_transactions".to_owned())
             .unwrap();
@@ -91,13 +595,18 @@ fn register_blockchain_and_init(engine: &mut Engine) {
         add_block_from_local_transactions_fn,
     );
 
-    let mut transactions = transaction_module::new();
-
-    let create_and_broadcast_transaction_fn = move |from: String, to: String| {
-        let cmd = format!("create_and_broadcast_transaction {} {}", from, to);
-        tx5.send(cmd).unwrap();
-    };
-    engine.register_fn(
+    /* the passphrase comes last, as it may contain spaces */
+    let create_and_broadcast_transaction_fn =
+        move |from: String, to: String, value_hex: String, passphrase: String| {
+            decode_hex(&value_hex)?;
+            let cmd = format!(
+                "create_and_broadcast_transaction {} {} {} {}",
+                from, to, value_hex, passphrase
+            );
+            tx5.send(cmd).unwrap();
+            Ok(())
+        };
+    engine.register_result_fn(
         "create_and_broadcast_transaction",
         create_and_broadcast_transaction_fn,
     );
@@ -107,110 +616,270 @@ fn register_blockchain_and_init(engine: &mut Engine) {
     };
     engine.register_fn("list_transaction_local", list_transaction_local_fn);
 
+    let list_commands_fn = move || {
+        tx9.send("help".to_owned()).unwrap();
+    };
+    engine.register_fn("list_commands", list_commands_fn);
+
+    /* scripts document the commands they define, the help command lists them */
+    let register_command_fn = move |command_name: String, command_params: String, help: String| {
+        let command = ScriptCommand {
+            name: command_name,
+            params: command_params,
+            description: help,
+        };
+        let mut script_commands = script_commands.lock().unwrap();
+        if register_script_command(COMMANDS, &mut script_commands, command) {
+            Ok(())
+        } else {
+            Err(EvalAltResult::ErrorRuntime(
+                "a command of the main loop already has this name".to_string(),
+            ))
+        }
+    };
+    engine.register_result_fn("register_command", register_command_fn);
+
     let main_loop = move || {
         loop {
             let input = rx.recv().unwrap_or("".to_string());
             //println!("input {}",input);
             let splitted: Vec<&str> = input.split(' ').collect();
 
-            /* get() returns &&str, so we mention result type &str
-            and get it from a reference (*) */
-            let command: &str = match splitted.get(0) {
-                Some(value) => *value,
-                None => {
-                    continue;
-                }
+            /* unknown commands, and commands missing arguments, are ignored */
+            let command = match splitted
+                .first()
+                .and_then(|name| find_command(COMMANDS, name))
+            {
+                Some(command) => command,
+                None => continue,
             };
+            if splitted.len() - 1 < command.arity {
+                continue;
+            }
 
-            const ADD_BLOCK: &str = "add_block";
-            const SEE_BLOCKCHAIN: &str = "list_blocks";
-            const ADD_PEER: &str = "add_peer";
-            const LIST_PEERS: &str = "list_peers";
-            const EXIT: &str = "exit";
-            const HELP: &str = "help";
-
-            let option = match splitted.get(1) {
-                Some(option) => option,
-                None => {
-                    if command == ADD_BLOCK || command == ADD_PEER {
-                        continue;
-                    }
-
-                    ""
-                }
-            };
-            if command == ADD_BLOCK {
-                //let data_vec: Vec<u8> = option.chars().map(|x| x.to_digit(16).unwrap() as u8).collect();
-                let data_vec: Vec<u8> = option.as_bytes().to_vec();
-                let mut chain = chain.lock().unwrap();
+            if !(command.handler)(&mut context, &splitted[1..]) {
+                break;
+            }
+        }
+    };
+    spawn(main_loop);
+}
 
-                let mut previous_digest = String::new();
+#[cfg(test)]
+mod test {
+    use super::*;
 
-                if !chain.is_empty() {
-                    previous_digest = chain.last().unwrap().get_current().to_string();
-                }
+    use rust_blockchain::identity::{
+        privatekey_to_signature, publickey_to_hex, publickkey_from_passphrase,
+    };
 
-                let block = Block::new(&data_vec, previous_digest);
-                chain.push(block.clone());
+    #[test]
+    fn commands_match_their_help() {
+        for (i, command) in COMMANDS.iter().enumerate() {
+            assert!(COMMANDS[i + 1..]
+                .iter()
+                .all(|other| other.name != command.name));
+            assert_eq!(command.params.split_whitespace().count(), command.arity);
+        }
+    }
 
-                println!("New block added.");
+    #[test]
+    fn scripts_register_commands() {
+        let mut engine = new_engine();
+
+        assert_eq!(
+            engine.eval::<()>("register_command(\"greet\", \"[name]\", \"say hello\")"),
+            Ok(())
+        );
+        assert!(engine
+            .eval::<()>("register_command(\"add_block\", \"\", \"replaced\")")
+            .is_err());
+    }
 
-            //todo broadcast_block(&peers, block);
-            } else if command == SEE_BLOCKCHAIN {
-                println!("list blocks.");
-                list_blocks(&chain);
-            } else if command == ADD_PEER {
-                let full_address = format!("{}:{}", option, LISTENING_PORT);
-                peers.push(full_address.clone());
+    #[test]
+    fn repl_keeps_scope_between_lines() {
+        let mut engine = Engine::new();
+        let input = "let x = 40;\nx + 2\n\nx = x + 1;\nx\n\"a\" + \"b\"\nunknown_fn()\nx\n";
+        let mut output = Vec::new();
 
-                println!("Address {} added to peers list.", option);
-            /*
-            let stream = create_stream(&full_address);
-            if stream.is_some() {
-                let remote_chain = get_chain_from_stream(stream.unwrap());
+        repl(&mut engine, input.as_bytes(), &mut output).unwrap();
 
-                let mut chain = chain.lock().unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(&lines[..4], &["42", "41", "41", "\"ab\""]);
+        assert!(lines[4].starts_with("Error: "));
+        assert_eq!(lines[5], "41");
+    }
 
-                if remote_chain.len() > chain.len() {
-                    *chain = remote_chain.clone();
-                    println!("The local chain is outdated compared to the remote one, replaced.");
-                } else {
-                    println!("The local chain is up-to-date compared to the remote one.");
-                }
+    #[test]
+    fn start_node_only_once() {
+        use std::net::TcpStream;
 
-            }*/
-            } else if command == LIST_PEERS {
-                list_peers(&peers);
-            } else if command == HELP {
-                list_commands();
-            } else if command == EXIT {
-                break;
-            } else if command == "create_and_broadcast_transaction" {
-                let from = splitted.get(1).unwrap().to_string();
-                let to = splitted.get(2).unwrap().to_string();
-                //let value: u32 = splitted.get(3).unwrap().parse().unwrap();
-                transactions.create_and_broadcast_transaction(from, to);
-            } else if command == "list_transaction_local" {
-                transactions.list_transaction_local();
-            } else if command == "add_block_from_local_transactions" {
-                let mut chain = chain.lock().unwrap();
-                let data_vec = serialize(transactions.get_current()).unwrap();
-                let mut previous_digest = String::new();
-
-                if !chain.is_empty() {
-                    previous_digest = chain.last().unwrap().get_current().to_string();
-                }
+        /* any free port, so that the test does not depend on the default one */
+        let mut engine = new_engine_listening_on("127.0.0.1:0");
+
+        assert_eq!(engine.eval::<String>("node_address()"), Ok(String::new()));
+        assert_eq!(engine.eval::<bool>("start_node()"), Ok(true));
+        assert_eq!(engine.eval::<bool>("start_node()"), Ok(false));
+
+        let address = engine.eval::<String>("node_address()").unwrap();
+        assert!(TcpStream::connect(&address).is_ok());
+
+        assert_eq!(engine.eval::<bool>("stop_node()"), Ok(true));
+        assert_eq!(engine.eval::<bool>("stop_node()"), Ok(false));
+        assert!(TcpStream::connect(&address).is_err());
+
+        assert_eq!(engine.eval::<bool>("start_node()"), Ok(true));
+        assert_eq!(engine.eval::<bool>("stop_node()"), Ok(true));
+    }
+
+    #[test]
+    fn mine_block_at_difficulty() {
+        let mut engine = new_engine();
+
+        assert_eq!(engine.eval::<i64>("current_difficulty()"), Ok(0));
+
+        let tip = engine
+            .eval::<String>("set_difficulty(1); mine_block(\"00ff\")")
+            .unwrap();
+
+        assert_eq!(engine.eval::<i64>("current_difficulty()"), Ok(1));
+        assert!(tip.starts_with("0"));
+
+        /* the chain difficulty is never lowered, whatever the setting */
+        let next = engine
+            .eval::<String>("set_difficulty(0); mine_block(\"01\")")
+            .unwrap();
+        assert!(next.starts_with("0"));
+        engine.eval::<()>("set_difficulty(1)").unwrap();
+
+        assert!(engine.eval::<()>("set_difficulty(65)").is_err());
+        assert!(engine.eval::<()>("set_difficulty(-1)").is_err());
+        assert_eq!(engine.eval::<i64>("current_difficulty()"), Ok(1));
+    }
+
+    #[test]
+    fn chain_functions_do_not_deadlock() {
+        use std::sync::mpsc::channel;
+        use std::time::Duration;
+
+        let (sender, receiver) = channel();
+        spawn(move || {
+            let mut engine = new_engine();
+            let result =
+                engine.eval::<i64>("mine_block(\"01\"); mine_block(\"02\"); chain_length()");
+            sender.send(result.map_err(|e| e.to_string())).unwrap();
+        });
+
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(10)), Ok(Ok(2)));
+    }
+
+    #[test]
+    fn nested_arrays_to_string() {
+        let mut engine = new_engine();
+
+        assert_eq!(
+            engine.eval::<String>("to_string([1, [2, [3]], 4.5])"),
+            Ok("[1, [2, [3]], 4.5]".to_string())
+        );
+        assert_eq!(engine.eval::<()>("print([1, [2]])"), Ok(()));
+    }
 
-                let block = Block::new(&data_vec, previous_digest);
-                chain.push(block.clone());
+    #[test]
+    fn fallible_builtins() {
+        let mut engine = new_engine();
+
+        assert_eq!(engine.eval::<i64>("parse_int(\" 42 \")"), Ok(42));
+        assert_eq!(
+            engine.eval::<i64>("parse_int(\"forty-two\")"),
+            Err(EvalAltResult::ErrorRuntime(
+                "not an integer: \"forty-two\"".to_string()
+            ))
+        );
+        assert_eq!(engine.eval::<i64>("hex_decode(\"00ff\")[1]"), Ok(255));
+        assert!(engine.eval::<i64>("hex_decode(\"zz\")[0]").is_err());
+        assert!(engine.eval::<String>("mine_block(\"zz\")").is_err());
+
+        let passphrase = "this is a passphrase";
+        let public_key = publickey_to_hex(&publickkey_from_passphrase(passphrase));
+        let signature = privatekey_to_signature(&[1, 2], passphrase);
+        let script = format!(
+            "verify_signature(\"{}\", \"{}\", \"0102\")",
+            public_key, signature
+        );
+        assert_eq!(engine.eval::<bool>(&script), Ok(true));
+        let script = format!(
+            "verify_signature(\"{}\", \"{}\", \"01\")",
+            public_key, signature
+        );
+        assert_eq!(engine.eval::<bool>(&script), Ok(false));
+    }
+
+    #[test]
+    fn iterate_chain_from_script() {
+        let mut engine = new_engine();
 
-                println!("New block added.");
+        let tip = engine
+            .eval::<String>("mine_block(\"01\"); mine_block(\"02\")")
+            .unwrap();
 
-            //todo broadcast_block(&peers, block);
-            } else {
-                //println!("Command not found. Type 'help' to list commands.");
+        let script = "
+            let heights = 0;
+            let data = \"\";
+            let last = \"\";
+            for b in get_chain() {
+                heights += b.height;
+                data += b.data;
+                last = b.hash;
             }
-        }
-    };
-    spawn(main_loop);
+            [heights, data, last]
+        ";
+        let result = engine.eval::<Vec<Box<dyn Any>>>(script).unwrap();
+
+        assert_eq!(result[0].downcast_ref::<i64>(), Some(&1));
+        assert_eq!(result[1].downcast_ref::<String>(), Some(&"0102".to_string()));
+        assert_eq!(result[2].downcast_ref::<String>(), Some(&tip));
+    }
+
+    #[test]
+    fn help_lists_blockchain_bindings() {
+        let printed = Arc::new(Mutex::new(Vec::new()));
+        let print_printed = printed.clone();
+
+        let mut engine = new_engine();
+        engine.register_fn("print", move |s: String| print_printed.lock().unwrap().push(s));
+
+        assert_eq!(engine.eval::<()>("help()"), Ok(()));
+
+        let printed = printed.lock().unwrap();
+        assert!(printed.iter().any(|f| f.starts_with("add_block(")));
+        assert!(printed.iter().any(|f| f.starts_with("mine_block(")));
+    }
+
+    #[test]
+    fn index_block_bytes_from_script() {
+        let mut engine = new_engine();
+
+        engine.eval::<String>("mine_block(\"0a0b0c\")").unwrap();
+
+        assert_eq!(
+            engine.eval::<i64>("let chain = get_chain(); let b = chain[0]; b.bytes[2]"),
+            Ok(12)
+        );
+    }
+
+    #[test]
+    fn run_file_prints_last_value() {
+        let path = env::temp_dir().join("interpreter_with_blockchain_run_file.rhai");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "40+2").unwrap();
+
+        let mut engine = Engine::new();
+        let mut output = Vec::new();
+        run_file(&mut engine, path, &mut output).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "42\n");
+    }
 }